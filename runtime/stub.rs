@@ -1,4 +1,14 @@
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::ffi::CStr;
+use std::hash::Hasher;
+use std::os::raw::c_char;
+
+#[allow(dead_code)]
+#[path = "../src/bignum.rs"]
+mod bignum;
+use bignum::BigInt;
 
 #[repr(C)]
 #[derive(PartialEq, Eq, Copy, Clone)]
@@ -29,13 +39,348 @@ static TAG_MASK: u64 = 0x00_00_00_00_00_00_00_01;
 static SNAKE_TRU: SnakeVal = SnakeVal(0xFF_FF_FF_FF_FF_FF_FF_FF);
 static SNAKE_FLS: SnakeVal = SnakeVal(0x7F_FF_FF_FF_FF_FF_FF_FF);
 
+// Boxed values are tagged 0b101 and their first word is one of these kinds
+static BOX_TAG: u64 = 0b101;
+static BIGNUM_KIND: u64 = 0;
+static VECTOR_KIND: u64 = 1;
+static DATA_KIND: u64 = 2;
+static RECORD_KIND: u64 = 3;
+static GENERATOR_KIND: u64 = 4;
+static CHANNEL_KIND: u64 = 5;
+static KEYWORDS_KIND: u64 = 6;
+static DICT_KIND: u64 = 7;
+static THUNK_KIND: u64 = 8;
+
+static I63_MAX: i64 = 0x3F_FF_FF_FF_FF_FF_FF_FF;
+static I63_MIN: i64 = -0x40_00_00_00_00_00_00_00;
+
 // reinterprets the bytes of an unsigned number to a signed number
 fn unsigned_to_signed(x: u64) -> i64 {
     i64::from_le_bytes(x.to_le_bytes())
 }
 
-fn print_array(a: &SnakeArray, visited: &mut HashSet<u64>) -> String {
-    let mut s = "[".to_string();
+fn snake_bool(b: bool) -> SnakeVal {
+    if b {
+        SNAKE_TRU
+    } else {
+        SNAKE_FLS
+    }
+}
+
+// Returns the kind word of a boxed value
+fn box_kind(x: SnakeVal) -> Option<u64> {
+    if x.0 & 0b111 == BOX_TAG {
+        unsafe { Some(*((x.0 - BOX_TAG) as *const u64)) }
+    } else {
+        None
+    }
+}
+
+// Allocates a boxed object outside of the snake heap. Values are never
+// freed, just like the bump-allocated snake heap.
+fn alloc_box(words: Vec<u64>) -> SnakeVal {
+    let p = Box::leak(words.into_boxed_slice()).as_ptr();
+    SnakeVal(p as u64 | BOX_TAG)
+}
+
+// Allocates an array outside of the snake heap, see alloc_box
+fn alloc_array(elts: &[SnakeVal]) -> SnakeVal {
+    let mut words = vec![elts.len() as u64];
+    words.extend(elts.iter().map(|v| v.0));
+    let p = Box::leak(words.into_boxed_slice()).as_ptr();
+    SnakeVal(p as u64 | 1)
+}
+
+/* Bignums are laid out as [BIGNUM_KIND, sign, number of limbs, limbs...]
+ * and are only used for numbers that don't fit into 63 bits.
+ */
+fn load_bignum(x: SnakeVal) -> BigInt {
+    unsafe {
+        let p = (x.0 - BOX_TAG) as *const u64;
+        let neg = *p.add(1) != 0;
+        let len = *p.add(2) as usize;
+        BigInt::from_parts(neg, std::slice::from_raw_parts(p.add(3), len).to_vec())
+    }
+}
+
+/* Vectors are laid out as [VECTOR_KIND, length, backing array] where
+ * the backing array is an untagged array whose size is the capacity.
+ */
+fn load_vector(x: SnakeVal) -> SnakeArray {
+    unsafe {
+        let p = (x.0 - BOX_TAG) as *const u64;
+        let backing = *p.add(2) as *const u64;
+        SnakeArray {
+            size: *p.add(1),
+            elts: backing.add(1) as *const SnakeVal,
+        }
+    }
+}
+
+/* Data values are laid out as [DATA_KIND, descriptor, fields...] where
+ * the descriptor of their constructor is [number of fields, name] and
+ * the name is a NUL-terminated string. Returns the descriptor's address
+ * and the fields.
+ */
+fn load_data(x: SnakeVal) -> (u64, SnakeArray) {
+    unsafe {
+        let p = (x.0 - BOX_TAG) as *const u64;
+        let descriptor = *p.add(1);
+        let fields = SnakeArray {
+            size: *(descriptor as *const u64),
+            elts: p.add(2) as *const SnakeVal,
+        };
+        (descriptor, fields)
+    }
+}
+
+fn ctor_name(descriptor: u64) -> String {
+    c_string(descriptor + 8)
+}
+
+fn c_string(address: u64) -> String {
+    let name = unsafe { CStr::from_ptr(address as *const c_char) };
+    name.to_string_lossy().into_owned()
+}
+
+/* Records are laid out like data, but their descriptor is their shape
+ * [number of fields, address of the name of each field...]. Returns the
+ * names of the fields and their values.
+ */
+fn load_record(x: SnakeVal) -> (Vec<String>, SnakeArray) {
+    let (shape, fields) = load_data(x);
+    let names = (0..fields.size)
+        .map(|i| c_string(unsafe { *(shape as *const u64).add(i as usize + 1) }))
+        .collect();
+    (names, fields)
+}
+
+/* Generators are laid out as [GENERATOR_KIND, state, closure, saved rsp,
+ * saved handler, resumer's generator, last yielded value, stack base]
+ * and run on a stack of their own, freed once they finish.
+ */
+static GENERATOR_STACK_WORDS: usize = 1 << 17;
+
+fn free_generator_stack(g: u64) {
+    unsafe {
+        let base = *(g as *const u64).add(7) as *mut u64;
+        drop(Vec::from_raw_parts(base, 0, GENERATOR_STACK_WORDS));
+    }
+}
+
+/* Channels are laid out as [CHANNEL_KIND, id] where the id is the index
+ * of the channel in the scheduler, which runs every thread but the main
+ * one on a stack of its own. A thread that isn't running is either ready
+ * to run or waiting to receive on a channel, and the values sent on a
+ * channel no thread was waiting on are kept until they are received.
+ */
+static THREAD_STACK_WORDS: usize = 1 << 17;
+
+// What snake_switch saves of a thread that isn't running: its rsp, its
+// innermost handler and the generator it is running
+#[repr(C)]
+struct Context {
+    rsp: u64,
+    handler: u64,
+    generator: u64,
+}
+
+struct Thread {
+    context: Context,
+    // the base of its stack, 0 for the main thread
+    stack: u64,
+    // the value sent to it while it was waiting on a channel
+    received: SnakeVal,
+}
+
+struct Channel {
+    values: VecDeque<SnakeVal>,
+    receivers: VecDeque<usize>,
+}
+
+struct Scheduler {
+    // boxed so that their contexts don't move while a thread is suspended
+    threads: Vec<Box<Thread>>,
+    current: usize,
+    ready: VecDeque<usize>,
+    channels: Vec<Channel>,
+    // the stacks of the threads that finished, freed once the scheduler
+    // runs on another stack
+    finished: Vec<u64>,
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<Scheduler> = RefCell::new(Scheduler {
+        threads: vec![Box::new(Thread {
+            context: Context { rsp: 0, handler: 0, generator: 0 },
+            stack: 0,
+            received: SNAKE_FLS,
+        })],
+        current: 0,
+        ready: VecDeque::new(),
+        channels: vec![],
+        finished: vec![],
+    });
+}
+
+// Runs the next ready thread and returns once the current thread runs
+// again. Fails if no thread is ready, as every thread is then waiting
+fn run_next_thread() {
+    let next = SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        let next = s.ready.pop_front()?;
+        let current = s.current;
+        let from = &mut s.threads[current].context as *mut Context;
+        s.current = next;
+        Some((from, &s.threads[next].context as *const Context))
+    });
+    match next {
+        Some((from, to)) => unsafe { snake_switch(from, to) },
+        None => snake_error(DEADLOCK_ERROR, SNAKE_FLS, 0),
+    }
+    SCHEDULER.with(|s| {
+        for base in s.borrow_mut().finished.drain(..) {
+            unsafe { drop(Vec::from_raw_parts(base as *mut u64, 0, THREAD_STACK_WORDS)) }
+        }
+    });
+}
+
+fn load_channel(c: SnakeVal) -> usize {
+    if box_kind(c) != Some(CHANNEL_KIND) {
+        snake_error(NON_CHANNEL_ERROR, c, 0);
+    }
+    unsafe { *((c.0 - BOX_TAG) as *const u64).add(1) as usize }
+}
+
+/* Dicts are laid out as [DICT_KIND, address of the table], a hash table
+ * owned by the runtime. Keys are hashed by their structure, so a key that
+ * changes once it is in a dict can't be found anymore. The entries are
+ * kept in the order their keys were first put.
+ */
+struct Entry {
+    hash: u64,
+    key: SnakeVal,
+    value: SnakeVal,
+}
+
+struct Dict {
+    entries: Vec<Entry>,
+    // the indices of the entries by hash, their number is a power of 2
+    buckets: Vec<Vec<usize>>,
+}
+
+// How deep hash_key looks into arrays, data and records, which keeps the
+// hash of a cyclic value finite
+static HASH_DEPTH: u32 = 4;
+
+// Values equal by snake_equal hash the same
+fn hash_key(x: SnakeVal, depth: u32, h: &mut DefaultHasher) {
+    if let Some(array) = load_sequence(x) {
+        h.write_u64(array.size);
+        if depth > 0 {
+            for i in 0..array.size as usize {
+                hash_key(unsafe { *array.elts.add(i) }, depth - 1, h);
+            }
+        }
+    } else if box_kind(x) == Some(BIGNUM_KIND) {
+        let n = load_bignum(x);
+        let (neg, limbs) = n.to_parts();
+        h.write_u64(neg as u64);
+        for limb in limbs {
+            h.write_u64(*limb);
+        }
+    } else if box_kind(x) == Some(DATA_KIND) || box_kind(x) == Some(RECORD_KIND) {
+        let (descriptor, fields) = load_data(x);
+        h.write_u64(descriptor);
+        if depth > 0 {
+            for i in 0..fields.size as usize {
+                hash_key(unsafe { *fields.elts.add(i) }, depth - 1, h);
+            }
+        }
+    } else {
+        // numbers, booleans and everything compared by identity
+        h.write_u64(x.0);
+    }
+}
+
+impl Dict {
+    fn find(&self, key: SnakeVal, hash: u64) -> Option<usize> {
+        let bucket = &self.buckets[hash as usize & (self.buckets.len() - 1)];
+        bucket
+            .iter()
+            .copied()
+            .find(|i| equal_inner(self.entries[*i].key, key, &mut HashSet::new()))
+    }
+
+    fn put(&mut self, key: SnakeVal, value: SnakeVal) {
+        let hash = hash_of(key);
+        if let Some(i) = self.find(key, hash) {
+            self.entries[i].value = value;
+            return;
+        }
+        // at most two entries per bucket on average
+        if self.entries.len() >= 2 * self.buckets.len() {
+            let n = 2 * self.buckets.len();
+            self.buckets = vec![vec![]; n];
+            for (i, entry) in self.entries.iter().enumerate() {
+                self.buckets[entry.hash as usize & (n - 1)].push(i);
+            }
+        }
+        let n = self.buckets.len();
+        self.buckets[hash as usize & (n - 1)].push(self.entries.len());
+        self.entries.push(Entry { hash, key, value });
+    }
+}
+
+fn hash_of(key: SnakeVal) -> u64 {
+    let mut h = DefaultHasher::new();
+    hash_key(key, HASH_DEPTH, &mut h);
+    h.finish()
+}
+
+fn load_dict(d: SnakeVal) -> *mut Dict {
+    if box_kind(d) != Some(DICT_KIND) {
+        snake_error(NON_DICT_ERROR, d, 0);
+    }
+    unsafe { *((d.0 - BOX_TAG) as *const u64).add(1) as *mut Dict }
+}
+
+// Arrays and vectors print and compare the same way
+fn load_sequence(x: SnakeVal) -> Option<SnakeArray> {
+    if x.0 & 0b111 == 1 {
+        Some(load_snake_array(x.0))
+    } else if box_kind(x) == Some(VECTOR_KIND) {
+        Some(load_vector(x))
+    } else {
+        None
+    }
+}
+
+fn snake_num(n: BigInt) -> SnakeVal {
+    match n.to_i64() {
+        Some(i) if i <= I63_MAX && i >= I63_MIN => SnakeVal((i << 1) as u64),
+        _ => {
+            let (neg, limbs) = n.to_parts();
+            let mut words = vec![BIGNUM_KIND, neg as u64, limbs.len() as u64];
+            words.extend_from_slice(limbs);
+            alloc_box(words)
+        }
+    }
+}
+
+fn to_bignum(x: SnakeVal) -> Option<BigInt> {
+    if x.0 & TAG_MASK == 0 {
+        Some(BigInt::from_i64(unsigned_to_signed(x.0) >> 1))
+    } else if box_kind(x) == Some(BIGNUM_KIND) {
+        Some(load_bignum(x))
+    } else {
+        None
+    }
+}
+
+// The elements of [a] separated by commas
+fn print_elts(a: &SnakeArray, visited: &mut HashSet<u64>) -> String {
+    let mut s = String::new();
     let mut p = a.elts;
     for i in 0..a.size {
         unsafe {
@@ -44,11 +389,9 @@ fn print_array(a: &SnakeArray, visited: &mut HashSet<u64>) -> String {
         }
         if i < a.size - 1 {
             s += ", ";
-        } else {
-            s += "]";
         }
     }
-    s.to_string()
+    s
 }
 
 fn sprint_snake_val_inner(x: SnakeVal, visited: &mut HashSet<u64>) -> String {
@@ -59,15 +402,83 @@ fn sprint_snake_val_inner(x: SnakeVal, visited: &mut HashSet<u64>) -> String {
         String::from("true")
     } else if x == SNAKE_FLS {
         String::from("false")
-    } else if x.0 & 0b111 == 1 {
+    } else if let Some(array) = load_sequence(x) {
         if visited.contains(&x.0) {
             return "<loop>".to_string();
         }
+        // only arrays on the path from the root are loops, arrays
+        // shared between siblings are printed each time
         visited.insert(x.0);
-        let array = load_snake_array(x.0);
-        print_array(&array, visited)
+        let s = format!("[{}]", print_elts(&array, visited));
+        visited.remove(&x.0);
+        s
     } else if x.0 & 0b111 == 0b11 {
         "<closure>".to_string()
+    } else if box_kind(x) == Some(GENERATOR_KIND) {
+        "<generator>".to_string()
+    } else if box_kind(x) == Some(CHANNEL_KIND) {
+        "<channel>".to_string()
+    } else if box_kind(x) == Some(THUNK_KIND) {
+        // the value is only printed once it was computed, and a stream
+        // may well contain itself
+        let thunk = load_thunk(x);
+        if unsafe { *thunk.add(2) } != SNAKE_TRU.0 {
+            return "<thunk>".to_string();
+        }
+        if visited.contains(&x.0) {
+            return "<loop>".to_string();
+        }
+        visited.insert(x.0);
+        let v = SnakeVal(unsafe { *thunk.add(1) });
+        let s = format!("<thunk: {}>", sprint_snake_val_inner(v, visited));
+        visited.remove(&x.0);
+        s
+    } else if box_kind(x) == Some(BIGNUM_KIND) {
+        load_bignum(x).to_string()
+    } else if box_kind(x) == Some(DATA_KIND) {
+        // a constructor without fields is printed as just its name
+        let (descriptor, fields) = load_data(x);
+        if fields.size == 0 {
+            ctor_name(descriptor)
+        } else {
+            format!(
+                "{}({})",
+                ctor_name(descriptor),
+                print_elts(&fields, visited)
+            )
+        }
+    } else if box_kind(x) == Some(DICT_KIND) {
+        if visited.contains(&x.0) {
+            return "<loop>".to_string();
+        }
+        visited.insert(x.0);
+        let dict = unsafe { &*load_dict(x) };
+        let entries: Vec<String> = dict
+            .entries
+            .iter()
+            .map(|e| {
+                let key = sprint_snake_val_inner(e.key, visited);
+                format!("{}: {}", key, sprint_snake_val_inner(e.value, visited))
+            })
+            .collect();
+        visited.remove(&x.0);
+        format!("dict({})", entries.join(", "))
+    } else if box_kind(x) == Some(RECORD_KIND) {
+        if visited.contains(&x.0) {
+            return "<loop>".to_string();
+        }
+        visited.insert(x.0);
+        let (names, fields) = load_record(x);
+        let fields: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let v = unsafe { *fields.elts.add(i) };
+                format!("{}: {}", name, sprint_snake_val_inner(v, visited))
+            })
+            .collect();
+        visited.remove(&x.0);
+        format!("{{{}}}", fields.join(", "))
     } else {
         format!("Invalid snake value 0x{:x}", x.0)
     }
@@ -87,10 +498,11 @@ extern "sysv64" fn print_snake_val(v: SnakeVal) -> SnakeVal {
  * input and output types as needed for your design.
  *
 **/
+// The same as ErrorCode in src/syntax.rs
 type ErrorCode = u64;
 static ARITH_TYPE_ERROR: ErrorCode = 0;
 static CMP_TYPE_ERROR: ErrorCode = 1;
-static OVERFLOW_ERROR: ErrorCode = 2;
+// 2 is unused, arithmetic that overflows promotes to bignums
 static IF_TYPE_ERROR: ErrorCode = 3;
 static LOGIC_TYPE_ERROR: ErrorCode = 4;
 static NON_ARRAY_ERROR: ErrorCode = 5;
@@ -98,15 +510,56 @@ static INDEX_NOT_NUMBER: ErrorCode = 6;
 static INDEX_OUT_OF_BOUNDS: ErrorCode = 7;
 static NON_CLOSURE_ERROR: ErrorCode = 8;
 static LAMBDA_ARITY_ERROR: ErrorCode = 9;
+static ARRAY_SIZE_ERROR: ErrorCode = 10;
+static NEGATIVE_SIZE_ERROR: ErrorCode = 11;
+static OUT_OF_MEMORY: ErrorCode = 12;
+static NON_VECTOR_ERROR: ErrorCode = 13;
+static EMPTY_VECTOR_ERROR: ErrorCode = 14;
+static MATCH_ERROR: ErrorCode = 15;
+static MISSING_FIELD_ERROR: ErrorCode = 16;
+static NON_RECORD_ERROR: ErrorCode = 17;
+static DESTRUCTURE_ERROR: ErrorCode = 18;
+static RAISE_ERROR: ErrorCode = 19;
+static NON_GENERATOR_ERROR: ErrorCode = 20;
+static GENERATOR_EXHAUSTED: ErrorCode = 21;
+static GENERATOR_RUNNING: ErrorCode = 22;
+static YIELD_ERROR: ErrorCode = 23;
+static NON_CHANNEL_ERROR: ErrorCode = 24;
+static DEADLOCK_ERROR: ErrorCode = 25;
+static ARGUMENT_ERROR: ErrorCode = 26;
+static NON_DICT_ERROR: ErrorCode = 27;
+static CAST_ERROR: ErrorCode = 28;
+static NON_THUNK_ERROR: ErrorCode = 29;
+static ASSERT_ERROR: ErrorCode = 30;
+static STACK_ERROR: ErrorCode = 99;
+
+// Passes the error to the handler of the innermost try, if there is one,
+// in which case this doesn't return. A raised value is caught as is, any
+// other error as RuntimeError(code, v), which programs can't build, so no
+// raised value is mistaken for it. Running out of memory or stack and a
+// deadlock can't be caught.
+fn unwind(err_code: ErrorCode, v: SnakeVal) {
+    if err_code == OUT_OF_MEMORY || err_code == STACK_ERROR || err_code == DEADLOCK_ERROR {
+        return;
+    }
+    let caught = if err_code == RAISE_ERROR {
+        v
+    } else {
+        let ctor = unsafe { std::ptr::addr_of!(snake_error_ctor) as u64 };
+        alloc_box(vec![DATA_KIND, ctor, err_code << 1, v.0])
+    };
+    unsafe { snake_unwind(caught) }
+}
 
+// [field] is the address of the name of the field for a missing field,
+// which the error reports along with the record [v]
 #[export_name = "\x01snake_error"]
-extern "sysv64" fn snake_error(err_code: ErrorCode, v: SnakeVal) {
+extern "sysv64" fn snake_error(err_code: ErrorCode, v: SnakeVal, field: u64) {
+    unwind(err_code, v);
     if err_code == ARITH_TYPE_ERROR {
         eprintln!("arithmetic expected a number {}", sprint_snake_val(v));
     } else if err_code == CMP_TYPE_ERROR {
         eprintln!("comparison expected a number {}", sprint_snake_val(v));
-    } else if err_code == OVERFLOW_ERROR {
-        eprintln!("overflow {}", sprint_snake_val(v));
     } else if err_code == IF_TYPE_ERROR {
         eprintln!("if expected a boolean {}", sprint_snake_val(v));
     } else if err_code == LOGIC_TYPE_ERROR {
@@ -120,8 +573,45 @@ extern "sysv64" fn snake_error(err_code: ErrorCode, v: SnakeVal) {
     } else if err_code == NON_CLOSURE_ERROR {
         eprintln!("called a non-function {}", sprint_snake_val(v));
     } else if err_code == LAMBDA_ARITY_ERROR {
-        eprintln!("wrong number of arguments for lambda: {}", sprint_snake_val(v));
-    } else if err_code == 99 {
+        eprintln!(
+            "wrong number of arguments for lambda: {}",
+            sprint_snake_val(v)
+        );
+    } else if err_code == ARRAY_SIZE_ERROR {
+        eprintln!("array size not a number: {}", sprint_snake_val(v));
+    } else if err_code == NEGATIVE_SIZE_ERROR {
+        eprintln!("negative array size: {}", sprint_snake_val(v));
+    } else if err_code == OUT_OF_MEMORY {
+        eprintln!("out of memory allocating {} words", v.0);
+    } else if err_code == NON_VECTOR_ERROR {
+        eprintln!("not a vector {}", sprint_snake_val(v));
+    } else if err_code == EMPTY_VECTOR_ERROR {
+        eprintln!("pop from an empty vector {}", sprint_snake_val(v));
+    } else if err_code == MATCH_ERROR {
+        eprintln!("no pattern matched {}", sprint_snake_val(v));
+    } else if err_code == MISSING_FIELD_ERROR {
+        eprintln!("missing field {}", c_string(field));
+    } else if err_code == NON_RECORD_ERROR {
+        eprintln!("not a record {}", sprint_snake_val(v));
+    } else if err_code == NON_GENERATOR_ERROR {
+        eprintln!("not a generator {}", sprint_snake_val(v));
+    } else if err_code == GENERATOR_EXHAUSTED {
+        eprintln!("generator is exhausted");
+    } else if err_code == GENERATOR_RUNNING {
+        eprintln!("generator is already running");
+    } else if err_code == YIELD_ERROR {
+        eprintln!("yield outside of a generator {}", sprint_snake_val(v));
+    } else if err_code == NON_CHANNEL_ERROR {
+        eprintln!("not a channel {}", sprint_snake_val(v));
+    } else if err_code == NON_DICT_ERROR {
+        eprintln!("not a dict {}", sprint_snake_val(v));
+    } else if err_code == NON_THUNK_ERROR {
+        eprintln!("not a thunk {}", sprint_snake_val(v));
+    } else if err_code == DEADLOCK_ERROR {
+        eprintln!("deadlock: every thread is waiting on a channel");
+    } else if err_code == RAISE_ERROR {
+        eprintln!("uncaught exception {}", sprint_snake_val(v));
+    } else if err_code == STACK_ERROR {
         eprintln!("stack error: {:x}", v.0);
     } else {
         eprintln!("Unknown error {}", err_code);
@@ -129,10 +619,494 @@ extern "sysv64" fn snake_error(err_code: ErrorCode, v: SnakeVal) {
     std::process::exit(1);
 }
 
+// A destructuring let at [line] and [col] bound a pattern of [expected]
+// elements to [v]
+#[export_name = "\x01snake_destructure_error"]
+extern "sysv64" fn snake_destructure_error(v: SnakeVal, expected: u64, line: u64, col: u64) {
+    unwind(DESTRUCTURE_ERROR, v);
+    eprintln!(
+        "destructuring at line {}, column {} expected an array of {} elements but got {}",
+        line,
+        col,
+        expected,
+        sprint_snake_val(v)
+    );
+    std::process::exit(1);
+}
+
+// [v] didn't have the type of the annotation at [line] and [col], which
+// is Num, Bool or Array for [expected] 0, 1 or 2
+#[export_name = "\x01snake_cast_error"]
+extern "sysv64" fn snake_cast_error(v: SnakeVal, expected: u64, line: u64, col: u64) {
+    unwind(CAST_ERROR, v);
+    let expected = ["Num", "Bool", "Array"][expected as usize];
+    eprintln!(
+        "the annotation {} at line {}, column {} doesn't hold for {}",
+        expected,
+        line,
+        col,
+        sprint_snake_val(v)
+    );
+    std::process::exit(1);
+}
+
+// The assertion at [line] and [col] failed. [what] is 0 or one more than
+// the index of the comparison it made in COMPARISONS, in which case [l]
+// and [r] are its operands, plus 8 if it has a code. A try catches the
+// code as is, or ASSERT_ERROR for an assertion without one
+static COMPARISONS: [&str; 7] = ["<", ">", "<=", ">=", "==", "!=", "equal"];
+
+#[export_name = "\x01snake_assert_error"]
+extern "sysv64" fn snake_assert_error(
+    l: SnakeVal,
+    r: SnakeVal,
+    code: SnakeVal,
+    line: u64,
+    col: u64,
+    what: u64,
+) {
+    if what & 8 != 0 {
+        unsafe { snake_unwind(code) };
+    } else {
+        unwind(ASSERT_ERROR, SNAKE_FLS);
+    }
+    let mut msg = String::from("assertion failed");
+    if what & 8 != 0 {
+        msg += &format!(" with code {}", sprint_snake_val(code));
+    }
+    msg += &format!(" at line {}, column {}", line, col);
+    let (l, r) = (sprint_snake_val(l), sprint_snake_val(r));
+    match (what & 7) as usize {
+        0 => {}
+        7 => msg += &format!(": equal({}, {})", l, r),
+        i => msg += &format!(": {} {} {}", l, COMPARISONS[i - 1], r),
+    }
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
+
+fn argument_error(code: ErrorCode, msg: String) -> ! {
+    unwind(code, SNAKE_FLS);
+    eprintln!("{}", msg);
+    std::process::exit(1);
+}
+
+/* Closure calls that leave out parameters with defaults or pass keyword
+ * arguments enter the closure through here. The [count] arguments are at
+ * [top] - 8 * (i + 1) followed by the environment, whose last elements are
+ * the defaults of the last parameters before the rest parameter. Keyword
+ * arguments are passed as the last argument, laid out like a record. The
+ * arguments are moved into the slots of the parameters, then the rest
+ * array, if [variadic], then the environment. Returns [top].
+ */
+#[export_name = "\x01snake_bind_arguments"]
+extern "sysv64" fn snake_bind_arguments(
+    top: u64,
+    count: u64,
+    shape: u64,
+    required: u64,
+    variadic: u64,
+) -> u64 {
+    let slot = |i: u64| (top - 8 * (i + 1)) as *mut SnakeVal;
+    let names: Vec<String> = unsafe {
+        let fixed = *(shape as *const u64);
+        (0..fixed)
+            .map(|i| c_string(*(shape as *const u64).add(i as usize + 1)))
+            .collect()
+    };
+    let fixed = names.len();
+    let env = unsafe { *slot(count) };
+    let mut args: Vec<SnakeVal> = (0..count).map(|i| unsafe { *slot(i) }).collect();
+    let mut keywords = vec![];
+    if let Some(last) = args.last() {
+        if box_kind(*last) == Some(KEYWORDS_KIND) {
+            let (keys, values) = load_record(*last);
+            keywords = keys
+                .into_iter()
+                .enumerate()
+                .map(|(i, k)| (k, unsafe { *values.elts.add(i) }))
+                .collect();
+            args.pop();
+        }
+    }
+    if args.len() > fixed && variadic == 0 {
+        argument_error(
+            LAMBDA_ARITY_ERROR,
+            "wrong number of arguments for lambda: <closure>".to_string(),
+        );
+    }
+    let rest = args.split_off(args.len().min(fixed));
+    let mut bound: Vec<Option<SnakeVal>> = args.into_iter().map(Some).collect();
+    bound.resize(fixed, None);
+    for (k, v) in keywords {
+        match names.iter().position(|p| *p == k) {
+            None => argument_error(ARGUMENT_ERROR, format!("no parameter named {}", k)),
+            Some(i) if bound[i].is_some() => argument_error(
+                ARGUMENT_ERROR,
+                format!("parameter {} got more than one argument", k),
+            ),
+            Some(i) => bound[i] = Some(v),
+        }
+    }
+    let env_array = load_snake_array(env.0);
+    let defaults = fixed - required as usize;
+    let mut values = vec![];
+    for (i, v) in bound.into_iter().enumerate() {
+        match v {
+            Some(v) => values.push(v),
+            None if i >= required as usize => {
+                let d = env_array.size as usize - defaults + i - required as usize;
+                values.push(unsafe { *env_array.elts.add(d) })
+            }
+            None => argument_error(
+                ARGUMENT_ERROR,
+                format!("missing argument for parameter {}", names[i]),
+            ),
+        }
+    }
+    if variadic != 0 {
+        values.push(alloc_array(&rest));
+    }
+    values.push(env);
+    for (i, v) in values.into_iter().enumerate() {
+        unsafe { *slot(i as u64) = v }
+    }
+    top
+}
+
+// Makes a generator running the body of [f], a closure without parameters
+#[export_name = "\x01snake_generator"]
+extern "sysv64" fn snake_generator(f: SnakeVal) -> SnakeVal {
+    if f.0 & 0b111 != 0b11 {
+        snake_error(NON_CLOSURE_ERROR, f, 0);
+    }
+    let arity = unsafe { *((f.0 - 0b11) as *const u64).add(1) };
+    if arity != 0 {
+        snake_error(LAMBDA_ARITY_ERROR, f, 0);
+    }
+    let stack = Vec::<u64>::with_capacity(GENERATOR_STACK_WORDS);
+    let base = stack.leak().as_mut_ptr() as u64;
+    let top = (base + 8 * GENERATOR_STACK_WORDS as u64) & !0b1111;
+    alloc_box(vec![GENERATOR_KIND, 0, f.0, top, 0, 0, SNAKE_FLS.0, base])
+}
+
+// Called on the stack of the resumer once the generator [g], untagged, has
+// finished
+#[export_name = "\x01snake_generator_done"]
+extern "sysv64" fn snake_generator_done(g: u64) {
+    free_generator_stack(g);
+}
+
+// Like snake_generator_done, when the generator [g] finished because [v]
+// was raised in it and not caught. Raises [v] again in the resumer
+#[export_name = "\x01snake_generator_failed"]
+extern "sysv64" fn snake_generator_failed(g: u64, v: SnakeVal) {
+    free_generator_stack(g);
+    snake_error(RAISE_ERROR, v, 0);
+}
+
+// Makes a thread running the body of [f], a closure without parameters,
+// ready to run after the threads that already are. Evaluates to its id
+#[export_name = "\x01snake_spawn"]
+extern "sysv64" fn snake_spawn(f: SnakeVal) -> SnakeVal {
+    if f.0 & 0b111 != 0b11 {
+        snake_error(NON_CLOSURE_ERROR, f, 0);
+    }
+    let arity = unsafe { *((f.0 - 0b11) as *const u64).add(1) };
+    if arity != 0 {
+        snake_error(LAMBDA_ARITY_ERROR, f, 0);
+    }
+    let stack = Vec::<u64>::with_capacity(THREAD_STACK_WORDS);
+    let base = stack.leak().as_mut_ptr() as u64;
+    let top = (base + 8 * THREAD_STACK_WORDS as u64) & !0b1111;
+    // snake_switch pops the callee-saved registers, then returns to
+    // snake_thread_start with the closure on top of the stack
+    let rsp = top - 8 * 8;
+    unsafe {
+        let words = rsp as *mut u64;
+        for i in 0..6 {
+            *words.add(i) = 0;
+        }
+        *words.add(6) = snake_thread_start as unsafe extern "C" fn() as usize as u64;
+        *words.add(7) = f.0;
+    }
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        s.threads.push(Box::new(Thread {
+            context: Context {
+                rsp,
+                handler: 0,
+                generator: 0,
+            },
+            stack: base,
+            received: SNAKE_FLS,
+        }));
+        let id = s.threads.len() - 1;
+        s.ready.push_back(id);
+        SnakeVal((id as u64) << 1)
+    })
+}
+
+#[export_name = "\x01snake_channel"]
+extern "sysv64" fn snake_channel() -> SnakeVal {
+    let id = SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        s.channels.push(Channel {
+            values: VecDeque::new(),
+            receivers: VecDeque::new(),
+        });
+        s.channels.len() - 1
+    });
+    alloc_box(vec![CHANNEL_KIND, id as u64])
+}
+
+// Sends [v] on [c] without waiting: the first thread waiting on [c], if
+// any, receives it and is ready to run. Evaluates to [v]
+#[export_name = "\x01snake_send"]
+extern "sysv64" fn snake_send(c: SnakeVal, v: SnakeVal) -> SnakeVal {
+    let id = load_channel(c);
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        match s.channels[id].receivers.pop_front() {
+            Some(receiver) => {
+                s.threads[receiver].received = v;
+                s.ready.push_back(receiver);
+            }
+            None => s.channels[id].values.push_back(v),
+        }
+    });
+    v
+}
+
+// The first value sent on [c] not received yet. If there is none, the
+// other threads run until one is sent
+#[export_name = "\x01snake_recv"]
+extern "sysv64" fn snake_recv(c: SnakeVal) -> SnakeVal {
+    let id = load_channel(c);
+    let value = SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        let value = s.channels[id].values.pop_front();
+        if value.is_none() {
+            let current = s.current;
+            s.channels[id].receivers.push_back(current);
+        }
+        value
+    });
+    match value {
+        Some(v) => v,
+        None => {
+            run_next_thread();
+            SCHEDULER.with(|s| {
+                let s = s.borrow();
+                s.threads[s.current].received
+            })
+        }
+    }
+}
+
+// Called on the stack of the current thread once its closure returned.
+// Never returns
+#[export_name = "\x01snake_thread_done"]
+extern "sysv64" fn snake_thread_done() {
+    SCHEDULER.with(|s| {
+        let mut s = s.borrow_mut();
+        let stack = s.threads[s.current].stack;
+        s.finished.push(stack);
+    });
+    run_next_thread();
+    unreachable!()
+}
+
+#[export_name = "\x01snake_dict"]
+extern "sysv64" fn snake_dict() -> SnakeVal {
+    let dict = Box::new(Dict {
+        entries: vec![],
+        buckets: vec![vec![]; 8],
+    });
+    alloc_box(vec![DICT_KIND, Box::leak(dict) as *mut Dict as u64])
+}
+
+// The value of [key] in [d], [default] if [d] has no such key
+#[export_name = "\x01snake_dict_get"]
+extern "sysv64" fn snake_dict_get(d: SnakeVal, key: SnakeVal, default: SnakeVal) -> SnakeVal {
+    let dict = unsafe { &*load_dict(d) };
+    match dict.find(key, hash_of(key)) {
+        Some(i) => dict.entries[i].value,
+        None => default,
+    }
+}
+
+// Evaluates to [d]
+#[export_name = "\x01snake_dict_put"]
+extern "sysv64" fn snake_dict_put(d: SnakeVal, key: SnakeVal, value: SnakeVal) -> SnakeVal {
+    unsafe { &mut *load_dict(d) }.put(key, value);
+    d
+}
+
+#[export_name = "\x01snake_dict_has"]
+extern "sysv64" fn snake_dict_has(d: SnakeVal, key: SnakeVal) -> SnakeVal {
+    let dict = unsafe { &*load_dict(d) };
+    snake_bool(dict.find(key, hash_of(key)).is_some())
+}
+
+// A new array of the keys of [d], in the order they were first put
+#[export_name = "\x01snake_dict_keys"]
+extern "sysv64" fn snake_dict_keys(d: SnakeVal) -> SnakeVal {
+    let dict = unsafe { &*load_dict(d) };
+    let keys: Vec<SnakeVal> = dict.entries.iter().map(|e| e.key).collect();
+    alloc_array(&keys)
+}
+
+/* Thunks are laid out as [THUNK_KIND, closure or value, forced] where
+ * the closure, which has no parameters, is replaced by the value it
+ * computed once the thunk is forced. The compiled code calls the closure
+ * itself, see desugar.rs.
+ */
+fn load_thunk(t: SnakeVal) -> *mut u64 {
+    if box_kind(t) != Some(THUNK_KIND) {
+        snake_error(NON_THUNK_ERROR, t, 0);
+    }
+    (t.0 - BOX_TAG) as *mut u64
+}
+
+#[export_name = "\x01snake_delay"]
+extern "sysv64" fn snake_delay(f: SnakeVal) -> SnakeVal {
+    if f.0 & 0b111 != 0b11 {
+        snake_error(NON_CLOSURE_ERROR, f, 0);
+    }
+    let arity = unsafe { *((f.0 - 0b11) as *const u64).add(1) };
+    if arity != 0 {
+        snake_error(LAMBDA_ARITY_ERROR, f, 0);
+    }
+    alloc_box(vec![THUNK_KIND, f.0, SNAKE_FLS.0])
+}
+
+#[export_name = "\x01snake_forced"]
+extern "sysv64" fn snake_forced(t: SnakeVal) -> SnakeVal {
+    SnakeVal(unsafe { *load_thunk(t).add(2) })
+}
+
+// Keeps [v] as the value of [t], unless forcing [t] forced it already in
+// the meantime. Evaluates to the value [t] keeps
+#[export_name = "\x01snake_set_thunk"]
+extern "sysv64" fn snake_set_thunk(t: SnakeVal, v: SnakeVal) -> SnakeVal {
+    let thunk = load_thunk(t);
+    unsafe {
+        if *thunk.add(2) != SNAKE_TRU.0 {
+            *thunk.add(1) = v.0;
+            *thunk.add(2) = SNAKE_TRU.0;
+        }
+        SnakeVal(*thunk.add(1))
+    }
+}
+
+fn num_or_error(x: SnakeVal, err_code: ErrorCode) -> BigInt {
+    match to_bignum(x) {
+        Some(n) => n,
+        None => {
+            snake_error(err_code, x, 0);
+            unreachable!()
+        }
+    }
+}
+
+// Slow path of +, - and *: called when an operand isn't a 63-bit
+// number or when the inline operation overflowed
+#[export_name = "\x01snake_arith"]
+extern "sysv64" fn snake_arith(op: u64, a: SnakeVal, b: SnakeVal) -> SnakeVal {
+    let n1 = num_or_error(a, ARITH_TYPE_ERROR);
+    let n2 = num_or_error(b, ARITH_TYPE_ERROR);
+    match op {
+        0 => snake_num(n1.add(&n2)),
+        1 => snake_num(n1.sub(&n2)),
+        2 => snake_num(n1.mul(&n2)),
+        _ => panic!("unknown arithmetic operation {}", op),
+    }
+}
+
+// Slow path of <, >, <= and >=
+#[export_name = "\x01snake_cmp"]
+extern "sysv64" fn snake_cmp(op: u64, a: SnakeVal, b: SnakeVal) -> SnakeVal {
+    let n1 = num_or_error(a, CMP_TYPE_ERROR);
+    let n2 = num_or_error(b, CMP_TYPE_ERROR);
+    snake_bool(match op {
+        0 => n1 < n2,
+        1 => n1 > n2,
+        2 => n1 <= n2,
+        3 => n1 >= n2,
+        _ => panic!("unknown comparison {}", op),
+    })
+}
+
+// Called by == when the two words differ but might both be bignums
+#[export_name = "\x01snake_num_eq"]
+extern "sysv64" fn snake_num_eq(a: SnakeVal, b: SnakeVal) -> SnakeVal {
+    if box_kind(a) == Some(BIGNUM_KIND) && box_kind(b) == Some(BIGNUM_KIND) {
+        snake_bool(load_bignum(a) == load_bignum(b))
+    } else {
+        SNAKE_FLS
+    }
+}
+
+fn equal_inner(a: SnakeVal, b: SnakeVal, visited: &mut HashSet<(u64, u64)>) -> bool {
+    if a == b {
+        return true;
+    }
+    // an array is never equal to a vector
+    let same_shape = a.0 & 0b111 == b.0 & 0b111 && box_kind(a) == box_kind(b);
+    if let (true, Some(arr1), Some(arr2)) = (same_shape, load_sequence(a), load_sequence(b)) {
+        // pairs of arrays already being compared are assumed equal
+        if !visited.insert((a.0, b.0)) {
+            return true;
+        }
+        if arr1.size != arr2.size {
+            return false;
+        }
+        for i in 0..arr1.size as usize {
+            let (x, y) = unsafe { (*arr1.elts.add(i), *arr2.elts.add(i)) };
+            if !equal_inner(x, y, visited) {
+                return false;
+            }
+        }
+        return true;
+    }
+    // records are equal when they have the same shape and equal fields
+    let described = [Some(DATA_KIND), Some(RECORD_KIND)];
+    if described.contains(&box_kind(a)) && box_kind(a) == box_kind(b) {
+        if !visited.insert((a.0, b.0)) {
+            return true;
+        }
+        let ((d1, fields1), (d2, fields2)) = (load_data(a), load_data(b));
+        return d1 == d2
+            && (0..fields1.size as usize).all(|i| {
+                let (x, y) = unsafe { (*fields1.elts.add(i), *fields2.elts.add(i)) };
+                equal_inner(x, y, visited)
+            });
+    }
+    snake_num_eq(a, b) == SNAKE_TRU
+}
+
+// Structural equality: numbers by value, arrays and vectors element-wise (with
+// cycle detection), data by constructor and fields, records by shape and fields
+// and everything else, including closures, by identity
+#[export_name = "\x01snake_equal"]
+extern "sysv64" fn snake_equal(a: SnakeVal, b: SnakeVal) -> SnakeVal {
+    snake_bool(equal_inner(a, b, &mut HashSet::new()))
+}
+
 #[link(name = "compiled_code", kind = "static")]
 extern "C" {
     #[link_name = "\x01start_here"]
     fn start_here() -> SnakeVal;
+    #[link_name = "\x01snake_unwind"]
+    fn snake_unwind(v: SnakeVal);
+    #[link_name = "\x01snake_switch"]
+    fn snake_switch(from: *mut Context, to: *const Context);
+    #[link_name = "\x01snake_thread_start"]
+    fn snake_thread_start();
+    // the descriptor of RuntimeError
+    #[link_name = "\x01snake_error_ctor"]
+    static snake_error_ctor: u64;
 }
 
 fn main() {
@@ -109,6 +109,7 @@ pub enum Instr {
     Jle(JmpArg),
     Jg(JmpArg),
     Jge(JmpArg),
+    Ja(JmpArg), // unsigned
 
     Jz(JmpArg),
     Jnz(JmpArg),
@@ -295,6 +296,9 @@ fn instr_to_string(i: &Instr) -> String {
         Instr::Jge(s) => {
             format!("        jge {}", jmp_arg_to_string(s))
         }
+        Instr::Ja(s) => {
+            format!("        ja {}", jmp_arg_to_string(s))
+        }
         Instr::Jz(s) => {
             format!("        jz {}", jmp_arg_to_string(s))
         }
@@ -0,0 +1,190 @@
+/* Arbitrary-precision integers.
+ *
+ * This module has no dependencies outside of std so that it can be
+ * shared between the compiler crate (the reference interpreter) and
+ * runtime/stub.rs, which is built with a bare `rustc` invocation and
+ * pulls this file in with a #[path] attribute.
+ */
+use std::cmp::Ordering;
+use std::fmt;
+
+// Sign-magnitude representation. The magnitude is stored as
+// little-endian 64-bit limbs with no trailing zero limbs, so zero is
+// the empty vector (and is never negative).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BigInt {
+    neg: bool,
+    mag: Vec<u64>,
+}
+
+fn trim(mag: &mut Vec<u64>) {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+}
+
+fn cmp_mag(a: &[u64], b: &[u64]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_mag(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut res = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u128;
+    for (i, x) in long.iter().enumerate() {
+        let sum = *x as u128 + *short.get(i).unwrap_or(&0) as u128 + carry;
+        res.push(sum as u64);
+        carry = sum >> 64;
+    }
+    if carry != 0 {
+        res.push(carry as u64);
+    }
+    res
+}
+
+// requires |a| >= |b|
+fn sub_mag(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut res = Vec::with_capacity(a.len());
+    let mut borrow = false;
+    for (i, x) in a.iter().enumerate() {
+        let (d1, b1) = x.overflowing_sub(*b.get(i).unwrap_or(&0));
+        let (d2, b2) = d1.overflowing_sub(borrow as u64);
+        res.push(d2);
+        borrow = b1 || b2;
+    }
+    trim(&mut res);
+    res
+}
+
+fn mul_mag(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut res = vec![0u64; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u128;
+        for (j, y) in b.iter().enumerate() {
+            let cur = res[i + j] as u128 + (*x as u128) * (*y as u128) + carry;
+            res[i + j] = cur as u64;
+            carry = cur >> 64;
+        }
+        res[i + b.len()] = carry as u64;
+    }
+    trim(&mut res);
+    res
+}
+
+impl BigInt {
+    pub fn from_i64(n: i64) -> BigInt {
+        let mut mag = vec![n.unsigned_abs()];
+        trim(&mut mag);
+        BigInt { neg: n < 0, mag }
+    }
+
+    // Rebuilds a number from the words produced by to_parts
+    pub fn from_parts(neg: bool, mut mag: Vec<u64>) -> BigInt {
+        trim(&mut mag);
+        let neg = neg && !mag.is_empty();
+        BigInt { neg, mag }
+    }
+
+    pub fn to_parts(&self) -> (bool, &[u64]) {
+        (self.neg, &self.mag)
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        match self.mag.len() {
+            0 => Some(0),
+            1 => {
+                let m = self.mag[0];
+                if self.neg {
+                    if m <= i64::MAX as u64 + 1 {
+                        Some((m as i64).wrapping_neg())
+                    } else {
+                        None
+                    }
+                } else if m <= i64::MAX as u64 {
+                    Some(m as i64)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::from_parts(!self.neg, self.mag.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.neg == other.neg {
+            return BigInt::from_parts(self.neg, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Equal => BigInt::from_i64(0),
+            Ordering::Greater => BigInt::from_parts(self.neg, sub_mag(&self.mag, &other.mag)),
+            Ordering::Less => BigInt::from_parts(other.neg, sub_mag(&other.mag, &self.mag)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::from_parts(self.neg != other.neg, mul_mag(&self.mag, &other.mag))
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        static CHUNK: u128 = 10_000_000_000_000_000_000; // 10^19
+        if self.mag.is_empty() {
+            return write!(f, "0");
+        }
+        // repeatedly divide the magnitude by 10^19, collecting remainders
+        let mut digits = vec![];
+        let mut cur = self.mag.clone();
+        while !cur.is_empty() {
+            let mut rem = 0u128;
+            for limb in cur.iter_mut().rev() {
+                let acc = (rem << 64) | *limb as u128;
+                *limb = (acc / CHUNK) as u64;
+                rem = acc % CHUNK;
+            }
+            trim(&mut cur);
+            digits.push(rem as u64);
+        }
+        if self.neg {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", digits.pop().unwrap())?;
+        for d in digits.iter().rev() {
+            write!(f, "{:019}", d)?;
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,38 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use crate::{compile::CompileErr, syntax::*};
 
-static I63_MAX: i64 = 0x3F_FF_FF_FF_FF_FF_FF_FF;
-static I63_MIN: i64 = -0x40_00_00_00_00_00_00_00;
-
 pub fn check_prog<Span>(
     e: &Exp<Span>,
     symbols: &HashSet<String>,
 ) -> Result<(), CompileErr<Span>>
+where
+    Span: Clone,
+{
+    // programs can match runtime errors but not build them
+    let error = runtime_error_ctor();
+    let ctors = HashMap::from([(error.name, error.fields.len())]);
+    check_exp(e, symbols, &HashSet::new(), &ctors, &HashMap::new())
+}
+
+// [assignable] are the variables in [symbols] that are let-bound,
+// [ctors] maps the constructors in scope to their number of fields, a
+// variable with the same name takes a constructor out of scope. [sigs]
+// maps the functions in scope to the annotated types of the parameters
+// that aren't the rest parameter, and is scoped like [ctors]
+fn check_exp<Span>(
+    e: &Exp<Span>,
+    symbols: &HashSet<String>,
+    assignable: &HashSet<String>,
+    ctors: &HashMap<String, usize>,
+    sigs: &HashMap<String, Vec<Option<TypeAnn>>>,
+) -> Result<(), CompileErr<Span>>
 where
     Span: Clone,
 {
     match e {
-        Exp::Num(i, ann) => {
-            if *i > I63_MAX || *i < I63_MIN {
-                return Err(CompileErr::Overflow {
-                    num: *i,
-                    location: ann.clone(),
-                });
-            }
-            Ok(())
-        }
+        // literals that don't fit into 63 bits become bignums
+        Exp::Num(_, _) => Ok(()),
         Exp::Var(name, ann) => {
             if !symbols.contains(name) {
                 return Err(CompileErr::UnboundVariable {
@@ -31,9 +42,15 @@ where
             }
             Ok(())
         }
+        // a cast that is known to fail, e.g. of the result of
+        // def f(x): Bool: x + 1
+        Exp::Prim(Prim::Cast(expected, _, _), exps, _) => {
+            check_exp(&exps[0], symbols, assignable, ctors, sigs)?;
+            check_known_type(&exps[0], *expected)
+        }
         Exp::Prim(_, exps, _) => {
             for e in exps {
-                check_prog(e, symbols)?;
+                check_exp(e, symbols, assignable, ctors, sigs)?;
             }
             Ok(())
         }
@@ -43,6 +60,9 @@ where
             ann,
         } => {
             let mut scoped_symbols = symbols.clone();
+            let mut scoped_assignable = assignable.clone();
+            let mut scoped_ctors = ctors.clone();
+            let mut scoped_sigs = sigs.clone();
             let mut appeared = HashSet::new();
             for (name, value) in bindings {
                 if appeared.contains(name) {
@@ -53,9 +73,18 @@ where
                 }
                 appeared.insert(name);
                 scoped_symbols.insert(name.clone());
-                check_prog(value, &scoped_symbols)?;
+                scoped_assignable.insert(name.clone());
+                scoped_ctors.remove(name);
+                scoped_sigs.remove(name);
+                check_exp(
+                    value,
+                    &scoped_symbols,
+                    &scoped_assignable,
+                    &scoped_ctors,
+                    &scoped_sigs,
+                )?;
             }
-            check_prog(body, &scoped_symbols)
+            check_exp(body, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
         }
         Exp::Bool(_, _) => Ok(()),
         Exp::If {
@@ -64,15 +93,21 @@ where
             els,
             ann,
         } => {
-            check_prog(cond, symbols)?;
-            check_prog(&thn, symbols)?;
-            check_prog(&els, symbols)?;
+            check_exp(cond, symbols, assignable, ctors, sigs)?;
+            check_exp(&thn, symbols, assignable, ctors, sigs)?;
+            check_exp(&els, symbols, assignable, ctors, sigs)?;
             Ok(())
         }
         Exp::FunDefs { decls, body, ann } => {
             let mut scoped_symbols = symbols.clone();
+            let mut scoped_assignable = assignable.clone();
+            let mut scoped_ctors = ctors.clone();
+            let mut scoped_sigs = sigs.clone();
             let mut mutual_funcs = HashSet::<String>::new();
             for decl in decls {
+                for d in &decl.defaults {
+                    check_exp(d, symbols, assignable, ctors, sigs)?;
+                }
                 if mutual_funcs.contains(&decl.name) {
                     return Err(CompileErr::DuplicateFunName {
                         duplicated_name: decl.name.clone(),
@@ -81,19 +116,105 @@ where
                 }
                 mutual_funcs.insert(decl.name.clone());
                 scoped_symbols.insert(decl.name.clone());
+                scoped_assignable.remove(&decl.name);
+                scoped_ctors.remove(&decl.name);
+                let mut param_types: Vec<_> =
+                    decl.param_types.iter().map(|t| t.as_ref().map(|(t, _)| *t)).collect();
+                if decl.variadic {
+                    param_types.pop();
+                }
+                scoped_sigs.insert(decl.name.clone(), param_types);
             }
             for decl in decls {
+                let mut decl_assignable = scoped_assignable.clone();
+                let mut decl_ctors = scoped_ctors.clone();
+                let mut decl_sigs = scoped_sigs.clone();
                 for param in &decl.parameters {
                     scoped_symbols.insert(param.clone());
+                    decl_assignable.remove(param);
+                    decl_ctors.remove(param);
+                    decl_sigs.remove(param);
+                }
+                check_exp(
+                    &decl.body,
+                    &scoped_symbols,
+                    &decl_assignable,
+                    &decl_ctors,
+                    &decl_sigs,
+                )?;
+            }
+            check_exp(body, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
+        }
+        // constructors are bound like functions, and can't be assigned either
+        Exp::DataDefs {
+            ctors: decls,
+            body,
+            ann,
+            ..
+        } => {
+            let mut scoped_symbols = symbols.clone();
+            let mut scoped_assignable = assignable.clone();
+            let mut scoped_ctors = ctors.clone();
+            let mut scoped_sigs = sigs.clone();
+            let mut appeared = HashSet::new();
+            for ctor in decls {
+                if !appeared.insert(&ctor.name) {
+                    return Err(CompileErr::DuplicateConstructor {
+                        duplicated_name: ctor.name.clone(),
+                        location: ann.clone(),
+                    });
+                }
+                let mut fields = HashSet::new();
+                for field in &ctor.fields {
+                    if !fields.insert(field) {
+                        return Err(CompileErr::DuplicateArgName {
+                            duplicated_name: field.clone(),
+                            location: ann.clone(),
+                        });
+                    }
                 }
-                check_prog(&decl.body, &scoped_symbols)?;
+                scoped_symbols.insert(ctor.name.clone());
+                scoped_assignable.remove(&ctor.name);
+                scoped_ctors.insert(ctor.name.clone(), ctor.fields.len());
+                scoped_sigs.remove(&ctor.name);
             }
-            check_prog(body, &scoped_symbols)
+            check_exp(body, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
         }
+        // the arguments of a function with annotations that are known to
+        // fail its casts
         Exp::Call(func, params, ann) => {
-            check_prog(func, symbols)?;
+            check_exp(func, symbols, assignable, ctors, sigs)?;
             for p in params {
-                check_prog(p, &symbols)?;
+                check_exp(p, &symbols, assignable, ctors, sigs)?;
+            }
+            if let Exp::Var(name, _) = &**func {
+                for (p, t) in params.iter().zip(sigs.get(name).into_iter().flatten()) {
+                    if let Some(t) = t {
+                        check_known_type(p, *t)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ann,
+        } => {
+            check_exp(fun, symbols, assignable, ctors, sigs)?;
+            for a in args {
+                check_exp(a, symbols, assignable, ctors, sigs)?;
+            }
+            let mut names = HashSet::new();
+            for (name, value) in keywords {
+                if !names.insert(name) {
+                    return Err(CompileErr::DuplicateArgName {
+                        duplicated_name: name.clone(),
+                        location: ann.clone(),
+                    });
+                }
+                check_exp(value, symbols, assignable, ctors, sigs)?;
             }
             Ok(())
         }
@@ -104,36 +225,224 @@ where
             ann,
             fun,
         } => todo!(),
+        Exp::LetPattern { .. } => unreachable!("rewritten into a Let by desugar"),
         Exp::Semicolon { e1, e2, ann } => {
-            check_prog(e1, symbols)?;
-            check_prog(
+            check_exp(e1, symbols, assignable, ctors, sigs)?;
+            check_exp(
                 &Exp::Let {
                     bindings: vec![("don't care".to_string(), *e1.clone())],
                     body: e2.clone(),
                     ann: ann.clone(),
                 },
                 symbols,
+                assignable,
+                ctors,
+                sigs,
             )?;
             Ok(())
         }
+        Exp::Assign { var, value, ann } => {
+            if !symbols.contains(var) {
+                return Err(CompileErr::UnboundVariable {
+                    unbound: var.clone(),
+                    location: ann.clone(),
+                });
+            }
+            if !assignable.contains(var) {
+                return Err(CompileErr::AssignToNonLocal {
+                    name: var.clone(),
+                    location: ann.clone(),
+                });
+            }
+            check_exp(value, symbols, assignable, ctors, sigs)
+        }
+        Exp::While { cond, body, .. } => {
+            check_exp(cond, symbols, assignable, ctors, sigs)?;
+            check_exp(body, symbols, assignable, ctors, sigs)
+        }
+        // the caught value is bound like a parameter
+        Exp::Try {
+            body, var, handler, ..
+        } => {
+            check_exp(body, symbols, assignable, ctors, sigs)?;
+            let mut scoped_symbols = symbols.clone();
+            let mut scoped_assignable = assignable.clone();
+            let mut scoped_ctors = ctors.clone();
+            let mut scoped_sigs = sigs.clone();
+            scoped_symbols.insert(var.clone());
+            scoped_assignable.remove(var);
+            scoped_ctors.remove(var);
+            scoped_sigs.remove(var);
+            check_exp(handler, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
+        }
+        Exp::ForRange {
+            var,
+            start,
+            end,
+            body,
+            ..
+        } => {
+            check_exp(start, symbols, assignable, ctors, sigs)?;
+            check_exp(end, symbols, assignable, ctors, sigs)?;
+            check_loop_body(var, body, symbols, assignable, ctors, sigs)
+        }
+        Exp::ForEach { var, array, body, .. } => {
+            check_exp(array, symbols, assignable, ctors, sigs)?;
+            check_loop_body(var, body, symbols, assignable, ctors, sigs)
+        }
+        // pattern variables are bound like the variables of a let
+        Exp::Match {
+            scrutinee,
+            arms,
+            ann,
+        } => {
+            check_exp(scrutinee, symbols, assignable, ctors, sigs)?;
+            for (pattern, body) in arms {
+                let mut scoped_symbols = symbols.clone();
+                let mut scoped_assignable = assignable.clone();
+                let mut scoped_sigs = sigs.clone();
+                let mut appeared = HashSet::new();
+                pattern_vars(pattern, &mut appeared, ctors, ann)?;
+                for name in appeared {
+                    scoped_symbols.insert(name.clone());
+                    scoped_sigs.remove(&name);
+                    scoped_assignable.insert(name);
+                }
+                check_exp(body, &scoped_symbols, &scoped_assignable, ctors, &scoped_sigs)?;
+            }
+            Ok(())
+        }
+        Exp::Record { fields, ann } => {
+            let mut appeared = HashSet::new();
+            for (field, value) in fields {
+                if !appeared.insert(field) {
+                    return Err(CompileErr::DuplicateField {
+                        duplicated_name: field.clone(),
+                        location: ann.clone(),
+                    });
+                }
+                check_exp(value, symbols, assignable, ctors, sigs)?;
+            }
+            Ok(())
+        }
+        Exp::GetField { record, .. } => check_exp(record, symbols, assignable, ctors, sigs),
+        Exp::SetField { record, value, .. } => {
+            check_exp(record, symbols, assignable, ctors, sigs)?;
+            check_exp(value, symbols, assignable, ctors, sigs)
+        }
         Exp::Lambda {
             parameters,
+            defaults,
             body,
-            ann,
+            ..
         } => {
+            for d in defaults {
+                check_exp(d, symbols, assignable, ctors, sigs)?;
+            }
             let mut scoped_symbols = symbols.clone();
+            let mut scoped_assignable = assignable.clone();
+            let mut scoped_ctors = ctors.clone();
+            let mut scoped_sigs = sigs.clone();
             for p in parameters {
                 scoped_symbols.insert(p.clone());
+                scoped_assignable.remove(p);
+                scoped_ctors.remove(p);
+                scoped_sigs.remove(p);
             }
-            check_prog(body, &scoped_symbols)
+            check_exp(body, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
         }
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => todo!(),
+        Exp::MakeClosure { .. } => todo!(),
         Exp::ClosureCall(_, _, _) => todo!(),
         Exp::DirectCall(_, _, _) => todo!(),
     }
 }
+
+// the loop variable of a for is in scope in the body but can't be assigned
+fn check_loop_body<Span>(
+    var: &str,
+    body: &Exp<Span>,
+    symbols: &HashSet<String>,
+    assignable: &HashSet<String>,
+    ctors: &HashMap<String, usize>,
+    sigs: &HashMap<String, Vec<Option<TypeAnn>>>,
+) -> Result<(), CompileErr<Span>>
+where
+    Span: Clone,
+{
+    let mut scoped_symbols = symbols.clone();
+    let mut scoped_assignable = assignable.clone();
+    scoped_symbols.insert(var.to_string());
+    scoped_assignable.remove(var);
+    let mut scoped_ctors = ctors.clone();
+    scoped_ctors.remove(var);
+    let mut scoped_sigs = sigs.clone();
+    scoped_sigs.remove(var);
+    check_exp(body, &scoped_symbols, &scoped_assignable, &scoped_ctors, &scoped_sigs)
+}
+
+// Fails if [e] is known to have another type than [expected]
+fn check_known_type<Span>(e: &Exp<Span>, expected: TypeAnn) -> Result<(), CompileErr<Span>>
+where
+    Span: Clone,
+{
+    match e.known_type() {
+        Some(found) if found != expected => Err(CompileErr::AnnotationMismatch {
+            expected,
+            found,
+            location: e.ann().clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn pattern_vars<Span>(
+    pattern: &Pattern,
+    appeared: &mut HashSet<String>,
+    ctors: &HashMap<String, usize>,
+    ann: &Span,
+) -> Result<(), CompileErr<Span>>
+where
+    Span: Clone,
+{
+    match pattern {
+        Pattern::Var(name) => {
+            if !appeared.insert(name.clone()) {
+                return Err(CompileErr::DuplicatePatternVar {
+                    duplicated_name: name.clone(),
+                    location: ann.clone(),
+                });
+            }
+            Ok(())
+        }
+        Pattern::Array(patterns) => {
+            for p in patterns {
+                pattern_vars(p, appeared, ctors, ann)?;
+            }
+            Ok(())
+        }
+        Pattern::Constructor(name, patterns) => {
+            match ctors.get(name) {
+                None => {
+                    return Err(CompileErr::UnknownConstructor {
+                        name: name.clone(),
+                        location: ann.clone(),
+                    })
+                }
+                Some(&arity) if arity != patterns.len() => {
+                    return Err(CompileErr::ConstructorArity {
+                        name: name.clone(),
+                        expected: arity,
+                        got: patterns.len(),
+                        location: ann.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+            for p in patterns {
+                pattern_vars(p, appeared, ctors, ann)?;
+            }
+            Ok(())
+        }
+        Pattern::Num(_) | Pattern::Bool(_) | Pattern::Wildcard => Ok(()),
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+
+// Comments are lexed before the rest of a source file:
+//
+//   # a line comment, up to the end of the line
+//   #| a block comment, #| which may nest |# and span lines |#
+//   ## a doc comment, for the def right after it
+//
+// strip blanks them out, keeping the newlines, so the parser sees them as
+// whitespace and every token keeps its offset in the file. Spans are thus
+// the same with and without comments. The lines of consecutive doc
+// comments make up one doc comment.
+pub struct Stripped {
+    pub source: String,
+    // the doc comments by the offset of the token right after them
+    pub docs: HashMap<usize, String>,
+}
+
+// Fails with the offset of a block comment that isn't closed
+pub fn strip(src: &str) -> Result<Stripped, usize> {
+    let bytes = src.as_bytes();
+    let mut source = bytes.to_vec();
+    let mut docs = HashMap::new();
+    let mut doc: Vec<&str> = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let end = match (bytes[i], bytes.get(i + 1)) {
+            (b'#', Some(b'|')) => block_end(bytes, i).ok_or(i)?,
+            (b'#', next) => {
+                let end = src[i..].find('\n').map_or(src.len(), |n| i + n);
+                if next == Some(&b'#') {
+                    let line = &src[i + 2..end];
+                    doc.push(line.strip_prefix(' ').unwrap_or(line).trim_end());
+                }
+                end
+            }
+            (c, _) if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            (c, _) => {
+                if !doc.is_empty() {
+                    docs.insert(i, doc.join("\n"));
+                    doc.clear();
+                }
+                // a # in a string is part of the string
+                if c == b'"' {
+                    i += src[i + 1..].find('"').map_or(src.len() - i, |n| n + 2);
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+        };
+        for b in &mut source[i..end] {
+            if *b != b'\n' {
+                *b = b' ';
+            }
+        }
+        i = end;
+    }
+    Ok(Stripped {
+        // only whole characters were blanked out
+        source: String::from_utf8(source).unwrap(),
+        docs,
+    })
+}
+
+// The offset right after the block comment starting at [start], if it is
+// closed
+fn block_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = start;
+    while i + 1 < bytes.len() {
+        match (bytes[i], bytes[i + 1]) {
+            (b'#', b'|') => depth += 1,
+            (b'|', b'#') => depth -= 1,
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+        i += 2;
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
@@ -2,11 +2,14 @@ use crate::asm::{instrs_to_string, JmpArg, Offset};
 use crate::asm::{Arg32, Arg64, BinArgs, Instr, MemRef, MovArgs, Reg, Reg32};
 use crate::checker;
 use crate::error_handler::*;
-use crate::lambda_lift::lambda_lift;
+use crate::generator::*;
+use crate::lambda_lift::{lambda_lift, Tables};
 use crate::sequentializer;
 use crate::syntax::{
-    Exp, FunDecl, ImmExp, Prim, SeqExp, SeqProg, SurfFunDecl, SurfProg, VarOrLabel,
+    ErrorCode, Exp, FunDecl, ImmExp, Prim, SeqExp, SeqProg, SurfFunDecl, SurfProg, TypeAnn,
+    VarOrLabel,
 };
+use crate::thread::*;
 
 use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
@@ -41,6 +44,82 @@ pub enum CompileErr<Span> {
         duplicated_name: String,
         location: Span,
     },
+
+    // only let-bound variables can be assigned, not parameters or functions
+    AssignToNonLocal {
+        name: String,
+        location: Span,
+    },
+    DuplicatePatternVar {
+        duplicated_name: String,
+        location: Span,
+    },
+
+    // The Span here is the Span of the data declaration
+    DuplicateConstructor {
+        duplicated_name: String,
+        location: Span,
+    },
+    // a pattern names a constructor that isn't in scope
+    UnknownConstructor {
+        name: String,
+        location: Span,
+    },
+    // a pattern has the wrong number of fields for its constructor
+    ConstructorArity {
+        name: String,
+        expected: usize,
+        got: usize,
+        location: Span,
+    },
+
+    // The Span here is the Span of the record literal
+    DuplicateField {
+        duplicated_name: String,
+        location: Span,
+    },
+
+    // The Span here is the Span of the import
+    UnknownImport {
+        name: String,
+        module: String,
+        location: Span,
+    },
+    DuplicateImport {
+        name: String,
+        location: Span,
+    },
+    // the module imports a module that imports it, directly or not
+    ImportCycle {
+        module: String,
+        location: Span,
+    },
+
+    // Only with --typecheck. The types name their variables 'a, 'b, ...
+    // the same way in expected and found
+    TypeMismatch {
+        expected: String,
+        found: String,
+        location: Span,
+    },
+    // the type variable would have to contain itself
+    InfiniteType {
+        var: String,
+        ty: String,
+        location: Span,
+    },
+    // the typechecker only covers numbers, booleans, arrays and functions
+    Untypable {
+        construct: String,
+        location: Span,
+    },
+    // an expression at [location] of a type known to differ from the
+    // annotation it's checked against
+    AnnotationMismatch {
+        expected: TypeAnn,
+        found: TypeAnn,
+        location: Span,
+    },
 }
 
 pub fn check_prog<Span>(p: &SurfProg<Span>) -> Result<(), CompileErr<Span>>
@@ -80,7 +159,7 @@ fn imm_to_arg64(imm: &ImmExp, vars: &HashMap<String, i32>) -> Arg64 {
     }
 }
 
-fn sub_for_cmp(exps: &Vec<ImmExp>, vars: &HashMap<String, i32>, reverse: bool) -> Vec<Instr> {
+fn sub_for_cmp(exps: &[ImmExp], vars: &HashMap<String, i32>, reverse: bool) -> Vec<Instr> {
     let mut res = vec![];
     if reverse {
         // exps[1] - exps[0]
@@ -125,70 +204,889 @@ fn is_non_neg() -> Vec<Instr> {
     ]
 }
 
-fn arith_check(reg: Reg) -> Vec<Instr> {
+// The checks leave the value in Rcx, which the error reports
+fn cmp_check(reg: Reg) -> Vec<Instr> {
     vec![
         Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(reg))),
-        Instr::And(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Je(JmpArg::Label(ARITH_ERROR.to_string())),
+        Instr::Test(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+        Instr::Jnz(JmpArg::Label(CMP_ERROR.to_string())),
     ]
 }
 
-fn cmp_check(reg: Reg) -> Vec<Instr> {
+fn logic_check(reg: Reg) -> Vec<Instr> {
     vec![
         Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(reg))),
-        Instr::And(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Je(JmpArg::Label(CMP_ERROR.to_string())),
+        Instr::Test(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+        Instr::Jz(JmpArg::Label(LOGIC_ERROR.to_string())),
     ]
 }
 
-fn logic_check(reg: Reg) -> Vec<Instr> {
+fn if_check(reg: Reg) -> Vec<Instr> {
     vec![
         Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(reg))),
-        Instr::And(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(0))),
-        Instr::Je(JmpArg::Label(LOGIC_ERROR.to_string())),
+        Instr::Test(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+        Instr::Jz(JmpArg::Label(IF_ERROR.to_string())),
     ]
 }
 
-fn if_check(reg: Reg) -> Vec<Instr> {
+// Heap objects tagged with BOX_TAG start with a word identifying their kind
+static BOX_TAG: u32 = 0b101;
+static BIGNUM_KIND: u32 = 0;
+// [VECTOR_KIND, length, untagged address of the backing array]
+static VECTOR_KIND: u32 = 1;
+// [DATA_KIND, address of the constructor's descriptor, fields...]
+// A descriptor is the number of fields followed by the constructor's name
+static DATA_KIND: u32 = 2;
+// [RECORD_KIND, address of the record's shape, fields...]
+static RECORD_KIND: u32 = 3;
+// Keyword arguments are laid out like records and passed after the
+// positional arguments of a closure call
+static KEYWORDS_KIND: u32 = 6;
+
+// Calls [name] in the runtime with [args] placed in the sysv64 argument
+// registers. The arguments are loaded before moving rsp, so they may
+// refer to stack variables. The runtime may report an error, which
+// unwinds to the innermost try, so the heap pointer is saved first.
+fn call_runtime(name: &str, args: &[Arg64], stack: i32) -> Vec<Instr> {
+    static ARG_REGS: [Reg; 6] = [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+    let mut res = save_heap_pointer();
+    res.extend(
+        args.iter()
+            .zip(ARG_REGS.iter())
+            .map(|(arg, reg)| Instr::Mov(MovArgs::ToReg(*reg, arg.clone()))),
+    );
+    res.extend(vec![
+        Instr::Sub(BinArgs::ToReg(
+            Reg::Rsp,
+            Arg32::Signed(align_stack(stack) + 8),
+        )),
+        Instr::Call(JmpArg::Label(name.to_string())),
+        Instr::Add(BinArgs::ToReg(
+            Reg::Rsp,
+            Arg32::Signed(align_stack(stack) + 8),
+        )),
+    ]);
+    res
+}
+
+// Jumps to [label] unless both Rax and Rdx hold 63-bit numbers
+fn jump_unless_fixnums(label: &str) -> Vec<Instr> {
     vec![
-        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(reg))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(Reg::Rax))),
+        Instr::Or(BinArgs::ToReg(Reg::Rcx, Arg32::Reg(Reg::Rdx))),
         Instr::And(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
-        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(0))),
-        Instr::Je(JmpArg::Label(IF_ERROR.to_string())),
+        Instr::Jnz(JmpArg::Label(label.to_string())),
     ]
 }
 
+// The fast path works on 63-bit numbers inline. Bignum operands,
+// overflow and type errors are all handled by snake_arith in the runtime.
+fn compile_arith(
+    p: Prim,
+    a: &ImmExp,
+    b: &ImmExp,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+    stack: i32,
+) -> Vec<Instr> {
+    *counter += 1;
+    let slow_label = format!("arith_slow_{}", counter);
+    let done_label = format!("arith_done_{}", counter);
+    let (code, op) = match p {
+        Prim::Add | Prim::Add1 => (
+            0,
+            Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+        ),
+        Prim::Sub | Prim::Sub1 => (
+            1,
+            Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+        ),
+        Prim::Mul => (
+            2,
+            Instr::IMul(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+        ),
+        _ => unreachable!(),
+    };
+    let mut res = imm_to_rax(a, vars);
+    res.push(Instr::Mov(MovArgs::ToReg(Reg::Rdx, imm_to_arg64(b, vars))));
+    res.extend(jump_unless_fixnums(&slow_label));
+    if p == Prim::Mul {
+        res.push(Instr::Sar(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(1))));
+    }
+    res.push(op);
+    res.push(Instr::Jno(JmpArg::Label(done_label.clone())));
+    res.push(Instr::Label(slow_label));
+    res.extend(call_runtime(
+        "snake_arith",
+        &[
+            Arg64::Signed(code),
+            imm_to_arg64(a, vars),
+            imm_to_arg64(b, vars),
+        ],
+        stack,
+    ));
+    res.push(Instr::Label(done_label));
+    res
+}
+
+fn compile_cmp(
+    p: Prim,
+    imms: &[ImmExp],
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+    stack: i32,
+) -> Vec<Instr> {
+    *counter += 1;
+    let slow_label = format!("cmp_slow_{}", counter);
+    let done_label = format!("cmp_done_{}", counter);
+    let (code, fast_path) = match p {
+        Prim::Lt => (0, [sub_for_cmp(imms, vars, false), is_neg()].concat()),
+        Prim::Gt => (1, [sub_for_cmp(imms, vars, true), is_neg()].concat()),
+        Prim::Le => (2, [sub_for_cmp(imms, vars, true), is_non_neg()].concat()),
+        Prim::Ge => (3, [sub_for_cmp(imms, vars, false), is_non_neg()].concat()),
+        _ => unreachable!(),
+    };
+    let mut res = imm_to_rax(&imms[0], vars);
+    res.push(Instr::Mov(MovArgs::ToReg(
+        Reg::Rdx,
+        imm_to_arg64(&imms[1], vars),
+    )));
+    res.extend(jump_unless_fixnums(&slow_label));
+    res.extend(fast_path);
+    res.push(Instr::Jmp(JmpArg::Label(done_label.clone())));
+    res.push(Instr::Label(slow_label));
+    res.extend(call_runtime(
+        "snake_cmp",
+        &[
+            Arg64::Signed(code),
+            imm_to_arg64(&imms[0], vars),
+            imm_to_arg64(&imms[1], vars),
+        ],
+        stack,
+    ));
+    res.push(Instr::Label(done_label));
+    res
+}
+
+// Equal words are always equal values. Otherwise only two boxed
+// values (bignums) can still be equal, which the runtime decides.
+fn compile_eq(
+    imms: &[ImmExp],
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+    stack: i32,
+    negate: bool,
+) -> Vec<Instr> {
+    *counter += 1;
+    let tru_label = format!("true_{}", counter);
+    let fls_label = format!("false_{}", counter);
+    let done_label = format!("cmp_done_{}", counter);
+    let (when_equal, when_different) = if negate {
+        (fls_label.clone(), tru_label.clone())
+    } else {
+        (tru_label.clone(), fls_label.clone())
+    };
+    let mut res = imm_to_rax(&imms[0], vars);
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, imm_to_arg64(&imms[1], vars))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+        Instr::Je(JmpArg::Label(when_equal)),
+        Instr::And(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(TYPE_MASK))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BOX_TAG))),
+        Instr::Jne(JmpArg::Label(when_different)),
+    ]);
+    res.extend(call_runtime(
+        "snake_num_eq",
+        &[imm_to_arg64(&imms[0], vars), imm_to_arg64(&imms[1], vars)],
+        stack,
+    ));
+    if negate {
+        static BOOL_MASK: u64 = 0x80_00_00_00_00_00_00_00;
+        res.extend(vec![
+            Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Unsigned(BOOL_MASK))),
+            Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+        ]);
+    }
+    res.extend(vec![
+        Instr::Jmp(JmpArg::Label(done_label.clone())),
+        Instr::Label(tru_label),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
+        Instr::Jmp(JmpArg::Label(done_label.clone())),
+        Instr::Label(fls_label),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
+        Instr::Label(done_label),
+    ]);
+    res
+}
+
+// Fails with an out of memory error unless an array with as many
+// elements as the (untagged, non-negative) number in [size] fits into the
+// heap. The size is compared in words, its size in bytes may not fit
+// into 64 bits
+fn heap_check(size: Reg) -> Vec<Instr> {
+    vec![
+        Instr::RelativeLoadAddress(Reg::Rcx, "HEAP_END".to_string()),
+        Instr::Sub(BinArgs::ToReg(Reg::Rcx, Arg32::Reg(Reg::R15))),
+        Instr::Shr(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(3))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(size))),
+        Instr::Add(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Reg(Reg::Rcx))),
+        Instr::Ja(JmpArg::Label(OUT_OF_MEMORY.to_string())),
+    ]
+}
+
+// Vectors can be used wherever arrays are read, so this accepts both.
 // result:
-// Rax: address
-// R8: index
-fn array_access(address: &ImmExp, index: &ImmExp, vars: &HashMap<String, i32>) -> Vec<Instr> {
+// Rax: untagged address of the array (the backing array of a vector)
+// R9: number of elements (untagged)
+fn untag_sequence(address: &ImmExp, vars: &HashMap<String, i32>, counter: &mut u32) -> Vec<Instr> {
+    *counter += 1;
+    let array_label = format!("seq_array_{}", counter);
+    let done_label = format!("seq_done_{}", counter);
     vec![
         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(address, vars))),
         Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
         Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
         Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
+        Instr::Je(JmpArg::Label(array_label.clone())),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
         Instr::Jne(JmpArg::Label(NON_ARRAY_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, mem(Reg::Rdx, 0))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(VECTOR_KIND))),
+        Instr::Jne(JmpArg::Label(NON_ARRAY_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, mem(Reg::Rdx, 8))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rdx, 16))),
+        Instr::Jmp(JmpArg::Label(done_label.clone())),
+        Instr::Label(array_label),
         Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
-        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(index, vars))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, mem(Reg::Rax, 0))),
+        Instr::Label(done_label),
+    ]
+}
+
+fn mem(reg: Reg, offset: i32) -> Arg64 {
+    Arg64::Mem(MemRef {
+        reg,
+        offset: Offset::Constant(offset),
+    })
+}
+
+// Loads the state of [generator], a generator that was resumed, into Rcx
+fn generator_state(generator: &ImmExp, vars: &HashMap<String, i32>) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, imm_to_arg64(generator, vars))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, mem(Reg::Rdx, 8 - BOX_TAG as i32))),
+    ]
+}
+
+// result:
+// Rax: the vector, still tagged
+// Rdx: untagged address of the vector
+fn vector_check(vector: &ImmExp, vars: &HashMap<String, i32>) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(vector, vars))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+        Instr::Jne(JmpArg::Label(NON_VECTOR_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, mem(Reg::Rdx, 0))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Unsigned(VECTOR_KIND))),
+        Instr::Jne(JmpArg::Label(NON_VECTOR_ERROR.to_string())),
+    ]
+}
+
+// Data and records are laid out as [kind, address of a descriptor, fields...]
+fn compile_make_described(
+    kind: u32,
+    descriptor: String,
+    fields: &[ImmExp],
+    vars: &HashMap<String, i32>,
+) -> Vec<Instr> {
+    let len: i32 = fields.len().try_into().unwrap();
+    let mut res = vec![Instr::Mov(MovArgs::ToReg(
+        Reg::R8,
+        Arg64::Signed(len.into()),
+    ))];
+    res.extend(heap_check(Reg::R8));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Unsigned(kind),
+        )),
+        Instr::RelativeLoadAddress(Reg::Rax, descriptor),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(8),
+            },
+            Reg32::Reg(Reg::Rax),
+        )),
+    ]);
+    for (i, field) in fields.iter().enumerate() {
+        let i: i32 = i.try_into().unwrap();
+        res.extend(vec![
+            Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(field, vars))),
+            Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::R15,
+                    offset: Offset::Constant(16 + 8 * i),
+                },
+                Reg32::Reg(Reg::Rax),
+            )),
+        ]);
+    }
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BOX_TAG))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Signed(16 + 8 * len))),
+    ]);
+    res
+}
+
+// One descriptor per constructor, the name is padded with zeros to a
+// multiple of 8 bytes. A record shape is the number of its fields
+// followed by the addresses of their names, so two fields are the same
+// if their names are at the same address. The runtime builds the
+// runtime errors with constructor 0.
+fn descriptors(tables: &Tables) -> String {
+    let mut res = String::from("section .data\n");
+    for (id, (_, ctor)) in tables.ctors.iter().enumerate() {
+        let name = ctor.printed_name();
+        let padding = 8 - name.len() % 8;
+        if id == 0 {
+            res.push_str("snake_error_ctor:\n");
+        }
+        res.push_str(&format!(
+            "ctor_{}:\n        dq {}\n        db \"{}\"{}\n",
+            id,
+            ctor.fields.len(),
+            name,
+            ", 0".repeat(padding)
+        ));
+    }
+    for (id, shape) in tables.shapes.iter().enumerate() {
+        let mut words = vec![shape.len().to_string()];
+        words.extend(shape.iter().map(|field| format!("field_{}", field)));
+        res.push_str(&format!("shape_{}:\n        dq {}\n", id, words.join(", ")));
+    }
+    for (id, field) in tables.fields.iter().enumerate() {
+        res.push_str(&format!("field_{}:\n        db \"{}\", 0\n", id, field));
+    }
+    res
+}
+
+// result:
+// R9: untagged address of the record
+// Rcx: index of the field (untagged)
+// Fails unless [record] is a record with the field with id [field]
+fn field_lookup(
+    record: &ImmExp,
+    field: usize,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+) -> Vec<Instr> {
+    *counter += 1;
+    let loop_label = format!("field_search_{}", counter);
+    let missing_label = format!("field_missing_{}", counter);
+    let done_label = format!("field_found_{}", counter);
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(record, vars))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rax))),
+        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(TYPE_MASK))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(BOX_TAG))),
+        Instr::Jne(JmpArg::Label(NON_RECORD_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rax))),
+        Instr::Sub(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(BOX_TAG))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, mem(Reg::R9, 0))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(RECORD_KIND))),
+        Instr::Jne(JmpArg::Label(NON_RECORD_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, mem(Reg::R9, 8))),
+        Instr::RelativeLoadAddress(Reg::Rsi, format!("field_{}", field)),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Signed(0))),
+        Instr::Label(loop_label.clone()),
+        Instr::Cmp(BinArgs::ToReg(
+            Reg::Rcx,
+            Arg32::Mem(MemRef {
+                reg: Reg::Rdx,
+                offset: Offset::Constant(0),
+            }),
+        )),
+        Instr::Jge(JmpArg::Label(missing_label.clone())),
+        Instr::Cmp(BinArgs::ToReg(
+            Reg::Rsi,
+            Arg32::Mem(MemRef {
+                reg: Reg::Rdx,
+                offset: Offset::Computed {
+                    reg: Reg::Rcx,
+                    factor: 8,
+                    constant: 8,
+                },
+            }),
+        )),
+        Instr::Je(JmpArg::Label(done_label.clone())),
+        Instr::Add(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+        Instr::Jmp(JmpArg::Label(loop_label)),
+        // the error reports the record and the name of the field
+        Instr::Label(missing_label),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rsi))),
+        Instr::Jmp(JmpArg::Label(MISSING_FIELD_ERROR.to_string())),
+        Instr::Label(done_label),
+    ]
+}
+
+fn field_at(reg: Reg, index: usize) -> MemRef {
+    let index: i32 = index.try_into().unwrap();
+    MemRef {
+        reg,
+        offset: Offset::Constant(16 - BOX_TAG as i32 + 8 * index),
+    }
+}
+
+// A closure is called with the number of its arguments in Rcx, not
+// counting the environment passed last
+fn closure_arg_count(args: &[ImmExp]) -> Arg64 {
+    Arg64::Unsigned(args.len() as u64 - 1)
+}
+
+// The code a variadic closure is entered through, at [entry]: packs the
+// arguments after the first [fixed] into an array passed in their place,
+// followed by the environment, and jumps to [code]
+fn rest_entry(fixed: usize, code: &str, entry: &str, counter: &mut u32) -> Vec<Instr> {
+    let fixed: i32 = fixed.try_into().unwrap();
+    let copy_label = format!("rest_copy_{}", counter);
+    let done_label = format!("rest_done_{}", counter);
+    let mut res = vec![
+        Instr::Label(entry.to_string()),
+        // the environment is after the Rcx arguments
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rcx))),
+        Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Signed(3))),
+        Instr::Mov(MovArgs::ToReg(Reg::R11, Arg64::Reg(Reg::Rsp))),
+        Instr::Sub(BinArgs::ToReg(Reg::R11, Arg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, mem(Reg::R11, -8))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::Rcx))),
+        Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Signed(fixed))),
+    ];
+    res.extend(heap_check(Reg::R8));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Reg(Reg::R8),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(Reg::Rsp))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(8 * (fixed + 1)))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::R15))),
+        Instr::Mov(MovArgs::ToReg(Reg::R11, Arg64::Reg(Reg::R8))),
+        Instr::Label(copy_label.clone()),
+        Instr::Cmp(BinArgs::ToReg(Reg::R11, Arg32::Signed(0))),
+        Instr::Je(JmpArg::Label(done_label.clone())),
+        Instr::Add(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(8))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rcx, 0))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rdx,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Reg(Reg::Rax),
+        )),
+        Instr::Sub(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(8))),
+        Instr::Sub(BinArgs::ToReg(Reg::R11, Arg32::Signed(1))),
+        Instr::Jmp(JmpArg::Label(copy_label)),
+        Instr::Label(done_label),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Add(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+        Instr::Shl(BinArgs::ToReg(Reg::R8, Arg32::Signed(3))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Reg(Reg::R8))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsp,
+                offset: Offset::Constant(-8 * (fixed + 1)),
+            },
+            Reg32::Reg(Reg::Rax),
+        )),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsp,
+                offset: Offset::Constant(-8 * (fixed + 2)),
+            },
+            Reg32::Reg(Reg::R10),
+        )),
+        Instr::Jmp(JmpArg::Label(code.to_string())),
+    ]);
+    res
+}
+
+// Calls that leave out parameters with defaults or pass keyword arguments
+// enter through here. The runtime moves the arguments into the slots of
+// the parameters, so it needs room below them for its own frame.
+fn bind_entry(
+    fixed: usize,
+    variadic: bool,
+    required: usize,
+    keywords: usize,
+    code: &str,
+    entry: &str,
+) -> Vec<Instr> {
+    let fixed: i32 = fixed.try_into().unwrap();
+    vec![
+        Instr::Label(entry.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rsp))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rcx))),
+        Instr::RelativeLoadAddress(Reg::Rdx, format!("shape_{}", keywords)),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Unsigned(required as u64))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Unsigned(variadic as u64))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::Rsi))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Signed(fixed + 3))),
+        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Signed(3))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Reg(Reg::Rax))),
+        Instr::And(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(-16))),
+    ]
+    .into_iter()
+    .chain(save_heap_pointer())
+    .chain(vec![
+        Instr::Call(JmpArg::Label("snake_bind_arguments".to_string())),
+        // the runtime returns the original rsp
+        Instr::Mov(MovArgs::ToReg(Reg::Rsp, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(code.to_string())),
+    ])
+    .collect()
+}
+
+fn compile_new_vector() -> Vec<Instr> {
+    // the vector header is followed by an empty backing array
+    let mut res = vec![Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Signed(3)))];
+    res.extend(heap_check(Reg::R8));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Unsigned(VECTOR_KIND),
+        )),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(8),
+            },
+            Reg32::Signed(0),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Signed(24))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(16),
+            },
+            Reg32::Reg(Reg::Rax),
+        )),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(24),
+            },
+            Reg32::Signed(0),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BOX_TAG))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Signed(32))),
+    ]);
+    res
+}
+
+// A full backing array is replaced by a copy with capacity 2n + 4, so
+// pushes take amortized constant time. The old backing array is garbage.
+fn compile_push(
+    vector: &ImmExp,
+    elt: &ImmExp,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+) -> Vec<Instr> {
+    let mut res = vector_check(vector, vars);
+    *counter += 1;
+    let store_label = format!("push_store_{}", counter);
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rdx))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, mem(Reg::Rsi, 8))),
+        Instr::Mov(MovArgs::ToReg(Reg::R11, mem(Reg::Rsi, 16))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, mem(Reg::R11, 0))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Reg(Reg::R9))),
+        Instr::Jl(JmpArg::Label(store_label.clone())),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Reg(Reg::R8))),
+        Instr::Shl(BinArgs::ToReg(Reg::R10, Arg32::Signed(1))),
+        Instr::Add(BinArgs::ToReg(Reg::R10, Arg32::Signed(4))),
+    ]);
+    res.extend(heap_check(Reg::R10));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Reg(Reg::R10),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R11))),
         Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::R8))),
-        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(0b1))),
-        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(0b1))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Signed(0))),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Signed(0))),
+    ]);
+    res.extend(copy_elements(counter));
+    res.extend(vec![
+        // the new backing array takes its capacity plus the header
+        Instr::Mov(MovArgs::ToReg(Reg::R11, Arg64::Reg(Reg::R15))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, mem(Reg::R15, 0))),
+        Instr::Add(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+        Instr::Shl(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(3))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Reg(Reg::Rcx))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsi,
+                offset: Offset::Constant(16),
+            },
+            Reg32::Reg(Reg::R11),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, mem(Reg::Rsi, 8))),
+        Instr::Label(store_label),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, imm_to_arg64(elt, vars))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R11,
+                offset: Offset::Computed {
+                    reg: Reg::R8,
+                    factor: 8,
+                    constant: 8,
+                },
+            },
+            Reg32::Reg(Reg::R9),
+        )),
+        Instr::Add(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rsi,
+                offset: Offset::Constant(8),
+            },
+            Reg32::Reg(Reg::R8),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(vector, vars))),
+    ]);
+    res
+}
+
+fn compile_pop(vector: &ImmExp, vars: &HashMap<String, i32>) -> Vec<Instr> {
+    let mut res = vector_check(vector, vars);
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R8, mem(Reg::Rdx, 8))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+        Instr::Je(JmpArg::Label(EMPTY_VECTOR_ERROR.to_string())),
+        Instr::Sub(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::Rdx,
+                offset: Offset::Constant(8),
+            },
+            Reg32::Reg(Reg::R8),
+        )),
+        Instr::Mov(MovArgs::ToReg(Reg::R11, mem(Reg::Rdx, 16))),
+        Instr::Mov(MovArgs::ToReg(
+            Reg::Rax,
+            Arg64::Mem(MemRef {
+                reg: Reg::R11,
+                offset: Offset::Computed {
+                    reg: Reg::R8,
+                    factor: 8,
+                    constant: 8,
+                },
+            }),
+        )),
+    ]);
+    res
+}
+
+// loads [index] into R8, failing unless it is a number
+fn load_index(index: &ImmExp, vars: &HashMap<String, i32>) -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(index, vars))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(Reg::R8))),
+        Instr::And(BinArgs::ToReg(Reg::Rcx, Arg32::Unsigned(0b1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Unsigned(0b1))),
         Instr::Je(JmpArg::Label(INDEX_ERROR.to_string())),
+    ]
+}
+
+// result:
+// Rax: address
+// R8: index
+fn array_access(
+    address: &ImmExp,
+    index: &ImmExp,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+) -> Vec<Instr> {
+    let mut res = untag_sequence(address, vars, counter);
+    res.extend(load_index(index, vars));
+    // compare tagged numbers so that errors report the index as written
+    res.extend(vec![
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+        Instr::Jl(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
+        Instr::Shl(BinArgs::ToReg(Reg::R9, Arg32::Signed(1))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R9, Arg32::Reg(Reg::R8))),
+        Instr::Jle(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
         Instr::Sar(BinArgs::ToReg(Reg::R8, Arg32::Unsigned(1))),
-        Instr::Cmp(BinArgs::ToMem(
-            MemRef {
+    ]);
+    res
+}
+
+// Copies elements [R8, R9) of the untagged array in Rax into the array
+// being built at R15, starting from slot R10. R10 ends up past the
+// last copied element.
+fn copy_elements(counter: &mut u32) -> Vec<Instr> {
+    *counter += 1;
+    let loop_label = format!("copy_{}", counter);
+    let done_label = format!("copy_done_{}", counter);
+    vec![
+        Instr::Label(loop_label.clone()),
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Reg(Reg::R9))),
+        Instr::Jge(JmpArg::Label(done_label.clone())),
+        Instr::Mov(MovArgs::ToReg(
+            Reg::Rdx,
+            Arg64::Mem(MemRef {
                 reg: Reg::Rax,
+                offset: Offset::Computed {
+                    reg: Reg::R8,
+                    factor: 8,
+                    constant: 8,
+                },
+            }),
+        )),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
+                offset: Offset::Computed {
+                    reg: Reg::R10,
+                    factor: 8,
+                    constant: 8,
+                },
+            },
+            Reg32::Reg(Reg::Rdx),
+        )),
+        Instr::Add(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+        Instr::Add(BinArgs::ToReg(Reg::R10, Arg32::Signed(1))),
+        Instr::Jmp(JmpArg::Label(loop_label)),
+        Instr::Label(done_label),
+    ]
+}
+
+// Finishes the array built at R15 whose size is in R10 and returns it in Rax
+fn finish_array() -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R15,
                 offset: Offset::Constant(0),
             },
-            Reg32::Reg(Reg::R8),
+            Reg32::Reg(Reg::R10),
         )),
-        Instr::Jle(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+        Instr::Shl(BinArgs::ToReg(Reg::R10, Arg32::Signed(3))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Reg(Reg::R10))),
+        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Signed(8))),
     ]
 }
 
+fn compile_slice(
+    array: &ImmExp,
+    start: &ImmExp,
+    end: &ImmExp,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+) -> Vec<Instr> {
+    let mut res = untag_sequence(array, vars, counter);
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R11, Arg64::Reg(Reg::R9))),
+        Instr::Shl(BinArgs::ToReg(Reg::R11, Arg32::Signed(1))),
+    ]);
+    res.extend(load_index(end, vars));
+    res.push(Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Reg(Reg::R8))));
+    res.extend(load_index(start, vars));
+    res.extend(vec![
+        // 0 <= start <= end
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+        Instr::Jl(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Reg(Reg::R10))),
+        Instr::Jg(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
+        // end <= length
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Reg(Reg::R10))),
+        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Reg(Reg::R11))),
+        Instr::Jg(JmpArg::Label(INDEX_OUT_OF_BOUNDS.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::R10))),
+        Instr::Sar(BinArgs::ToReg(Reg::R9, Arg32::Signed(1))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(start, vars))),
+        Instr::Sar(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Reg(Reg::R9))),
+        Instr::Sub(BinArgs::ToReg(Reg::R10, Arg32::Reg(Reg::R8))),
+    ]);
+    res.extend(heap_check(Reg::R10));
+    res.push(Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Signed(0))));
+    res.extend(copy_elements(counter));
+    res.extend(finish_array());
+    res
+}
+
+fn compile_concat(
+    a: &ImmExp,
+    b: &ImmExp,
+    vars: &HashMap<String, i32>,
+    counter: &mut u32,
+) -> Vec<Instr> {
+    let mut res = untag_sequence(b, vars, counter);
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R11, Arg64::Reg(Reg::Rax))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R9))),
+    ]);
+    res.extend(untag_sequence(a, vars, counter));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Reg(Reg::R9))),
+        Instr::Add(BinArgs::ToReg(Reg::R10, Arg32::Reg(Reg::Rsi))),
+    ]);
+    res.extend(heap_check(Reg::R10));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Signed(0))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Signed(0))),
+    ]);
+    res.extend(copy_elements(counter));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R11))),
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Signed(0))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::Rsi))),
+    ]);
+    res.extend(copy_elements(counter));
+    res.extend(finish_array());
+    res
+}
+
+fn compile_copy(array: &ImmExp, vars: &HashMap<String, i32>, counter: &mut u32) -> Vec<Instr> {
+    let mut res = untag_sequence(array, vars, counter);
+    res.extend(heap_check(Reg::R9));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Signed(0))),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Signed(0))),
+    ]);
+    res.extend(copy_elements(counter));
+    res.extend(finish_array());
+    res
+}
+
 // [vars] variable name -> offset from rsp in stack (negative number)
 // [functions] function name -> stack size when function is declared
 fn compile_to_instrs_inner<'a, 'b>(
@@ -203,56 +1101,11 @@ fn compile_to_instrs_inner<'a, 'b>(
         SeqExp::Prim(p, imms, _) => {
             //
             match p {
-                Prim::Add => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(arith_check(Reg::Rax));
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        imm_to_arg64(&imms[1], vars),
-                    )));
-                    res.extend(arith_check(Reg::Rdx));
-                    res.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
-                    res.push(Instr::Jo(JmpArg::Label(OVERFLOW.to_string())));
-                    res
-                }
-                Prim::Sub => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(arith_check(Reg::Rax));
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        imm_to_arg64(&imms[1], vars),
-                    )));
-                    res.extend(arith_check(Reg::Rdx));
-                    res.push(Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
-                    res.push(Instr::Jo(JmpArg::Label(OVERFLOW.to_string())));
-                    res
-                }
-                Prim::Mul => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(arith_check(Reg::Rax));
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        imm_to_arg64(&imms[1], vars),
-                    )));
-                    res.extend(arith_check(Reg::Rdx));
-                    res.push(Instr::Sar(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(1))));
-                    res.push(Instr::IMul(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
-                    res.push(Instr::Jo(JmpArg::Label(OVERFLOW.to_string())));
-                    res
-                }
-                Prim::Add1 => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(arith_check(Reg::Rax));
-                    res.push(Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0x2))));
-                    res.push(Instr::Jo(JmpArg::Label(OVERFLOW.to_string())));
-                    res
+                Prim::Add | Prim::Sub | Prim::Mul => {
+                    compile_arith(*p, &imms[0], &imms[1], vars, counter, stack)
                 }
-                Prim::Sub1 => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(arith_check(Reg::Rax));
-                    res.push(Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0x2))));
-                    res.push(Instr::Jo(JmpArg::Label(OVERFLOW.to_string())));
-                    res
+                Prim::Add1 | Prim::Sub1 => {
+                    compile_arith(*p, &imms[0], &ImmExp::Num(1), vars, counter, stack)
                 }
                 Prim::Not => {
                     let mut res = imm_to_rax(&imms[0], vars);
@@ -266,18 +1119,7 @@ fn compile_to_instrs_inner<'a, 'b>(
                     res
                 }
                 Prim::Print => {
-                    vec![
-                        Instr::Mov(MovArgs::ToReg(Reg::Rdi, imm_to_arg64(&imms[0], vars))),
-                        Instr::Sub(BinArgs::ToReg(
-                            Reg::Rsp,
-                            Arg32::Signed(align_stack(stack) + 8),
-                        )),
-                        Instr::Call(JmpArg::Label("print_snake_val".to_string())),
-                        Instr::Add(BinArgs::ToReg(
-                            Reg::Rsp,
-                            Arg32::Signed(align_stack(stack) + 8),
-                        )),
-                    ]
+                    call_runtime("print_snake_val", &[imm_to_arg64(&imms[0], vars)], stack)
                 }
                 Prim::IsBool => {
                     *counter += 1;
@@ -296,15 +1138,39 @@ fn compile_to_instrs_inner<'a, 'b>(
                     ]
                 }
                 Prim::IsNum => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        Arg64::Unsigned(SNAKE_FLS),
-                    )));
-                    res.push(Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
-                    res.push(Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Signed(63))));
-                    res.push(Instr::Or(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
-                    res
+                    *counter += 1;
+                    let fixnum_label = format!("fixnum_{}", counter);
+                    let tru_label = format!("true_{}", counter);
+                    let fls_label = format!("false_{}", counter);
+                    let done_label = format!("cmp_done_{}", counter);
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+                        Instr::Jne(JmpArg::Label(fixnum_label.clone())),
+                        // boxed values are numbers if they are bignums
+                        Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BOX_TAG))),
+                        Instr::Mov(MovArgs::ToReg(
+                            Reg::Rax,
+                            Arg64::Mem(MemRef {
+                                reg: Reg::Rax,
+                                offset: Offset::Constant(0),
+                            }),
+                        )),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BIGNUM_KIND))),
+                        Instr::Je(JmpArg::Label(tru_label.clone())),
+                        Instr::Jmp(JmpArg::Label(fls_label.clone())),
+                        Instr::Label(fixnum_label),
+                        Instr::And(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                        Instr::Jnz(JmpArg::Label(fls_label.clone())),
+                        Instr::Label(tru_label),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
+                        Instr::Jmp(JmpArg::Label(done_label.clone())),
+                        Instr::Label(fls_label),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
+                        Instr::Label(done_label),
+                    ]
                 }
                 Prim::And => {
                     let mut res = imm_to_rax(&imms[0], vars);
@@ -328,106 +1194,271 @@ fn compile_to_instrs_inner<'a, 'b>(
                     res.push(Instr::Or(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))));
                     res
                 }
-                Prim::Lt => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(sub_for_cmp(imms, vars, false));
-                    res.extend(is_neg());
-                    res
-                }
-                Prim::Gt => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(sub_for_cmp(imms, vars, true));
-                    res.extend(is_neg());
-                    res
-                }
-                Prim::Le => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(sub_for_cmp(imms, vars, true));
-                    res.extend(is_non_neg());
-                    res
+                Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge => {
+                    compile_cmp(*p, imms, vars, counter, stack)
                 }
-                Prim::Ge => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.extend(sub_for_cmp(imms, vars, false));
-                    res.extend(is_non_neg());
+                Prim::Eq => compile_eq(imms, vars, counter, stack, false),
+                Prim::Neq => compile_eq(imms, vars, counter, stack, true),
+                Prim::Equal => call_runtime(
+                    "snake_equal",
+                    &[imm_to_arg64(&imms[0], vars), imm_to_arg64(&imms[1], vars)],
+                    stack,
+                ),
+                Prim::Length => {
+                    let mut res = untag_sequence(&imms[0], vars, counter);
+                    res.extend(vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R9))),
+                        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Signed(1))),
+                    ]);
                     res
                 }
-                Prim::Eq => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        imm_to_arg64(&imms[1], vars),
-                    )));
+                Prim::IsFun => {
                     *counter += 1;
                     let fls_label = format!("false_{}", counter);
                     let done_label = format!("cmp_done_{}", counter);
-                    res.extend(vec![
-                        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b11))),
                         Instr::Jne(JmpArg::Label(fls_label.clone())),
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
                         Instr::Jmp(JmpArg::Label(done_label.clone())),
                         Instr::Label(fls_label),
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
                         Instr::Label(done_label),
-                    ]);
-                    res
+                    ]
                 }
-                Prim::Neq => {
-                    let mut res = imm_to_rax(&imms[0], vars);
-                    res.push(Instr::Mov(MovArgs::ToReg(
-                        Reg::Rdx,
-                        imm_to_arg64(&imms[1], vars),
-                    )));
+                Prim::IsArray => {
                     *counter += 1;
                     let fls_label = format!("false_{}", counter);
                     let done_label = format!("cmp_done_{}", counter);
-                    res.extend(vec![
-                        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
-                        Instr::Je(JmpArg::Label(fls_label.clone())),
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
+                        Instr::Jne(JmpArg::Label(fls_label.clone())),
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
                         Instr::Jmp(JmpArg::Label(done_label.clone())),
                         Instr::Label(fls_label),
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
                         Instr::Label(done_label),
-                    ]);
-                    res
+                    ]
                 }
-                Prim::Length => {
+                // calls with as many arguments as the closure has parameters
+                // go straight to its code, the others bind their arguments
+                // first. The arity word is arity, or -(arity + 1) when the
+                // closure takes the rest of the arguments.
+                Prim::GetCode(Some(arg_size)) => {
+                    *counter += 1;
+                    let direct_label = format!("code_direct_{}", counter);
+                    let bind_label = format!("code_bind_{}", counter);
+                    let too_many_label = format!("code_too_many_{}", counter);
+                    let done_label = format!("code_done_{}", counter);
+                    let variadic_label = format!("code_variadic_{}", counter);
+                    let arg_size: i32 = (*arg_size).try_into().unwrap();
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::R8, mem(Reg::Rax, 8))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(arg_size))),
+                        Instr::Je(JmpArg::Label(direct_label.clone())),
+                        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+                        Instr::Jl(JmpArg::Label(variadic_label.clone())),
+                        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(arg_size))),
+                        Instr::Jl(JmpArg::Label(too_many_label.clone())),
+                        Instr::Jmp(JmpArg::Label(bind_label.clone())),
+                        Instr::Label(variadic_label),
+                        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(-arg_size - 1))),
+                        Instr::Jge(JmpArg::Label(direct_label.clone())),
+                        Instr::Label(bind_label),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, 24))),
+                        Instr::Jmp(JmpArg::Label(done_label.clone())),
+                        // the error reports the tagged closure
+                        Instr::Label(too_many_label),
+                        Instr::Or(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
+                        Instr::Jmp(JmpArg::Label(LAMBDA_ARITY_ERROR.to_string())),
+                        Instr::Label(direct_label),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, 0))),
+                        Instr::Label(done_label),
+                    ]
+                }
+                Prim::GetCode(None) => {
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, 24))),
+                    ]
+                }
+                Prim::GetEnv => {
                     vec![
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
-                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
-                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
-                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
-                        Instr::Jne(JmpArg::Label(NON_ARRAY_ERROR.to_string())),
-                        Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
-                        // check address valid
                         Instr::Mov(MovArgs::ToReg(
                             Reg::Rax,
                             Arg64::Mem(MemRef {
                                 reg: Reg::Rax,
-                                offset: Offset::Constant(0),
+                                offset: Offset::Constant(16),
                             }),
                         )),
                     ]
                 }
-                Prim::IsFun => {
-                    *counter += 1;
-                    let fls_label = format!("false_{}", counter);
-                    let done_label = format!("cmp_done_{}", counter);
+                Prim::UntagClosure => {
                     vec![
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
                         Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
                         Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
                         Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b11))),
-                        Instr::Jne(JmpArg::Label(fls_label.clone())),
-                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
+                        Instr::Jne(JmpArg::Label(NON_CLOSURE_ERROR.to_string())),
+                        Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
+                    ]
+                }
+                Prim::CheckIterable => {
+                    *counter += 1;
+                    let generator_label = format!("not_array_{}", counter);
+                    let done_label = format!("iterable_{}", counter);
+                    vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
+                        Instr::Jne(JmpArg::Label(generator_label.clone())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, -1))),
+                        Instr::Shl(BinArgs::ToReg(Reg::Rax, Arg32::Signed(1))),
                         Instr::Jmp(JmpArg::Label(done_label.clone())),
-                        Instr::Label(fls_label),
-                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
+                        Instr::Label(generator_label),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+                        Instr::Jne(JmpArg::Label(NON_ARRAY_ERROR.to_string())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, mem(Reg::Rax, -(BOX_TAG as i32)))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(GENERATOR_KIND))),
+                        Instr::Jne(JmpArg::Label(NON_ARRAY_ERROR.to_string())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Signed(-1 << 1))),
                         Instr::Label(done_label),
                     ]
                 }
-                Prim::IsArray => {
+                Prim::Generator => {
+                    call_runtime("snake_generator", &[imm_to_arg64(&imms[0], vars)], stack)
+                }
+                Prim::Yield => call_runtime(SNAKE_YIELD, &[imm_to_arg64(&imms[0], vars)], stack),
+                Prim::Resume => {
+                    *counter += 1;
+                    let done_label = format!("resumed_{}", counter);
+                    let mut res =
+                        call_runtime(SNAKE_RESUME, &[imm_to_arg64(&imms[0], vars)], stack);
+                    res.extend(generator_state(&imms[0], vars));
+                    res.extend(vec![
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(FINISHED))),
+                        Instr::Jne(JmpArg::Label(done_label.clone())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Jmp(JmpArg::Label(GENERATOR_EXHAUSTED.to_string())),
+                        Instr::Label(done_label),
+                    ]);
+                    res
+                }
+                Prim::Advance => {
+                    *counter += 1;
+                    let done_label = format!("advanced_{}", counter);
+                    let mut res =
+                        call_runtime(SNAKE_RESUME, &[imm_to_arg64(&imms[0], vars)], stack);
+                    res.extend(generator_state(&imms[0], vars));
+                    res.extend(vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(FINISHED))),
+                        Instr::Jne(JmpArg::Label(done_label.clone())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_FLS))),
+                        Instr::Label(done_label),
+                    ]);
+                    res
+                }
+                Prim::LastYielded => vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, 48 - BOX_TAG as i32))),
+                ],
+                Prim::Spawn => call_runtime("snake_spawn", &[imm_to_arg64(&imms[0], vars)], stack),
+                // other threads may have run and allocated in the meantime
+                Prim::Recv => {
+                    let mut res =
+                        call_runtime("snake_recv", &[imm_to_arg64(&imms[0], vars)], stack);
+                    res.extend(restore_heap_pointer());
+                    res
+                }
+                // the runtime reports the mismatch with the location of
+                // the destructuring
+                Prim::CheckLength(len, line, col) => {
+                    *counter += 1;
+                    let fail_label = format!("length_mismatch_{}", counter);
+                    let done_label = format!("length_ok_{}", counter);
+                    let mut res = vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
+                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
+                        Instr::Jne(JmpArg::Label(fail_label.clone())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, mem(Reg::Rax, -1))),
+                        Instr::Cmp(BinArgs::ToReg(
+                            Reg::Rdx,
+                            Arg32::Signed((*len).try_into().unwrap()),
+                        )),
+                        Instr::Je(JmpArg::Label(done_label.clone())),
+                        Instr::Label(fail_label),
+                    ];
+                    res.extend(call_runtime(
+                        "snake_destructure_error",
+                        &[
+                            Arg64::Reg(Reg::Rax),
+                            Arg64::Unsigned(*len as u64),
+                            Arg64::Unsigned(*line as u64),
+                            Arg64::Unsigned(*col as u64),
+                        ],
+                        stack,
+                    ));
+                    res.push(Instr::Label(done_label));
+                    res
+                }
+                // the runtime reports the value with the annotation it
+                // failed, numbered in the order of TypeAnn
+                Prim::Cast(t, line, col) => {
+                    *counter += 1;
+                    let done_label = format!("cast_ok_{}", counter);
+                    let test = match t {
+                        TypeAnn::Num => Prim::IsNum,
+                        TypeAnn::Bool => Prim::IsBool,
+                        TypeAnn::Array => Prim::IsArray,
+                    };
+                    let mut res = compile_to_instrs_inner(
+                        &SeqExp::Prim(test, imms.clone(), ()),
+                        counter,
+                        stack,
+                        vars,
+                        functions,
+                    );
+                    res.extend(vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Unsigned(SNAKE_TRU))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+                        Instr::Je(JmpArg::Label(done_label.clone())),
+                    ]);
+                    res.extend(call_runtime(
+                        "snake_cast_error",
+                        &[
+                            imm_to_arg64(&imms[0], vars),
+                            Arg64::Unsigned(*t as u64),
+                            Arg64::Unsigned(*line as u64),
+                            Arg64::Unsigned(*col as u64),
+                        ],
+                        stack,
+                    ));
+                    res.push(Instr::Label(done_label));
+                    res.push(Instr::Mov(MovArgs::ToReg(
+                        Reg::Rax,
+                        imm_to_arg64(&imms[0], vars),
+                    )));
+                    res
+                }
+                // never returns
+                Prim::Raise => call_runtime(
+                    SNAKE_ERROR,
+                    &[error_code(ErrorCode::Raise), imm_to_arg64(&imms[0], vars)],
+                    stack,
+                ),
+                Prim::IsData(id) => {
                     *counter += 1;
                     let fls_label = format!("false_{}", counter);
                     let done_label = format!("cmp_done_{}", counter);
@@ -435,7 +1466,15 @@ fn compile_to_instrs_inner<'a, 'b>(
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
                         Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
                         Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
-                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(1))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(BOX_TAG))),
+                        Instr::Jne(JmpArg::Label(fls_label.clone())),
+                        Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(BOX_TAG))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, mem(Reg::Rax, 0))),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(DATA_KIND))),
+                        Instr::Jne(JmpArg::Label(fls_label.clone())),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rcx, mem(Reg::Rax, 8))),
+                        Instr::RelativeLoadAddress(Reg::Rdx, format!("ctor_{}", id)),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Reg(Reg::Rdx))),
                         Instr::Jne(JmpArg::Label(fls_label.clone())),
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Unsigned(SNAKE_TRU))),
                         Instr::Jmp(JmpArg::Label(done_label.clone())),
@@ -444,55 +1483,106 @@ fn compile_to_instrs_inner<'a, 'b>(
                         Instr::Label(done_label),
                     ]
                 }
-                Prim::GetCode => {
+                Prim::DataGet => {
+                    // both arguments are still tagged, field n is at 16 + 8n
                     vec![
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(&imms[1], vars))),
                         Instr::Mov(MovArgs::ToReg(
                             Reg::Rax,
                             Arg64::Mem(MemRef {
                                 reg: Reg::Rax,
-                                offset: Offset::Constant(0),
+                                offset: Offset::Computed {
+                                    reg: Reg::R8,
+                                    factor: 4,
+                                    constant: 11,
+                                },
                             }),
                         )),
                     ]
                 }
-                Prim::GetEnv => {
-                    vec![
-                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
-                        Instr::Mov(MovArgs::ToReg(
-                            Reg::Rax,
-                            Arg64::Mem(MemRef {
-                                reg: Reg::Rax,
-                                offset: Offset::Constant(16),
-                            }),
+                Prim::MakeData(id) => {
+                    compile_make_described(DATA_KIND, format!("ctor_{}", id), imms, vars)
+                }
+                Prim::MakeRecord(id) => {
+                    compile_make_described(RECORD_KIND, format!("shape_{}", id), imms, vars)
+                }
+                Prim::MakeKeywords(id) => {
+                    compile_make_described(KEYWORDS_KIND, format!("shape_{}", id), imms, vars)
+                }
+                Prim::GetField(field) => {
+                    let mut res = field_lookup(&imms[0], *field, vars, counter);
+                    res.push(Instr::Mov(MovArgs::ToReg(
+                        Reg::Rax,
+                        Arg64::Mem(MemRef {
+                            reg: Reg::R9,
+                            offset: Offset::Computed {
+                                reg: Reg::Rcx,
+                                factor: 8,
+                                constant: 16,
+                            },
+                        }),
+                    )));
+                    res
+                }
+                Prim::SetField(field) => {
+                    let mut res = field_lookup(&imms[0], *field, vars, counter);
+                    res.extend(vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[1], vars))),
+                        Instr::Mov(MovArgs::ToMem(
+                            MemRef {
+                                reg: Reg::R9,
+                                offset: Offset::Computed {
+                                    reg: Reg::Rcx,
+                                    factor: 8,
+                                    constant: 16,
+                                },
+                            },
+                            Reg32::Reg(Reg::Rax),
                         )),
-                    ]
+                    ]);
+                    res
                 }
-                Prim::CheckArityAndUntag(arg_size) => {
+                // the record is known to have the field at this index
+                Prim::GetFieldAt(index) => vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                    Instr::Mov(MovArgs::ToReg(
+                        Reg::Rax,
+                        Arg64::Mem(field_at(Reg::Rax, *index)),
+                    )),
+                ],
+                Prim::SetFieldAt(index) => vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::Rdx, imm_to_arg64(&imms[0], vars))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[1], vars))),
+                    Instr::Mov(MovArgs::ToMem(
+                        field_at(Reg::Rdx, *index),
+                        Reg32::Reg(Reg::Rax),
+                    )),
+                ],
+                Prim::MatchFail => vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                    Instr::Jmp(JmpArg::Label(MATCH_ERROR.to_string())),
+                ],
+                Prim::UncheckedArrayGet => {
+                    // both arguments are still tagged, an index n is 2n
                     vec![
                         Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
-                        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rax))),
-                        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(TYPE_MASK))),
-                        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b11))),
-                        Instr::Jne(JmpArg::Label(NON_CLOSURE_ERROR.to_string())),
-                        Instr::Xor(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
-                        // check arg size
+                        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(&imms[1], vars))),
                         Instr::Mov(MovArgs::ToReg(
-                            Reg::R8,
+                            Reg::Rax,
                             Arg64::Mem(MemRef {
                                 reg: Reg::Rax,
-                                offset: Offset::Constant(8),
+                                offset: Offset::Computed {
+                                    reg: Reg::R8,
+                                    factor: 4,
+                                    constant: 7,
+                                },
                             }),
                         )),
-                        Instr::Cmp(BinArgs::ToReg(
-                            Reg::R8,
-                            Arg32::Unsigned((*arg_size).try_into().unwrap()),
-                        )),
-                        Instr::Jne(JmpArg::Label(LAMBDA_ARITY_ERROR.to_string())),
                     ]
                 }
                 Prim::ArrayGet => {
-                    let mut res = array_access(&imms[0], &imms[1], vars);
+                    let mut res = array_access(&imms[0], &imms[1], vars, counter);
                     res.push(Instr::Mov(MovArgs::ToReg(
                         Reg::Rax,
                         Arg64::Mem(MemRef {
@@ -507,7 +1597,7 @@ fn compile_to_instrs_inner<'a, 'b>(
                     res
                 }
                 Prim::ArraySet => {
-                    let mut res = array_access(&imms[0], &imms[1], vars);
+                    let mut res = array_access(&imms[0], &imms[1], vars, counter);
                     res.extend(vec![
                         Instr::Mov(MovArgs::ToReg(Reg::R9, imm_to_arg64(&imms[2], vars))),
                         Instr::Mov(MovArgs::ToMem(
@@ -521,7 +1611,7 @@ fn compile_to_instrs_inner<'a, 'b>(
                             },
                             Reg32::Reg(Reg::R9),
                         )),
-                        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
                     ]);
                     res
                 }
@@ -554,6 +1644,117 @@ fn compile_to_instrs_inner<'a, 'b>(
                     ]);
                     res
                 }
+                Prim::Slice => compile_slice(&imms[0], &imms[1], &imms[2], vars, counter),
+                Prim::Concat => compile_concat(&imms[0], &imms[1], vars, counter),
+                Prim::Copy => compile_copy(&imms[0], vars, counter),
+                Prim::NewVector => compile_new_vector(),
+                Prim::Push => compile_push(&imms[0], &imms[1], vars, counter),
+                Prim::NewChannel => call_runtime("snake_channel", &[], stack),
+                Prim::Send => call_runtime(
+                    "snake_send",
+                    &[imm_to_arg64(&imms[0], vars), imm_to_arg64(&imms[1], vars)],
+                    stack,
+                ),
+                Prim::Pop => compile_pop(&imms[0], vars),
+                Prim::NewDict => call_runtime("snake_dict", &[], stack),
+                Prim::DictGet | Prim::DictPut | Prim::DictHas | Prim::DictKeys => {
+                    let name = match p {
+                        Prim::DictGet => "snake_dict_get",
+                        Prim::DictPut => "snake_dict_put",
+                        Prim::DictHas => "snake_dict_has",
+                        _ => "snake_dict_keys",
+                    };
+                    let args: Vec<_> = imms.iter().map(|imm| imm_to_arg64(imm, vars)).collect();
+                    call_runtime(name, &args, stack)
+                }
+                Prim::Delay => call_runtime("snake_delay", &[imm_to_arg64(&imms[0], vars)], stack),
+                Prim::Forced => {
+                    call_runtime("snake_forced", &[imm_to_arg64(&imms[0], vars)], stack)
+                }
+                // a thunk is [THUNK_KIND, closure or value, whether it was forced],
+                // Forced already checked the argument is one
+                Prim::ThunkValue => vec![
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[0], vars))),
+                    Instr::Mov(MovArgs::ToReg(Reg::Rax, mem(Reg::Rax, 8 - BOX_TAG as i32))),
+                ],
+                Prim::SetThunk => call_runtime(
+                    "snake_set_thunk",
+                    &[imm_to_arg64(&imms[0], vars), imm_to_arg64(&imms[1], vars)],
+                    stack,
+                ),
+                // the last argument tells the runtime the comparison, if any,
+                // and whether the assertion has a code
+                Prim::AssertFailed(comparison, line, col) => {
+                    let (operands, code) = imms.split_at(if comparison.is_some() { 2 } else { 0 });
+                    let operand = |i: usize| match operands.get(i) {
+                        Some(imm) => imm_to_arg64(imm, vars),
+                        None => Arg64::Signed(0),
+                    };
+                    let code_arg = match code.first() {
+                        Some(imm) => imm_to_arg64(imm, vars),
+                        None => Arg64::Signed(0),
+                    };
+                    let what = comparison.map_or(0, |c| c as i64 + 1) | (code.len() as i64) << 3;
+                    let args = [
+                        operand(0),
+                        operand(1),
+                        code_arg,
+                        Arg64::Unsigned(*line as u64),
+                        Arg64::Unsigned(*col as u64),
+                        Arg64::Signed(what),
+                    ];
+                    call_runtime("snake_assert_error", &args, stack)
+                }
+                Prim::Force | Prim::Assert => unreachable!("force and assert are desugared"),
+                Prim::NewArray => {
+                    *counter += 1;
+                    let loop_label = format!("fill_array_{}", counter);
+                    let done_label = format!("fill_array_done_{}", counter);
+                    let mut res = vec![
+                        Instr::Mov(MovArgs::ToReg(Reg::R8, imm_to_arg64(&imms[0], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Reg(Reg::R8))),
+                        Instr::And(BinArgs::ToReg(Reg::R9, Arg32::Unsigned(1))),
+                        Instr::Jnz(JmpArg::Label(ARRAY_SIZE_ERROR.to_string())),
+                        Instr::Cmp(BinArgs::ToReg(Reg::R8, Arg32::Signed(0))),
+                        Instr::Jl(JmpArg::Label(NEGATIVE_SIZE_ERROR.to_string())),
+                        Instr::Sar(BinArgs::ToReg(Reg::R8, Arg32::Signed(1))),
+                    ];
+                    res.extend(heap_check(Reg::R8));
+                    res.extend(vec![
+                        Instr::Mov(MovArgs::ToMem(
+                            MemRef {
+                                reg: Reg::R15,
+                                offset: Offset::Constant(0),
+                            },
+                            Reg32::Reg(Reg::R8),
+                        )),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(&imms[1], vars))),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Signed(0))),
+                        Instr::Label(loop_label.clone()),
+                        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Reg(Reg::R8))),
+                        Instr::Jge(JmpArg::Label(done_label.clone())),
+                        Instr::Mov(MovArgs::ToMem(
+                            MemRef {
+                                reg: Reg::R15,
+                                offset: Offset::Computed {
+                                    reg: Reg::Rcx,
+                                    factor: 8,
+                                    constant: 8,
+                                },
+                            },
+                            Reg32::Reg(Reg::Rax),
+                        )),
+                        Instr::Add(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(1))),
+                        Instr::Jmp(JmpArg::Label(loop_label)),
+                        Instr::Label(done_label),
+                        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
+                        Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(1))),
+                        Instr::Shl(BinArgs::ToReg(Reg::R8, Arg32::Signed(3))),
+                        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Reg(Reg::R8))),
+                        Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Signed(8))),
+                    ]);
+                    res
+                }
             }
         }
         SeqExp::Let {
@@ -562,7 +1763,9 @@ fn compile_to_instrs_inner<'a, 'b>(
             body,
             ann,
         } => {
-            let mut res = compile_to_instrs_inner(&bound_exp, counter, stack, vars, functions);
+            // bindings made while computing bound_exp are not in scope in the body
+            let mut res =
+                compile_to_instrs_inner(&bound_exp, counter, stack, &mut vars.clone(), functions);
             let offset: i32 = ((stack + 1) * -8).try_into().unwrap();
             res.push(Instr::Mov(MovArgs::ToMem(
                 MemRef {
@@ -663,12 +1866,15 @@ fn compile_to_instrs_inner<'a, 'b>(
                         return res;
                     }
                     VarOrLabel::Var(func) => {
-                        let mut res = compile_tail_call(args, stack, 0, vars);
+                        // the arguments may overwrite the code pointer's slot
+                        let mut res = vec![Instr::Mov(MovArgs::ToReg(
+                            Reg::R8,
+                            imm_to_arg64(&ImmExp::Var(func.to_string()), vars),
+                        ))];
+                        res.extend(compile_tail_call(args, stack, 0, vars));
                         res.extend(vec![
-                            Instr::Mov(MovArgs::ToReg(
-                                Reg::Rax,
-                                imm_to_arg64(&ImmExp::Var(func.to_string()), vars),
-                            )),
+                            Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R8))),
+                            Instr::Mov(MovArgs::ToReg(Reg::Rcx, closure_arg_count(args))),
                             Instr::Jmp(JmpArg::Reg(Reg::Rax)),
                         ]);
                         return res;
@@ -693,6 +1899,17 @@ fn compile_to_instrs_inner<'a, 'b>(
                 )));
                 offset += 8;
             }
+            // the code pointer has to be loaded before rsp moves
+            if let VarOrLabel::Var(func) = fun {
+                res.push(Instr::Mov(MovArgs::ToReg(
+                    Reg::Rax,
+                    imm_to_arg64(&ImmExp::Var(func.to_string()), vars),
+                )));
+                res.push(Instr::Mov(MovArgs::ToReg(
+                    Reg::Rcx,
+                    closure_arg_count(args),
+                )));
+            }
             res.push(Instr::Sub(BinArgs::ToReg(
                 Reg::Rsp,
                 Arg32::Signed(stack_top),
@@ -701,15 +1918,7 @@ fn compile_to_instrs_inner<'a, 'b>(
                 VarOrLabel::Label(fun_str) => {
                     res.push(Instr::Call(JmpArg::Label(format!("func_{}", fun_str))));
                 }
-                VarOrLabel::Var(func) => {
-                    res.extend(vec![
-                        Instr::Mov(MovArgs::ToReg(
-                            Reg::Rax,
-                            imm_to_arg64(&ImmExp::Var(func.to_string()), vars),
-                        )),
-                        Instr::Jmp(JmpArg::Reg(Reg::Rax)),
-                    ]);
-                }
+                VarOrLabel::Var(_) => res.push(Instr::Call(JmpArg::Reg(Reg::Rax))),
             }
             res.push(Instr::Add(BinArgs::ToReg(
                 Reg::Rsp,
@@ -719,12 +1928,39 @@ fn compile_to_instrs_inner<'a, 'b>(
         }
         SeqExp::MakeClosure {
             arity,
+            variadic,
             label,
+            keywords,
+            defaults,
             env,
             ann,
         } => {
-            vec![
-                Instr::RelativeLoadAddress(Reg::Rax, format!("func_{}", label)),
+            // a variadic closure is entered through code that packs the
+            // extra arguments, and its arity is stored as -(arity + 1).
+            // The last word is the entry that binds the arguments by name.
+            *counter += 1;
+            let bind = format!("bind_entry_{}", counter);
+            let done = format!("closure_{}", counter);
+            let mut res = vec![Instr::Jmp(JmpArg::Label(done.clone()))];
+            let mut code = format!("func_{}", label);
+            res.extend(bind_entry(
+                *arity,
+                *variadic,
+                arity - defaults,
+                *keywords,
+                &code,
+                &bind,
+            ));
+            let mut arity_word = Arg64::Unsigned(*arity as u64);
+            if *variadic {
+                let entry = format!("rest_entry_{}", counter);
+                res.extend(rest_entry(*arity, &code, &entry, counter));
+                code = entry;
+                arity_word = Arg64::Signed(-(*arity as i64) - 1);
+            }
+            res.push(Instr::Label(done));
+            res.extend(vec![
+                Instr::RelativeLoadAddress(Reg::Rax, code),
                 Instr::Mov(MovArgs::ToMem(
                     MemRef {
                         reg: Reg::R15,
@@ -732,7 +1968,7 @@ fn compile_to_instrs_inner<'a, 'b>(
                     },
                     Reg32::Reg(Reg::Rax),
                 )),
-                Instr::Mov(MovArgs::ToReg(Reg::R8, Arg64::Unsigned(*arity as u64))),
+                Instr::Mov(MovArgs::ToReg(Reg::R8, arity_word)),
                 Instr::Mov(MovArgs::ToMem(
                     MemRef {
                         reg: Reg::R15,
@@ -748,12 +1984,128 @@ fn compile_to_instrs_inner<'a, 'b>(
                     },
                     Reg32::Reg(Reg::R8),
                 )),
+                Instr::RelativeLoadAddress(Reg::Rax, bind),
+                Instr::Mov(MovArgs::ToMem(
+                    MemRef {
+                        reg: Reg::R15,
+                        offset: Offset::Constant(24),
+                    },
+                    Reg32::Reg(Reg::Rax),
+                )),
                 Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::R15))),
                 Instr::Add(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
-                Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Unsigned(24))),
-            ]
+                Instr::Add(BinArgs::ToReg(Reg::R15, Arg32::Unsigned(32))),
+            ]);
+            res
         }
         SeqExp::Semicolon { e1, e2, ann } => todo!(),
+        SeqExp::While { cond, body, .. } => {
+            *counter += 1;
+            let loop_label = format!("while_{}", counter);
+            let done_label = format!("while_done_{}", counter);
+            let mut res = vec![Instr::Label(loop_label.clone())];
+            res.extend(compile_to_instrs_inner(
+                cond,
+                counter,
+                stack,
+                &mut vars.clone(),
+                functions,
+            ));
+            res.extend(if_check(Reg::Rax));
+            res.extend(vec![
+                Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Unsigned(SNAKE_FLS))),
+                Instr::Cmp(BinArgs::ToReg(Reg::Rax, Arg32::Reg(Reg::Rdx))),
+                Instr::Je(JmpArg::Label(done_label.clone())),
+            ]);
+            res.extend(compile_to_instrs_inner(
+                body,
+                counter,
+                stack,
+                &mut vars.clone(),
+                functions,
+            ));
+            res.push(Instr::Jmp(JmpArg::Label(loop_label)));
+            // Rax still holds false from the condition
+            res.push(Instr::Label(done_label));
+            res
+        }
+        // The handler record [previous record, rsp, address of the handler]
+        // is kept in three stack slots below the variables in scope, and
+        // CURRENT_HANDLER points to it while the body runs. An error
+        // restores rsp from the record and jumps to the handler with the
+        // raised value in Rax, see unwind_instr.
+        SeqExp::Try {
+            body, var, handler, ..
+        } => {
+            *counter += 1;
+            let catch_label = format!("catch_{}", counter);
+            let done_label = format!("try_done_{}", counter);
+            let record = -8 * (stack + 3);
+            let slot = |i: i32| MemRef {
+                reg: Reg::Rsp,
+                offset: Offset::Constant(record + 8 * i),
+            };
+            let current = MemRef {
+                reg: Reg::Rdx,
+                offset: Offset::Constant(0),
+            };
+            let uninstall = vec![
+                Instr::RelativeLoadAddress(Reg::Rdx, CURRENT_HANDLER.to_string()),
+                Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Mem(slot(0)))),
+                Instr::Mov(MovArgs::ToMem(current, Reg32::Reg(Reg::Rcx))),
+            ];
+            let mut res = vec![
+                Instr::RelativeLoadAddress(Reg::Rdx, CURRENT_HANDLER.to_string()),
+                Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Mem(current))),
+                Instr::Mov(MovArgs::ToMem(slot(0), Reg32::Reg(Reg::Rcx))),
+                Instr::Mov(MovArgs::ToMem(slot(1), Reg32::Reg(Reg::Rsp))),
+                Instr::RelativeLoadAddress(Reg::Rcx, catch_label.clone()),
+                Instr::Mov(MovArgs::ToMem(slot(2), Reg32::Reg(Reg::Rcx))),
+                Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Reg(Reg::Rsp))),
+                Instr::Sub(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(-record))),
+                Instr::Mov(MovArgs::ToMem(current, Reg32::Reg(Reg::Rcx))),
+            ];
+            res.extend(compile_to_instrs_inner(
+                body,
+                counter,
+                stack + 3,
+                &mut vars.clone(),
+                functions,
+            ));
+            res.extend(uninstall.clone());
+            res.push(Instr::Jmp(JmpArg::Label(done_label.clone())));
+            res.push(Instr::Label(catch_label));
+            res.extend(uninstall);
+            let offset = -8 * (stack + 1);
+            res.push(Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::Rsp,
+                    offset: Offset::Constant(offset),
+                },
+                Reg32::Reg(Reg::Rax),
+            )));
+            let mut handler_vars = vars.clone();
+            handler_vars.insert(var.clone(), offset);
+            res.extend(compile_to_instrs_inner(
+                handler,
+                counter,
+                stack + 1,
+                &mut handler_vars,
+                functions,
+            ));
+            res.push(Instr::Label(done_label));
+            res
+        }
+        SeqExp::Assign { var, value, .. } => vec![
+            Instr::Mov(MovArgs::ToReg(Reg::Rax, imm_to_arg64(value, vars))),
+            Instr::Mov(MovArgs::ToMem(
+                MemRef {
+                    reg: Reg::Rsp,
+                    offset: Offset::Constant(vars[var]),
+                },
+                Reg32::Reg(Reg::Rax),
+            )),
+        ],
     }
 }
 
@@ -869,7 +2221,7 @@ where
     Span: Clone,
 {
     checker::check_prog(p, &HashSet::new())?;
-    let (global_functions, main) = lambda_lift(&p);
+    let (global_functions, main, tables) = lambda_lift(&p);
     println!("global function size = {}", global_functions.len());
     println!("main = {:?}", main);
     for f in &global_functions {
@@ -888,12 +2240,46 @@ where
 
     let res = format!(
         "\
-section .data
-        HEAP:    times 1024 dq 0
+section .bss
+        HEAP:    resq 1048576
+        HEAP_END:
+        HANDLER:    resq 1
+        SAVED_R15:  resq 1
+        GENERATOR:  resq 1
+        ERROR_STACK:    resq 65536
+        ERROR_STACK_END:
 section .text
         global start_here
+        global snake_unwind
+        global snake_switch
+        global snake_thread_start
+        global snake_error_ctor
         extern snake_error
         extern print_snake_val
+        extern snake_arith
+        extern snake_cmp
+        extern snake_num_eq
+        extern snake_equal
+        extern snake_destructure_error
+        extern snake_cast_error
+        extern snake_generator
+        extern snake_generator_done
+        extern snake_generator_failed
+        extern snake_spawn
+        extern snake_channel
+        extern snake_send
+        extern snake_recv
+        extern snake_thread_done
+        extern snake_bind_arguments
+        extern snake_dict
+        extern snake_dict_get
+        extern snake_dict_put
+        extern snake_dict_has
+        extern snake_dict_keys
+        extern snake_delay
+        extern snake_forced
+        extern snake_set_thunk
+        extern snake_assert_error
 {}
 {}
 start_here:
@@ -910,11 +2296,21 @@ start_here:
 main:
 {}
 {}
+{}
 ",
-        instrs_to_string(&error_handle_instr()),
+        instrs_to_string(
+            &[
+                error_handle_instr(),
+                unwind_instr(),
+                generator_instr(),
+                thread_instr()
+            ]
+            .concat()
+        ),
         functions_is,
         instrs_to_string(&stack_check()),
-        main_is
+        main_is,
+        descriptors(&tables)
     );
     println!("{}", res);
     Ok(res)
@@ -0,0 +1,503 @@
+use crate::syntax::*;
+use std::collections::HashMap;
+
+// Rewrites the program into the smaller language the later passes know.
+// [location] gives the line and column that failing checks report
+pub fn desugar_prog<Ann>(e: &Exp<Ann>, location: &dyn Fn(&Ann) -> (usize, usize)) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    desugar(e, location, &mut 0)
+}
+
+fn desugar<Ann>(
+    e: &Exp<Ann>,
+    location: &dyn Fn(&Ann) -> (usize, usize),
+    counter: &mut u32,
+) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    let mut go = |e: &Exp<Ann>| Box::new(desugar(e, location, counter));
+    match e {
+        Exp::Num(..) | Exp::Bool(..) | Exp::Var(..) => e.clone(),
+        // assert(a == b, code)
+        // ~>
+        // let l = a, r = b in
+        // if l == r: true else: AssertFailed(==, line, col)(l, r, code)
+        // the code is only evaluated once the assertion failed
+        Exp::Prim(Prim::Assert, es, ann) => {
+            let (line, col) = location(ann);
+            let mut bindings = vec![];
+            let comparison = match &*es[0] {
+                Exp::Prim(op, _, _) => Comparison::of(*op),
+                _ => None,
+            };
+            let (cond, mut args) = match (&*es[0], comparison) {
+                (Exp::Prim(op, operands, cmp_ann), Some(_)) => {
+                    let operands: Vec<_> = operands
+                        .iter()
+                        .map(|e| {
+                            let e = desugar(e, location, counter);
+                            Box::new(bind_fresh(e, ann, counter, &mut bindings))
+                        })
+                        .collect();
+                    (Exp::Prim(*op, operands.clone(), cmp_ann.clone()), operands)
+                }
+                (cond, _) => (desugar(cond, location, counter), vec![]),
+            };
+            if let Some(code) = es.get(1) {
+                args.push(Box::new(desugar(code, location, counter)));
+            }
+            let fail = Exp::Prim(Prim::AssertFailed(comparison, line, col), args, ann.clone());
+            let assert = Exp::If {
+                cond: Box::new(cond),
+                thn: Box::new(Exp::Bool(true, ann.clone())),
+                els: Box::new(fail),
+                ann: ann.clone(),
+            };
+            wrap_let(bindings, assert, ann)
+        }
+        // force_lazy(e)
+        // ~>
+        // let t = e in
+        // if Forced(t): ThunkValue(t) else: SetThunk(t, ThunkValue(t)())
+        // the closure is called from the program itself, so both backends
+        // run it as any other call
+        Exp::Prim(Prim::Force, es, ann) => {
+            let thunk = desugar(&es[0], location, counter);
+            *counter += 1;
+            let t = format!("{}", counter);
+            let var = || Box::new(Exp::Var(t.clone(), ann.clone()));
+            let prim = |op, es| Box::new(Exp::Prim(op, es, ann.clone()));
+            let run = Exp::Call(prim(Prim::ThunkValue, vec![var()]), vec![], ann.clone());
+            let force = Exp::If {
+                cond: prim(Prim::Forced, vec![var()]),
+                thn: prim(Prim::ThunkValue, vec![var()]),
+                els: prim(Prim::SetThunk, vec![var(), Box::new(run)]),
+                ann: ann.clone(),
+            };
+            wrap_let(vec![(t.clone(), thunk)], force, ann)
+        }
+        Exp::Prim(op, es, ann) => Exp::Prim(*op, es.iter().map(|e| go(e)).collect(), ann.clone()),
+        Exp::Let {
+            bindings,
+            body,
+            ann,
+        } => Exp::Let {
+            bindings: bindings.iter().map(|(x, e)| (x.clone(), *go(e))).collect(),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        // let [a, b] = e in body
+        // ~>
+        // let arr = CheckLength(2, line, col)(e), a = arr[0], b = arr[1] in body
+        Exp::LetPattern {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut flat = vec![];
+            for (pattern, e) in bindings {
+                let value = desugar(e, location, counter);
+                destructure(pattern, value, ann, location(ann), counter, &mut flat);
+            }
+            Exp::Let {
+                bindings: flat,
+                body: Box::new(desugar(body, location, counter)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond: go(cond),
+            thn: go(thn),
+            els: go(els),
+            ann: ann.clone(),
+        },
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: go(e1),
+            e2: go(e2),
+            ann: ann.clone(),
+        },
+        Exp::Assign { var, value, ann } => Exp::Assign {
+            var: var.clone(),
+            value: go(value),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, ann } => Exp::While {
+            cond: go(cond),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        Exp::Try {
+            body,
+            var,
+            handler,
+            ann,
+        } => Exp::Try {
+            body: go(body),
+            var: var.clone(),
+            handler: go(handler),
+            ann: ann.clone(),
+        },
+        Exp::ForRange {
+            var,
+            start,
+            end,
+            body,
+            ann,
+        } => Exp::ForRange {
+            var: var.clone(),
+            start: go(start),
+            end: go(end),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        Exp::ForEach {
+            var,
+            array,
+            body,
+            ann,
+        } => Exp::ForEach {
+            var: var.clone(),
+            array: go(array),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        Exp::Match {
+            scrutinee,
+            arms,
+            ann,
+        } => Exp::Match {
+            scrutinee: go(scrutinee),
+            arms: arms.iter().map(|(p, e)| (p.clone(), *go(e))).collect(),
+            ann: ann.clone(),
+        },
+        // default values are evaluated once, when the functions are
+        // defined, outside of their scope:
+        // def f(x, y = e): body in rest
+        // ~>
+        // let d = e in def f(x, y = d): body in rest
+        Exp::FunDefs { decls, body, ann } => {
+            let mut bindings = vec![];
+            let results: HashMap<String, TypeAnn> = decls
+                .iter()
+                .filter_map(|decl| Some((decl.name.clone(), decl.result_type.as_ref()?.0)))
+                .collect();
+            let decls = decls
+                .iter()
+                .map(|decl| FunDecl {
+                    name: decl.name.clone(),
+                    parameters: decl.parameters.clone(),
+                    variadic: decl.variadic,
+                    defaults: decl
+                        .defaults
+                        .iter()
+                        .map(|e| {
+                            let value = desugar(e, location, counter);
+                            bind_fresh(value, ann, counter, &mut bindings)
+                        })
+                        .collect(),
+                    param_types: decl.param_types.clone(),
+                    result_type: decl.result_type.clone(),
+                    doc: decl.doc.clone(),
+                    body: {
+                        let body = desugar(&decl.body, location, counter);
+                        cast_boundaries(decl, body, &results, location, counter)
+                    },
+                    ann: decl.ann.clone(),
+                })
+                .collect();
+            let defs = Exp::FunDefs {
+                decls,
+                body: Box::new(desugar(body, location, counter)),
+                ann: ann.clone(),
+            };
+            wrap_let(bindings, defs, ann)
+        }
+        Exp::Record { fields, ann } => Exp::Record {
+            fields: fields.iter().map(|(f, e)| (f.clone(), *go(e))).collect(),
+            ann: ann.clone(),
+        },
+        Exp::GetField { record, field, ann } => Exp::GetField {
+            record: go(record),
+            field: field.clone(),
+            ann: ann.clone(),
+        },
+        Exp::SetField {
+            record,
+            field,
+            value,
+            ann,
+        } => Exp::SetField {
+            record: go(record),
+            field: field.clone(),
+            value: go(value),
+            ann: ann.clone(),
+        },
+        Exp::DataDefs {
+            name,
+            ctors,
+            body,
+            ann,
+        } => Exp::DataDefs {
+            name: name.clone(),
+            ctors: ctors.clone(),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        Exp::Lambda {
+            parameters,
+            variadic,
+            defaults,
+            body,
+            ann,
+        } => {
+            let mut bindings = vec![];
+            let defaults = defaults
+                .iter()
+                .map(|e| {
+                    let value = desugar(e, location, counter);
+                    bind_fresh(value, ann, counter, &mut bindings)
+                })
+                .collect();
+            let lambda = Exp::Lambda {
+                parameters: parameters.clone(),
+                variadic: *variadic,
+                defaults,
+                body: Box::new(desugar(body, location, counter)),
+                ann: ann.clone(),
+            };
+            wrap_let(bindings, lambda, ann)
+        }
+        Exp::Call(fun, args, ann) => {
+            Exp::Call(go(fun), args.iter().map(|e| *go(e)).collect(), ann.clone())
+        }
+        // the arguments are evaluated in the order they are written, even
+        // though they are passed in the order of the parameters:
+        // f(e1, x = e2)
+        // ~>
+        // let a1 = e1, a2 = e2 in f(a1, x = a2)
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ann,
+        } => {
+            let mut bindings = vec![];
+            let fun = match desugar(fun, location, counter) {
+                fun @ Exp::Var(..) => fun,
+                fun => bind_fresh(fun, ann, counter, &mut bindings),
+            };
+            let args = args
+                .iter()
+                .map(|e| {
+                    let value = desugar(e, location, counter);
+                    bind_fresh(value, ann, counter, &mut bindings)
+                })
+                .collect();
+            let keywords = keywords
+                .iter()
+                .map(|(x, e)| {
+                    let value = desugar(e, location, counter);
+                    (x.clone(), bind_fresh(value, ann, counter, &mut bindings))
+                })
+                .collect();
+            let call = Exp::KeywordCall {
+                fun: Box::new(fun),
+                args,
+                keywords,
+                ann: ann.clone(),
+            };
+            wrap_let(bindings, call, ann)
+        }
+        Exp::MakeClosure { .. }
+        | Exp::ClosureCall(..)
+        | Exp::DirectCall(..)
+        | Exp::InternalTailCall(..)
+        | Exp::ExternalCall { .. } => unreachable!("desugar runs before lambda lifting"),
+    }
+}
+
+// The body of a function with type annotations checks the annotated
+// parameters when it's entered and the result when it returns:
+// def f(x: Num): Bool: body
+// ~>
+// def f(x): let c = Cast(Num)(x) in Cast(Bool)(body)
+// The annotations stay on the function for the checker, which rejects
+// the casts and calls that are known to fail. [results] are the result
+// annotations of the functions defined along with [decl], see cast_result
+fn cast_boundaries<Ann>(
+    decl: &FunDecl<Exp<Ann>, Ann>,
+    body: Exp<Ann>,
+    results: &HashMap<String, TypeAnn>,
+    location: &dyn Fn(&Ann) -> (usize, usize),
+    counter: &mut u32,
+) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    let mut results = results.clone();
+    for x in &decl.parameters {
+        results.remove(x);
+    }
+    let body = match &decl.result_type {
+        Some((t, t_ann)) => cast_result(body, *t, location(t_ann), &results),
+        None => body,
+    };
+    let mut bindings = vec![];
+    for (x, t) in decl.parameters.iter().zip(&decl.param_types) {
+        if let Some((t, t_ann)) = t {
+            *counter += 1;
+            let param = Exp::Var(x.clone(), decl.ann.clone());
+            let (line, col) = location(t_ann);
+            let cast = Exp::Prim(
+                Prim::Cast(*t, line, col),
+                vec![Box::new(param)],
+                decl.ann.clone(),
+            );
+            bindings.push((format!("{}", counter), cast));
+        }
+    }
+    wrap_let(bindings, body, &decl.ann)
+}
+
+// Casts the value of [e] to [t] in each of its tail positions, so that a
+// tail call stays one when it calls a function that casts its own result
+// to [t], which is what [results] maps its name to. A value that is known
+// to have type [t] isn't cast
+fn cast_result<Ann>(
+    e: Exp<Ann>,
+    t: TypeAnn,
+    (line, col): (usize, usize),
+    results: &HashMap<String, TypeAnn>,
+) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    match e {
+        Exp::Let {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut results = results.clone();
+            for (x, _) in &bindings {
+                results.remove(x);
+            }
+            Exp::Let {
+                bindings,
+                body: Box::new(cast_result(*body, t, (line, col), &results)),
+                ann,
+            }
+        }
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond,
+            thn: Box::new(cast_result(*thn, t, (line, col), results)),
+            els: Box::new(cast_result(*els, t, (line, col), results)),
+            ann,
+        },
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1,
+            e2: Box::new(cast_result(*e2, t, (line, col), results)),
+            ann,
+        },
+        Exp::Call(ref f, _, _) if matches!(&**f, Exp::Var(g, _) if results.get(g) == Some(&t)) => e,
+        _ if e.known_type() == Some(t) => e,
+        _ => {
+            let ann = e.ann().clone();
+            Exp::Prim(Prim::Cast(t, line, col), vec![Box::new(e)], ann)
+        }
+    }
+}
+
+// Binds [value] to a fresh variable in [bindings] and evaluates to the
+// variable, unless [value] is a constant. Variables are bound too since
+// they may be assigned before the fresh one is used
+fn bind_fresh<Ann>(
+    value: Exp<Ann>,
+    ann: &Ann,
+    counter: &mut u32,
+    bindings: &mut Vec<(String, Exp<Ann>)>,
+) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    match value {
+        Exp::Num(..) | Exp::Bool(..) => value,
+        _ => {
+            *counter += 1;
+            let x = format!("{}", counter);
+            bindings.push((x.clone(), value));
+            Exp::Var(x, ann.clone())
+        }
+    }
+}
+
+fn wrap_let<Ann>(bindings: Vec<(String, Exp<Ann>)>, body: Exp<Ann>, ann: &Ann) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    if bindings.is_empty() {
+        return body;
+    }
+    Exp::Let {
+        bindings,
+        body: Box::new(body),
+        ann: ann.clone(),
+    }
+}
+
+// Appends to [bindings] a binding for every variable of [pattern] to its
+// part of [value]. Wildcards are bound to fresh names so the value is
+// still evaluated, and still checked if it is destructured further.
+fn destructure<Ann>(
+    pattern: &Pattern,
+    value: Exp<Ann>,
+    ann: &Ann,
+    (line, col): (usize, usize),
+    counter: &mut u32,
+    bindings: &mut Vec<(String, Exp<Ann>)>,
+) where
+    Ann: Clone,
+{
+    match pattern {
+        Pattern::Var(x) => bindings.push((x.clone(), value)),
+        Pattern::Wildcard => {
+            *counter += 1;
+            bindings.push((format!("{}", counter), value));
+        }
+        Pattern::Array(ps) => {
+            *counter += 1;
+            let arr = format!("{}", counter);
+            let check = Prim::CheckLength(ps.len(), line, col);
+            bindings.push((
+                arr.clone(),
+                Exp::Prim(check, vec![Box::new(value)], ann.clone()),
+            ));
+            for (i, p) in ps.iter().enumerate() {
+                let elt = Exp::Prim(
+                    Prim::ArrayGet,
+                    vec![
+                        Box::new(Exp::Var(arr.clone(), ann.clone())),
+                        Box::new(Exp::Num(i as i64, ann.clone())),
+                    ],
+                    ann.clone(),
+                );
+                destructure(p, elt, ann, (line, col), counter, bindings);
+            }
+        }
+        // the parser only allows variables, wildcards and arrays
+        Pattern::Num(_) | Pattern::Bool(_) | Pattern::Constructor(..) => unreachable!(),
+    }
+}
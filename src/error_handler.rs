@@ -1,6 +1,6 @@
-use crate::asm::{Arg32, Arg64, BinArgs, Instr, JmpArg, MovArgs, Reg};
+use crate::asm::{Arg32, Arg64, BinArgs, Instr, JmpArg, MemRef, MovArgs, Offset, Reg, Reg32};
+use crate::syntax::ErrorCode;
 
-pub static OVERFLOW: &str = "overflow_error";
 pub static ARITH_ERROR: &str = "arith_error";
 pub static CMP_ERROR: &str = "cmp_error";
 pub static IF_ERROR: &str = "if_error";
@@ -10,8 +10,32 @@ pub static INDEX_ERROR: &str = "index_not_number";
 pub static INDEX_OUT_OF_BOUNDS: &str = "index_out_of_bounds";
 pub static NON_CLOSURE_ERROR: &str = "non_closure_error";
 pub static LAMBDA_ARITY_ERROR: &str = "lambda_arity_error";
+pub static ARRAY_SIZE_ERROR: &str = "array_size_not_number";
+pub static NEGATIVE_SIZE_ERROR: &str = "negative_array_size";
+pub static OUT_OF_MEMORY: &str = "out_of_memory";
+pub static NON_VECTOR_ERROR: &str = "non_vector_error";
+pub static EMPTY_VECTOR_ERROR: &str = "empty_vector_error";
+pub static MATCH_ERROR: &str = "match_error";
+pub static MISSING_FIELD_ERROR: &str = "missing_field_error";
+pub static NON_RECORD_ERROR: &str = "non_record_error";
+pub static NON_GENERATOR_ERROR: &str = "non_generator_error";
+pub static GENERATOR_EXHAUSTED: &str = "generator_exhausted";
+pub static GENERATOR_RUNNING: &str = "generator_running";
+pub static YIELD_ERROR: &str = "yield_outside_generator";
 pub static STACK_ERROR: &str = "stack_error";
 pub static SNAKE_ERROR: &str = "snake_error";
+pub static RUNTIME_ERROR: &str = "runtime_error";
+pub static SNAKE_UNWIND: &str = "snake_unwind";
+// the address of the innermost handler record, 0 outside of any try
+pub static CURRENT_HANDLER: &str = "HANDLER";
+// the heap pointer when the runtime was last called
+pub static SAVED_HEAP_POINTER: &str = "SAVED_R15";
+// the top of the stack snake_error runs on
+pub static ERROR_STACK: &str = "ERROR_STACK_END";
+
+pub fn error_code(code: ErrorCode) -> Arg64 {
+    Arg64::Signed(code as i64)
+}
 
 pub fn stack_check() -> Vec<Instr> {
     vec![
@@ -22,51 +46,166 @@ pub fn stack_check() -> Vec<Instr> {
     ]
 }
 
+// The labels report the value in the register they move to Rsi, and
+// snake_error gets the address of the name of a missing field in Rdx
 pub fn error_handle_instr() -> Vec<Instr> {
-    vec![
+    let mut res = vec![
         Instr::Label(ARITH_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(0))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::ArithType))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(CMP_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(1))),
-        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
-        Instr::Label(OVERFLOW.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(2))),
-        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::CmpType))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rcx))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(IF_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(3))),
-        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::IfType))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rcx))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(LOGIC_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(4))),
-        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::LogicType))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rcx))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(NON_ARRAY_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(5))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NonArray))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(INDEX_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(6))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::IndexNotNumber))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(INDEX_OUT_OF_BOUNDS.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(7))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::IndexOutOfBounds))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(NON_CLOSURE_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(8))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NonClosure))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(LAMBDA_ARITY_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(9))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::LambdaArity))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(ARRAY_SIZE_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::ArraySize))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
-        Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(NEGATIVE_SIZE_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NegativeSize))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(OUT_OF_MEMORY.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::OutOfMemory))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::R8))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(NON_VECTOR_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NonVector))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(EMPTY_VECTOR_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::EmptyVector))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(MATCH_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::Match))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(MISSING_FIELD_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::MissingField))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(NON_RECORD_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NonRecord))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(NON_GENERATOR_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::NonGenerator))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(GENERATOR_EXHAUSTED.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::GeneratorExhausted))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(GENERATOR_RUNNING.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::GeneratorRunning))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+        Instr::Label(YIELD_ERROR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::Yield))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
         Instr::Label(STACK_ERROR.to_string()),
-        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Signed(99))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, error_code(ErrorCode::Stack))),
         Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rsp))),
+        Instr::Jmp(JmpArg::Label(RUNTIME_ERROR.to_string())),
+    ];
+    // snake_error may unwind to a handler, which needs the heap pointer.
+    // The error labels are jumped to with the locals of the failing code
+    // below rsp, which the handler may still use, so snake_error runs on
+    // a stack of its own. It never returns.
+    res.push(Instr::Label(RUNTIME_ERROR.to_string()));
+    res.extend(save_heap_pointer());
+    res.extend(vec![
+        Instr::RelativeLoadAddress(Reg::Rsp, ERROR_STACK.to_string()),
+        Instr::And(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(-16))),
         Instr::Call(JmpArg::Label(SNAKE_ERROR.to_string())),
+    ]);
+    res
+}
+
+// Clobbers R11
+pub fn save_heap_pointer() -> Vec<Instr> {
+    vec![
+        Instr::RelativeLoadAddress(Reg::R11, SAVED_HEAP_POINTER.to_string()),
+        Instr::Mov(MovArgs::ToMem(
+            MemRef {
+                reg: Reg::R11,
+                offset: Offset::Constant(0),
+            },
+            Reg32::Reg(Reg::R15),
+        )),
     ]
+}
+
+// Clobbers R11
+pub fn restore_heap_pointer() -> Vec<Instr> {
+    vec![
+        Instr::RelativeLoadAddress(Reg::R11, SAVED_HEAP_POINTER.to_string()),
+        Instr::Mov(MovArgs::ToReg(
+            Reg::R15,
+            Arg64::Mem(MemRef {
+                reg: Reg::R11,
+                offset: Offset::Constant(0),
+            }),
+        )),
+    ]
+}
+
+// Called by the runtime with the value to pass to the innermost handler.
+// A handler record is [previous record, saved rsp, address of the
+// handler], see the compilation of Try. Returns if there is no handler.
+pub fn unwind_instr() -> Vec<Instr> {
+    let field = |i| {
+        Arg64::Mem(MemRef {
+            reg: Reg::Rdx,
+            offset: Offset::Constant(8 * i),
+        })
+    };
+    let mut res = vec![
+        Instr::Label(SNAKE_UNWIND.to_string()),
+        Instr::RelativeLoadAddress(Reg::Rdx, CURRENT_HANDLER.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, field(0))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(0))),
+        Instr::Jne(JmpArg::Label("unwind_to_handler".to_string())),
+        Instr::Ret,
+        Instr::Label("unwind_to_handler".to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsp, field(1))),
+    ];
+    res.extend(restore_heap_pointer());
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::Rdi))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, field(2))),
+        Instr::Jmp(JmpArg::Reg(Reg::Rcx)),
+    ]);
+    res
 }
\ No newline at end of file
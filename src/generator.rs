@@ -0,0 +1,153 @@
+use crate::asm::{Arg32, Arg64, BinArgs, Instr, JmpArg, MemRef, MovArgs, Offset, Reg, Reg32};
+use crate::error_handler::{
+    CURRENT_HANDLER, GENERATOR_RUNNING, NON_GENERATOR_ERROR, YIELD_ERROR,
+};
+
+/* A generator is a boxed value laid out as
+ *   [GENERATOR_KIND, state, closure, saved rsp, saved handler,
+ *    resumer's generator, last yielded value, base of its stack]
+ * and runs the body of the closure on a stack of its own, allocated by
+ * the runtime. While the generator runs, the saved rsp and handler are
+ * the ones of the code that resumed it, while it is suspended they are
+ * its own, so switching between the two is the same swap both ways.
+ */
+pub static GENERATOR_KIND: u32 = 4;
+pub static FRESH: i32 = 0;
+pub static SUSPENDED: i32 = 1;
+pub static RUNNING: i32 = 2;
+pub static FINISHED: i32 = 3;
+
+pub static SNAKE_RESUME: &str = "snake_resume";
+pub static SNAKE_YIELD: &str = "snake_yield";
+// the untagged address of the running generator, 0 outside of any
+pub static CURRENT_GENERATOR: &str = "GENERATOR";
+static GENERATOR_FAILED: &str = "generator_failed";
+
+fn field(reg: Reg, i: i32) -> MemRef {
+    MemRef {
+        reg,
+        offset: Offset::Constant(8 * i),
+    }
+}
+
+// The untagged generator in Rdx: swaps rsp and the innermost handler with
+// the ones saved in it. Clobbers R8, R9 and R10
+fn switch() -> Vec<Instr> {
+    vec![
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rdx, 3)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 3), Reg32::Reg(Reg::Rsp))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsp, Arg64::Reg(Reg::R9))),
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_HANDLER.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Mov(MovArgs::ToReg(Reg::R10, Arg64::Mem(field(Reg::Rdx, 4)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 4), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::R10))),
+    ]
+}
+
+// Sets the running generator, untagged in Rdx, to [state] and switches
+// back to the code that resumed it, which becomes the running generator
+// again. Clobbers R8, R9 and R10
+fn switch_back(state: i32) -> Vec<Instr> {
+    let mut res = vec![
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_GENERATOR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 1), Reg32::Signed(state))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rdx, 5)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::R9))),
+    ];
+    res.extend(switch());
+    res
+}
+
+// snake_resume is called with a generator in Rdi and returns the value it
+// yielded, or right away if it has finished: the caller tells the two
+// apart by the state of the generator.
+//
+// snake_yield is called by the running generator with the value to yield
+// and returns that value when the generator is resumed.
+//
+// A fresh generator first installs a handler record at the base of its
+// stack, so an error it doesn't catch finishes it and is raised again by
+// the runtime in the code that resumed it.
+pub fn generator_instr() -> Vec<Instr> {
+    let mut res = vec![
+        Instr::Label(SNAKE_RESUME.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::Rdi))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rdi))),
+        Instr::And(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b111))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b101))),
+        Instr::Jne(JmpArg::Label(NON_GENERATOR_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Reg(Reg::Rdi))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rdx, Arg32::Unsigned(0b101))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Mem(field(Reg::Rdx, 0)))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Unsigned(GENERATOR_KIND))),
+        Instr::Jne(JmpArg::Label(NON_GENERATOR_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToReg(Reg::Rcx, Arg64::Mem(field(Reg::Rdx, 1)))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(FINISHED))),
+        Instr::Je(JmpArg::Label("resume_done".to_string())),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(RUNNING))),
+        Instr::Je(JmpArg::Label(GENERATOR_RUNNING.to_string())),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 1), Reg32::Signed(RUNNING))),
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_GENERATOR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 5), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::Rdx))),
+    ];
+    res.extend(switch());
+    res.extend(vec![
+        Instr::Cmp(BinArgs::ToReg(Reg::Rcx, Arg32::Signed(FRESH))),
+        Instr::Je(JmpArg::Label("resume_fresh".to_string())),
+        // the value of the suspended yield, pushed by snake_yield
+        Instr::Pop(Arg32::Reg(Reg::Rax)),
+        Instr::Label("resume_done".to_string()),
+        Instr::Ret,
+        Instr::Label("resume_fresh".to_string()),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(32))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rsp, 0), Reg32::Signed(0))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rsp, 1), Reg32::Reg(Reg::Rsp))),
+        Instr::RelativeLoadAddress(Reg::R9, GENERATOR_FAILED.to_string()),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rsp, 2), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::Rsp))),
+        // call the closure with its environment as the only argument
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Mem(field(Reg::Rdx, 2)))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rax, 2)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rsp, -3), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Mem(field(Reg::Rax, 0)))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Call(JmpArg::Reg(Reg::Rax)),
+    ]);
+    res.extend(switch_back(FINISHED));
+    // back on the stack of the resumer, which returns to its caller
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rdx))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Call(JmpArg::Label("snake_generator_done".to_string())),
+        Instr::Add(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Ret,
+        // the handler at the base of the stack, with the error in Rax
+        Instr::Label(GENERATOR_FAILED.to_string()),
+    ]);
+    res.extend(switch_back(FINISHED));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rdi, Arg64::Reg(Reg::Rdx))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsi, Arg64::Reg(Reg::Rax))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Call(JmpArg::Label("snake_generator_failed".to_string())),
+        Instr::Label(SNAKE_YIELD.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::Rdi))),
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_GENERATOR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::Rdx, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Cmp(BinArgs::ToReg(Reg::Rdx, Arg32::Signed(0))),
+        Instr::Je(JmpArg::Label(YIELD_ERROR.to_string())),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdx, 6), Reg32::Reg(Reg::Rdi))),
+        Instr::Push(Arg32::Reg(Reg::Rdi)),
+    ]);
+    res.extend(switch_back(SUSPENDED));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Reg(Reg::Rdi))),
+        Instr::Ret,
+    ]);
+    res
+}
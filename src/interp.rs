@@ -1,6 +1,11 @@
-use crate::syntax::{Exp, Prim, SurfFunDecl, SurfProg};
+use crate::bignum::BigInt;
+use crate::syntax::{
+    runtime_error_ctor, Comparison, Constructor, ErrorCode, Exp, Pattern, Prim, SurfFunDecl,
+    SurfProg, TypeAnn, RUNTIME_ERROR_CTOR,
+};
 
-use std::collections::HashSet;
+use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
@@ -24,8 +29,19 @@ enum Machine<'exp, Ann> {
 pub enum SnakeVal {
     Num(i64), // should fit into 63 bits though
     Bool(bool),
-    Array(usize),   // index into the array arena
-    Closure(usize), // index into the closure arena
+    Array(usize),         // index into the array arena
+    Closure(usize),       // index into the closure arena
+    BigNum(usize),        // index into the bignum arena, never fits into 63 bits
+    Vector(usize),        // index into the array arena, but can grow
+    Data(usize, usize),   // constructor id, index of the fields in the array arena
+    Record(usize, usize), // shape id, index of the fields in the array arena
+    Generator(usize),     // index into the generator arena
+    Channel(usize),       // index into the channel arena
+    // index into the array arena, of the keys and their values in turn
+    Dict(usize),
+    // index into the array arena, of the closure or the value it computed
+    // and whether it was forced
+    Thunk(usize),
 }
 
 impl Display for SnakeVal {
@@ -35,6 +51,14 @@ impl Display for SnakeVal {
             SnakeVal::Bool(b) => write!(f, "{}", b),
             SnakeVal::Closure { .. } => write!(f, "closure"),
             SnakeVal::Array { .. } => write!(f, "array"),
+            SnakeVal::BigNum { .. } => write!(f, "bignum"),
+            SnakeVal::Vector { .. } => write!(f, "vector"),
+            SnakeVal::Data { .. } => write!(f, "data"),
+            SnakeVal::Record { .. } => write!(f, "record"),
+            SnakeVal::Generator { .. } => write!(f, "generator"),
+            SnakeVal::Channel { .. } => write!(f, "channel"),
+            SnakeVal::Dict { .. } => write!(f, "dict"),
+            SnakeVal::Thunk { .. } => write!(f, "thunk"),
         }
     }
 }
@@ -62,9 +86,11 @@ enum Stack<'exp, Ann> {
         body: &'exp Exp<Ann>,
         stk: Box<Stack<'exp, Ann>>,
     },
+    // the last keywords.len() arguments are passed by name
     CallFun {
         env: Env,
         args: Vec<&'exp Exp<Ann>>,
+        keywords: Vec<&'exp str>,
         stk: Box<Stack<'exp, Ann>>,
     },
     CallArgs {
@@ -72,39 +98,348 @@ enum Stack<'exp, Ann> {
         evaled_args: Vec<SnakeVal>,
         env: Env,
         remaining_args: Vec<&'exp Exp<Ann>>,
+        keywords: Vec<&'exp str>,
         stk: Box<Stack<'exp, Ann>>,
     },
     Semicolon {
         next: Closure<'exp, Ann>,
         stk: Box<Stack<'exp, Ann>>,
     },
+    Assign {
+        var: &'exp str,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the condition of [lp], a while loop
+    While {
+        lp: &'exp Exp<Ann>,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the start of a for over a range
+    ForStart {
+        var: &'exp str,
+        end: &'exp Exp<Ann>,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the end of a for over a range
+    ForEnd {
+        var: &'exp str,
+        start: SnakeVal,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the array of a for over an array
+    ForArray {
+        var: &'exp str,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the scrutinee of a match
+    Match {
+        arms: &'exp [(Pattern, Exp<Ann>)],
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the body of a for, run with [var] bound to [index]
+    // or, when there is an [array], to the element at [index]
+    For {
+        var: &'exp str,
+        index: SnakeVal,
+        stop: SnakeVal,
+        array: Option<usize>,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the body of the innermost try, whose handler is on top
+    // of the handlers of the store
+    Catch,
+    // waiting for the body of the running generator, which then finishes
+    GeneratorDone,
+    // waiting for the body of the running thread, which then finishes
+    ThreadDone,
+    // waiting for [generator] to yield the next value of a for over it
+    ForGenerator {
+        var: &'exp str,
+        generator: usize,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+    // waiting for the body of a for over [generator]
+    ForResume {
+        var: &'exp str,
+        generator: usize,
+        body: &'exp Exp<Ann>,
+        env: Env,
+        stk: Box<Stack<'exp, Ann>>,
+    },
+}
+
+// The handler of a try, run with [var] bound to whatever is raised in its
+// body and returning to [stk]
+struct Handler<'exp, Ann> {
+    var: &'exp str,
+    handler: &'exp Exp<Ann>,
+    env: Env,
+    stk: Stack<'exp, Ann>,
+}
+
+enum Generator<'exp, Ann> {
+    // the closure whose body it runs
+    Fresh(usize),
+    // yielded [v] with the rest of its body in [stk] and the handlers of
+    // the trys around the yield
+    Suspended {
+        v: SnakeVal,
+        stk: Stack<'exp, Ann>,
+        handlers: Vec<Handler<'exp, Ann>>,
+    },
+    Running,
+    Finished,
+}
+
+// The code that resumed the running generator: it continues with [stk]
+// and [handlers] when the generator yields or finishes
+struct Resumer<'exp, Ann> {
+    generator: usize,
+    stk: Stack<'exp, Ann>,
+    handlers: Vec<Handler<'exp, Ann>>,
+}
+
+// A thread that isn't running. Threads are switched only when the running
+// one waits on a channel or finishes, so the interpreter runs them in the
+// same order as the compiled program
+enum Thread<'exp, Ann> {
+    // the closure whose body it runs
+    Fresh(usize),
+    // returns [v] to [stk] when it runs again, with the handlers of the
+    // trys and the resumers of the generators it was in
+    Ready {
+        v: SnakeVal,
+        stk: Stack<'exp, Ann>,
+        handlers: Vec<Handler<'exp, Ann>>,
+        resumers: Vec<Resumer<'exp, Ann>>,
+    },
+    // waiting to receive on a channel, continuing like a ready thread
+    // with the value it receives
+    Waiting {
+        stk: Stack<'exp, Ann>,
+        handlers: Vec<Handler<'exp, Ann>>,
+        resumers: Vec<Resumer<'exp, Ann>>,
+    },
+    Running,
+    Finished,
+}
+
+// The values sent on a channel that no thread was waiting on, and the
+// threads waiting on it, in order
+struct Channel {
+    values: VecDeque<SnakeVal>,
+    receivers: VecDeque<usize>,
 }
 
-/* The semantic Store consists of arenas for allocating arrays and closures */
+/* The semantic Store consists of arenas for allocating arrays, closures and bignums */
 struct State<'e, Ann> {
     funs: Funs<'e, Ann>,
     heap: Heap,
+    used: i64, // the words the arrays take in the heap of the compiled code
+    bignums: Vec<BigInt>,
+    ctors: Vec<&'e Constructor>,     // indexed by constructor id
+    fields: Vec<&'e str>,            // indexed by field id
+    shapes: Vec<Vec<usize>>,         // the field ids of each record shape
+    handlers: Vec<Handler<'e, Ann>>, // the handlers of the enclosing trys
+    generators: Vec<Generator<'e, Ann>>,
+    resumers: Vec<Resumer<'e, Ann>>, // the innermost is running the current generator
+    threads: Vec<Thread<'e, Ann>>,   // the main thread is the first
+    current_thread: usize,
+    ready: VecDeque<usize>, // the threads ready to run, in the order they run
+    channels: Vec<Channel>,
 }
 type Heap = Vec<Vec<SnakeVal>>;
 
-struct SemFun<'e, Ann> {
-    parameters: &'e [String],
-    closure: Closure<'e, Ann>,
+enum SemFun<'e, Ann> {
+    Lambda {
+        parameters: &'e [String],
+        variadic: bool, // the last parameter is bound to the extra arguments
+        // the values of the last parameters before the rest parameter
+        defaults: Vec<SnakeVal>,
+        closure: Closure<'e, Ann>,
+    },
+    // builds a value of the constructor with this id from the arguments
+    Constructor(usize),
 }
 type Funs<'e, Ann> = Vec<SemFun<'e, Ann>>;
 
 impl<'e, Ann> State<'e, Ann> {
-    fn new() -> Self {
+    // [runtime_error] is the constructor 0 of what a try catches for a
+    // runtime error
+    fn new(runtime_error: &'e Constructor) -> Self {
         State {
             funs: vec![],
             heap: vec![],
+            used: 0,
+            bignums: vec![],
+            ctors: vec![runtime_error],
+            fields: vec![],
+            shapes: vec![],
+            handlers: vec![],
+            generators: vec![],
+            resumers: vec![],
+            threads: vec![Thread::Running],
+            current_thread: 0,
+            ready: VecDeque::new(),
+            channels: vec![],
+        }
+    }
+
+    // The index in the closure arena of [f], which fails unless it is a
+    // closure without parameters
+    fn prj_thunk_body(&self, f: SnakeVal) -> Interp<usize> {
+        let ptr = prj_fun(f)?;
+        let arity = match &self.funs[ptr] {
+            SemFun::Lambda { parameters, .. } => parameters.len(),
+            SemFun::Constructor(id) => self.ctors[*id].fields.len(),
+        };
+        if arity != 0 {
+            return Err(InterpErr::ArityErr {
+                expected_arity: arity,
+                num_provided: 0,
+                got: f,
+            });
+        }
+        Ok(ptr)
+    }
+
+    fn alloc_generator(&mut self, f: SnakeVal) -> Interp<SnakeVal> {
+        let ptr = self.prj_thunk_body(f)?;
+        self.generators.push(Generator::Fresh(ptr));
+        Ok(SnakeVal::Generator(self.generators.len() - 1))
+    }
+
+    // A thunk is the closure [f] until it is forced, and then the value
+    // it computed
+    fn alloc_thunk(&mut self, f: SnakeVal) -> Interp<SnakeVal> {
+        self.prj_thunk_body(f)?;
+        Ok(SnakeVal::Thunk(
+            self.alloc_array(vec![f, SnakeVal::Bool(false)]),
+        ))
+    }
+
+    // Makes a thread running the body of [f], a closure without
+    // parameters, ready to run. Evaluates to its id
+    fn spawn(&mut self, f: SnakeVal) -> Interp<SnakeVal> {
+        let ptr = self.prj_thunk_body(f)?;
+        self.threads.push(Thread::Fresh(ptr));
+        self.ready.push_back(self.threads.len() - 1);
+        Ok(SnakeVal::Num(self.threads.len() as i64 - 1))
+    }
+
+    // Sends [v] on [c]: the first thread waiting on it, if any, receives
+    // [v] and is ready to run
+    fn send(&mut self, c: SnakeVal, v: SnakeVal) -> Interp<SnakeVal> {
+        let channel = &mut self.channels[prj_channel(c)?];
+        match channel.receivers.pop_front() {
+            Some(receiver) => {
+                match std::mem::replace(&mut self.threads[receiver], Thread::Running) {
+                    Thread::Waiting {
+                        stk,
+                        handlers,
+                        resumers,
+                    } => {
+                        self.threads[receiver] = Thread::Ready {
+                            v,
+                            stk,
+                            handlers,
+                            resumers,
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                self.ready.push_back(receiver);
+            }
+            None => channel.values.push_back(v),
         }
+        Ok(v)
+    }
+
+    fn field_id(&mut self, field: &'e str) -> usize {
+        match self.fields.iter().position(|f| *f == field) {
+            Some(id) => id,
+            None => {
+                self.fields.push(field);
+                self.fields.len() - 1
+            }
+        }
+    }
+
+    fn shape_id(&mut self, fields: &'e [(String, Exp<Ann>)]) -> usize {
+        let shape: Vec<usize> = fields.iter().map(|(f, _)| self.field_id(f)).collect();
+        match self.shapes.iter().position(|s| *s == shape) {
+            Some(id) => id,
+            None => {
+                self.shapes.push(shape);
+                self.shapes.len() - 1
+            }
+        }
+    }
+
+    // RuntimeError(code, v), what a try catches for a runtime error
+    fn runtime_error(&mut self, code: ErrorCode, v: SnakeVal) -> SnakeVal {
+        SnakeVal::Data(0, self.alloc_array(vec![SnakeVal::Num(code as i64), v]))
+    }
+
+    // the index of the field in the record, which is stored at the
+    // returned heap pointer
+    fn field_index(&self, record: SnakeVal, field: usize) -> Interp<(usize, usize)> {
+        match record {
+            SnakeVal::Record(shape, ptr) => {
+                match self.shapes[shape].iter().position(|f| *f == field) {
+                    Some(i) => Ok((ptr, i)),
+                    None => Err(InterpErr::MissingField {
+                        field: self.fields[field].to_string(),
+                        got: record,
+                    }),
+                }
+            }
+            _ => Err(InterpErr::ExpectedRecord { got: record }),
+        }
+    }
+
+    // Defaults are constants or variables after desugar, so they are
+    // evaluated once, where the function is defined
+    fn eval_defaults(&mut self, defaults: &[Exp<Ann>], env: &Env) -> Vec<SnakeVal> {
+        defaults
+            .iter()
+            .map(|d| match d {
+                Exp::Num(n, _) => self.alloc_num(BigInt::from_i64(*n)),
+                Exp::Bool(b, _) => SnakeVal::Bool(*b),
+                Exp::Var(x, _) => env.lookup(x).expect("Unbound variable in interpreter!"),
+                _ => panic!("Shouldn't happen: defaults are bound to variables by desugar"),
+            })
+            .collect()
     }
 
-    fn alloc_fun(&mut self, parameters: &'e [String], body: &'e Exp<Ann>, env: Env) -> usize {
+    fn alloc_fun(
+        &mut self,
+        parameters: &'e [String],
+        variadic: bool,
+        defaults: &'e [Exp<Ann>],
+        body: &'e Exp<Ann>,
+        env: Env,
+    ) -> usize {
         let i = self.funs.len();
-        self.funs.push(SemFun {
+        let defaults = self.eval_defaults(defaults, &env);
+        self.funs.push(SemFun::Lambda {
             parameters,
+            variadic,
+            defaults,
             closure: Closure {
                 exp: body,
                 env: env.clone(),
@@ -113,6 +448,28 @@ impl<'e, Ann> State<'e, Ann> {
         i
     }
 
+    // A declaration that is evaluated again reuses its constructor ids,
+    // like the compiled code does
+    fn alloc_ctors(&mut self, ctors: &'e [Constructor], mut env: Env) -> Env {
+        for ctor in ctors {
+            let id = match self.ctors.iter().position(|c| std::ptr::eq(*c, ctor)) {
+                Some(id) => id,
+                None => {
+                    self.ctors.push(ctor);
+                    self.ctors.len() - 1
+                }
+            };
+            let v = if ctor.fields.is_empty() {
+                SnakeVal::Data(id, self.alloc_array(vec![]))
+            } else {
+                self.funs.push(SemFun::Constructor(id));
+                SnakeVal::Closure(self.funs.len() - 1)
+            };
+            env = env.push_local(ctor.name.clone(), v);
+        }
+        env
+    }
+
     fn alloc_funs(&mut self, decls: &'e [SurfFunDecl<Ann>], mut env: Env) -> Env {
         // Each of the closures captures the same environment: the
         // current environment extended with all of their names
@@ -122,8 +479,11 @@ impl<'e, Ann> State<'e, Ann> {
             env = env.push_local(d.name.clone(), SnakeVal::Closure(i + j));
         }
         for d in decls.iter() {
-            self.funs.push(SemFun {
+            let defaults = self.eval_defaults(&d.defaults, &env);
+            self.funs.push(SemFun::Lambda {
                 parameters: &d.parameters,
+                variadic: d.variadic,
+                defaults,
                 closure: Closure {
                     exp: &d.body,
                     env: env.clone(),
@@ -136,9 +496,42 @@ impl<'e, Ann> State<'e, Ann> {
     fn alloc_array(&mut self, vs: Vec<SnakeVal>) -> usize {
         // can raise an Out of memory error?
         let ptr = self.heap.len();
+        self.used += vs.len() as i64 + 1;
         self.heap.push(vs);
         ptr
     }
+
+    // Numbers that fit into 63 bits are always represented as Num so
+    // that equality on small numbers stays a simple comparison
+    fn alloc_num(&mut self, n: BigInt) -> SnakeVal {
+        match n.to_i64() {
+            Some(i) if !out_of_bounds(i) => SnakeVal::Num(i),
+            _ => {
+                let ptr = self.bignums.len();
+                self.bignums.push(n);
+                SnakeVal::BigNum(ptr)
+            }
+        }
+    }
+
+    fn big_of(&self, v: SnakeVal, who: &str, msg: &str) -> Interp<BigInt> {
+        match v {
+            SnakeVal::Num(n) => Ok(BigInt::from_i64(n)),
+            SnakeVal::BigNum(ptr) => Ok(self.bignums[ptr].clone()),
+            _ => Err(InterpErr::ExpectedNum {
+                who: String::from(who),
+                got: v,
+                msg: String::from(msg),
+            }),
+        }
+    }
+
+    fn num_eq(&self, v1: SnakeVal, v2: SnakeVal) -> bool {
+        match (v1, v2) {
+            (SnakeVal::BigNum(p1), SnakeVal::BigNum(p2)) => self.bignums[p1] == self.bignums[p2],
+            _ => v1 == v2,
+        }
+    }
 }
 
 // A reference-counted linked list/the functional programmer's List
@@ -175,9 +568,10 @@ fn get<'l, T>(stk: &'l List<(String, T)>, x: &str) -> Option<&'l T> {
 }
 
 // An environment is implemented as a ref-counted linked list to
-// enable sharing/avoid copying
+// enable sharing/avoid copying. Each variable is a shared cell so
+// that closures observe assignments made after they were created.
 #[derive(Debug, Clone)]
-struct Env(Rc<List<(String, SnakeVal)>>);
+struct Env(Rc<List<(String, Rc<Cell<SnakeVal>>)>>);
 
 impl Env {
     fn new() -> Env {
@@ -185,11 +579,18 @@ impl Env {
     }
 
     fn push_local(&self, name: String, v: SnakeVal) -> Env {
-        Env(Rc::new(List::Cons((name, v), self.0.clone())))
+        Env(Rc::new(List::Cons(
+            (name, Rc::new(Cell::new(v))),
+            self.0.clone(),
+        )))
+    }
+
+    fn lookup(&self, x: &str) -> Option<SnakeVal> {
+        get(&self.0, x).map(|cell| cell.get())
     }
 
-    fn lookup<'l>(&'l self, x: &str) -> Option<&'l SnakeVal> {
-        get(&self.0, x)
+    fn assign(&self, x: &str, v: SnakeVal) -> Option<()> {
+        get(&self.0, x).map(|cell| cell.set(v))
     }
 }
 
@@ -203,24 +604,53 @@ struct Closure<'exp, Ann> {
 pub enum InterpErr {
     ExpectedNum {
         who: String,
-        got: String,
+        got: SnakeVal,
         msg: String,
     },
     ExpectedBool {
         who: String,
-        got: String,
+        got: SnakeVal,
         msg: String,
     },
     ExpectedFun {
-        got: String,
+        got: SnakeVal,
     },
     ExpectedArray {
         msg: String,
         got: SnakeVal,
     },
-    ArrayOutOfBounds {},
-    Overflow {
+    ExpectedVector {
         msg: String,
+        got: SnakeVal,
+    },
+    ArrayOutOfBounds {
+        index: i64,
+    },
+    PopEmptyVector {
+        got: SnakeVal,
+    },
+    ExpectedRecord {
+        got: SnakeVal,
+    },
+    DestructureLength {
+        expected: usize,
+        got: SnakeVal,
+        line: usize,
+        col: usize,
+    },
+    MissingField {
+        field: String,
+        got: SnakeVal,
+    },
+    MatchFailure {
+        got: SnakeVal,
+    },
+    NegativeArraySize {
+        size: i64,
+    },
+    // an array of [size] elements doesn't fit into the heap
+    OutOfMemory {
+        size: i64,
     },
     Write {
         msg: String,
@@ -228,6 +658,54 @@ pub enum InterpErr {
     ArityErr {
         expected_arity: usize,
         num_provided: usize,
+        got: SnakeVal,
+    },
+    Raised {
+        got: SnakeVal,
+    },
+    ExpectedGenerator {
+        got: SnakeVal,
+    },
+    GeneratorExhausted {
+        got: SnakeVal,
+    },
+    GeneratorRunning {
+        got: SnakeVal,
+    },
+    YieldOutsideGenerator {
+        got: SnakeVal,
+    },
+    ExpectedChannel {
+        got: SnakeVal,
+    },
+    ExpectedDict {
+        got: SnakeVal,
+    },
+    ExpectedThunk {
+        got: SnakeVal,
+    },
+    // [msg] tells where the assertion is and the operands of the comparison
+    // it made, if any
+    AssertionFailed {
+        code: Option<SnakeVal>,
+        msg: String,
+    },
+    // a value that doesn't have the type of the annotation at [line], [col]
+    AnnotationFailed {
+        expected: TypeAnn,
+        got: SnakeVal,
+        line: usize,
+        col: usize,
+    },
+    Deadlock {},
+    MissingArgument {
+        param: String,
+    },
+    UnknownKeyword {
+        name: String,
+    },
+    DuplicateArgument {
+        param: String,
     },
 }
 
@@ -249,11 +727,65 @@ impl Display for InterpErr {
             InterpErr::ExpectedArray { got, msg } => {
                 write!(f, "Expected an array but got {} in {}", got, msg)
             }
-            InterpErr::Overflow { msg } => write!(f, "Operation {} overflowed", msg),
-            InterpErr::ArrayOutOfBounds {} => write!(f, "Array index out of bounds"),
+            InterpErr::ExpectedVector { got, msg } => {
+                write!(f, "Expected a vector but got {} in {}", got, msg)
+            }
+            InterpErr::PopEmptyVector { .. } => write!(f, "Cannot pop from an empty vector"),
+            InterpErr::ExpectedRecord { got } => write!(f, "Expected a record but got {}", got),
+            InterpErr::MissingField { field, .. } => write!(f, "Missing field {}", field),
+            InterpErr::DestructureLength {
+                expected,
+                got,
+                line,
+                col,
+            } => write!(
+                f,
+                "Destructuring at line {}, column {} expected an array of {} elements but got {}",
+                line, col, expected, got
+            ),
+            InterpErr::MatchFailure { got } => write!(f, "No pattern matched {}", got),
+            InterpErr::Raised { got } => write!(f, "Uncaught exception {}", got),
+            InterpErr::ExpectedGenerator { got } => {
+                write!(f, "Expected a generator but got {}", got)
+            }
+            InterpErr::GeneratorExhausted { .. } => write!(f, "Generator is exhausted"),
+            InterpErr::GeneratorRunning { .. } => write!(f, "Generator is already running"),
+            InterpErr::YieldOutsideGenerator { got } => {
+                write!(f, "Yield of {} outside of a generator", got)
+            }
+            InterpErr::ExpectedChannel { got } => write!(f, "Expected a channel but got {}", got),
+            InterpErr::ExpectedDict { got } => write!(f, "Expected a dict but got {}", got),
+            InterpErr::ExpectedThunk { got } => write!(f, "Expected a thunk but got {}", got),
+            InterpErr::AssertionFailed { msg, .. } => write!(f, "Assertion failed {}", msg),
+            InterpErr::AnnotationFailed {
+                expected,
+                got,
+                line,
+                col,
+            } => write!(
+                f,
+                "The annotation {} at line {}, column {} doesn't hold for {}",
+                expected, line, col, got
+            ),
+            InterpErr::Deadlock {} => write!(f, "Deadlock: every thread is waiting on a channel"),
+            InterpErr::MissingArgument { param } => {
+                write!(f, "Missing argument for parameter {}", param)
+            }
+            InterpErr::UnknownKeyword { name } => write!(f, "No parameter named {}", name),
+            InterpErr::DuplicateArgument { param } => {
+                write!(f, "Parameter {} got more than one argument", param)
+            }
+            InterpErr::ArrayOutOfBounds { .. } => write!(f, "Array index out of bounds"),
+            InterpErr::NegativeArraySize { size } => {
+                write!(f, "Cannot allocate an array of negative size {}", size)
+            }
+            InterpErr::OutOfMemory { size } => {
+                write!(f, "Out of memory allocating an array of size {}", size)
+            }
             InterpErr::ArityErr {
                 expected_arity,
                 num_provided,
+                ..
             } => {
                 write!(
                     f,
@@ -265,12 +797,69 @@ impl Display for InterpErr {
     }
 }
 
+impl InterpErr {
+    // The value a handler is run with when the error is raised in the body
+    // of a try: the raised value itself, or RuntimeError(code, value) with
+    // the code and the value the compiled program would report. I/O errors,
+    // running out of memory and deadlocks can't be caught.
+    fn caught<Ann>(&self, store: &mut State<'_, Ann>) -> Option<SnakeVal> {
+        let none = SnakeVal::Bool(false);
+        let (code, v) = match self {
+            InterpErr::Raised { got } => return Some(*got),
+            InterpErr::AssertionFailed {
+                code: Some(code), ..
+            } => return Some(*code),
+            InterpErr::Write { .. } | InterpErr::Deadlock {} | InterpErr::OutOfMemory { .. } => {
+                return None
+            }
+            InterpErr::ExpectedNum { who, got, .. } => match who.as_str() {
+                "arithmetic" => (ErrorCode::ArithType, *got),
+                "comparison" => (ErrorCode::CmpType, *got),
+                "array" => (ErrorCode::ArraySize, *got),
+                _ => (ErrorCode::IndexNotNumber, *got),
+            },
+            InterpErr::ExpectedBool { who, got, .. } if who == "logic" => {
+                (ErrorCode::LogicType, *got)
+            }
+            InterpErr::ExpectedBool { got, .. } => (ErrorCode::IfType, *got),
+            InterpErr::ExpectedArray { got, .. } => (ErrorCode::NonArray, *got),
+            InterpErr::ArrayOutOfBounds { index } => {
+                (ErrorCode::IndexOutOfBounds, SnakeVal::Num(*index))
+            }
+            InterpErr::ExpectedFun { got } => (ErrorCode::NonClosure, *got),
+            InterpErr::ArityErr { got, .. } => (ErrorCode::LambdaArity, *got),
+            InterpErr::NegativeArraySize { size } => {
+                (ErrorCode::NegativeSize, SnakeVal::Num(*size))
+            }
+            InterpErr::ExpectedVector { got, .. } => (ErrorCode::NonVector, *got),
+            InterpErr::PopEmptyVector { got } => (ErrorCode::EmptyVector, *got),
+            InterpErr::MatchFailure { got } => (ErrorCode::Match, *got),
+            InterpErr::MissingField { got, .. } => (ErrorCode::MissingField, *got),
+            InterpErr::ExpectedRecord { got } => (ErrorCode::NonRecord, *got),
+            InterpErr::DestructureLength { got, .. } => (ErrorCode::Destructure, *got),
+            InterpErr::ExpectedGenerator { got } => (ErrorCode::NonGenerator, *got),
+            InterpErr::GeneratorExhausted { got } => (ErrorCode::GeneratorExhausted, *got),
+            InterpErr::GeneratorRunning { got } => (ErrorCode::GeneratorRunning, *got),
+            InterpErr::YieldOutsideGenerator { got } => (ErrorCode::Yield, *got),
+            InterpErr::ExpectedChannel { got } => (ErrorCode::NonChannel, *got),
+            InterpErr::MissingArgument { .. }
+            | InterpErr::UnknownKeyword { .. }
+            | InterpErr::DuplicateArgument { .. } => (ErrorCode::Argument, none),
+            InterpErr::ExpectedDict { got } => (ErrorCode::NonDict, *got),
+            InterpErr::AnnotationFailed { got, .. } => (ErrorCode::Cast, *got),
+            InterpErr::ExpectedThunk { got } => (ErrorCode::NonThunk, *got),
+            InterpErr::AssertionFailed { .. } => (ErrorCode::Assert, none),
+        };
+        Some(store.runtime_error(code, v))
+    }
+}
+
 fn prj_bool(v: SnakeVal, who: &str, msg: &str) -> Interp<bool> {
     match v {
         SnakeVal::Bool(b) => Ok(b),
         _ => Err(InterpErr::ExpectedBool {
             who: String::from(who),
-            got: v.to_string(),
+            got: v,
             msg: String::from(msg),
         }),
     }
@@ -281,22 +870,54 @@ fn prj_num(v: SnakeVal, who: &str, msg: &str) -> Interp<i64> {
         SnakeVal::Num(n) => Ok(n),
         _ => Err(InterpErr::ExpectedNum {
             who: String::from(who),
-            got: v.to_string(),
+            got: v,
             msg: String::from(msg),
         }),
     }
 }
 
+fn prj_channel(v: SnakeVal) -> Interp<usize> {
+    match v {
+        SnakeVal::Channel(c) => Ok(c),
+        _ => Err(InterpErr::ExpectedChannel { got: v }),
+    }
+}
+
+fn prj_dict(v: SnakeVal) -> Interp<usize> {
+    match v {
+        SnakeVal::Dict(ptr) => Ok(ptr),
+        _ => Err(InterpErr::ExpectedDict { got: v }),
+    }
+}
+
+fn prj_thunk(v: SnakeVal) -> Interp<usize> {
+    match v {
+        SnakeVal::Thunk(ptr) => Ok(ptr),
+        _ => Err(InterpErr::ExpectedThunk { got: v }),
+    }
+}
+
+// The index in the array arena of the value of [key] in the dict [d], if
+// it has such a key. Dicts are searched from the start, comparing keys
+// with snake_equal: the compiled code hashes them instead
+fn dict_find<Ann>(d: usize, key: SnakeVal, store: &State<Ann>) -> Option<usize> {
+    store.heap[d]
+        .chunks(2)
+        .position(|entry| snake_equal(entry[0], key, store, &mut HashSet::new()))
+        .map(|i| 2 * i + 1)
+}
+
 fn prj_fun(v: SnakeVal) -> Interp<usize> {
     match v {
         SnakeVal::Closure(b) => Ok(b),
-        _ => Err(InterpErr::ExpectedFun { got: v.to_string() }),
+        _ => Err(InterpErr::ExpectedFun { got: v }),
     }
 }
 
+// Vectors can be read like arrays
 fn prj_array<'heap>(v: SnakeVal, msg: &str, heap: &'heap Heap) -> Interp<&'heap [SnakeVal]> {
     match v {
-        SnakeVal::Array(ptr) => {
+        SnakeVal::Array(ptr) | SnakeVal::Vector(ptr) => {
             let arr = heap
                 .get(ptr)
                 .expect("internal interp error: invalid heap pointer");
@@ -314,7 +935,7 @@ fn prj_array_mut<'heap>(
     heap: &'heap mut Heap,
 ) -> Interp<&'heap mut [SnakeVal]> {
     match v {
-        SnakeVal::Array(ptr) => {
+        SnakeVal::Array(ptr) | SnakeVal::Vector(ptr) => {
             let arr = heap
                 .get_mut(ptr)
                 .expect("internal interp error: invalid heap pointer");
@@ -327,24 +948,83 @@ fn prj_array_mut<'heap>(
     }
 }
 
+fn prj_vector<'heap>(
+    v: SnakeVal,
+    msg: &str,
+    heap: &'heap mut Heap,
+) -> Interp<&'heap mut Vec<SnakeVal>> {
+    match v {
+        SnakeVal::Vector(ptr) => {
+            let vec = heap
+                .get_mut(ptr)
+                .expect("internal interp error: invalid heap pointer");
+            Ok(vec)
+        }
+        _ => Err(InterpErr::ExpectedVector {
+            got: v,
+            msg: msg.to_string(),
+        }),
+    }
+}
+
 fn valid_index(n: i64) -> Interp<usize> {
     match TryInto::<usize>::try_into(n) {
-        Err(_) => Err(InterpErr::ArrayOutOfBounds {}),
+        Err(_) => Err(InterpErr::ArrayOutOfBounds { index: n }),
         Ok(ix) => Ok(ix),
     }
 }
 
-fn print_snake_val<W>(w: &mut W, v: SnakeVal, h: &Heap) -> Interp<SnakeVal>
+// Extends [env] with the variables of [p] if [v] matches it
+fn match_pattern<Ann>(p: &Pattern, v: SnakeVal, env: &mut Env, store: &State<Ann>) -> bool {
+    match (p, v) {
+        (Pattern::Wildcard, _) => true,
+        (Pattern::Var(x), _) => {
+            *env = env.push_local(x.to_string(), v);
+            true
+        }
+        (Pattern::Num(n), SnakeVal::Num(m)) => *n == m,
+        (Pattern::Num(n), SnakeVal::BigNum(ptr)) => store.bignums[ptr] == BigInt::from_i64(*n),
+        (Pattern::Bool(b), SnakeVal::Bool(c)) => *b == c,
+        (Pattern::Array(ps), SnakeVal::Array(ptr)) => {
+            let vs = &store.heap[ptr];
+            ps.len() == vs.len()
+                && ps
+                    .iter()
+                    .zip(vs.iter())
+                    .all(|(p, v)| match_pattern(p, *v, env, store))
+        }
+        (Pattern::Constructor(c, ps), SnakeVal::Data(id, ptr)) => {
+            // the constructor is whatever its name is bound to
+            let expected = match env.lookup(c) {
+                Some(SnakeVal::Data(id, _)) => id,
+                Some(SnakeVal::Closure(f)) => match store.funs[f] {
+                    SemFun::Constructor(id) => id,
+                    SemFun::Lambda { .. } => return false,
+                },
+                None if c == RUNTIME_ERROR_CTOR => 0,
+                _ => return false,
+            };
+            expected == id
+                && ps
+                    .iter()
+                    .zip(store.heap[ptr].iter())
+                    .all(|(p, v)| match_pattern(p, *v, env, store))
+        }
+        _ => false,
+    }
+}
+
+fn print_snake_val<W, Ann>(w: &mut W, v: SnakeVal, store: &State<Ann>) -> Interp<SnakeVal>
 where
     W: std::io::Write,
 {
     fn fixup_err(e: std::io::Error) -> InterpErr {
         InterpErr::Write { msg: e.to_string() }
     }
-    fn print_loop<W>(
+    fn print_loop<W, Ann>(
         w: &mut W,
         v: &SnakeVal,
-        h: &Heap,
+        store: &State<Ann>,
         mut parents: HashSet<usize>,
     ) -> Result<(), std::io::Error>
     where
@@ -353,74 +1033,211 @@ where
         match v {
             SnakeVal::Num(n) => write!(w, "{}", n)?,
             SnakeVal::Bool(b) => write!(w, "{}", b)?,
-            SnakeVal::Array(ptr) => {
+            SnakeVal::BigNum(ptr) => write!(w, "{}", store.bignums[*ptr])?,
+            SnakeVal::Array(ptr) | SnakeVal::Vector(ptr) => {
                 if parents.contains(ptr) {
                     write!(w, "<loop>")?
                 } else {
                     parents.insert(*ptr);
-                    let vs = &h[*ptr];
                     write!(w, "[")?;
-                    if !vs.is_empty() {
-                        print_loop(w, &vs[0], h, parents.clone())?;
-                        for v in &vs[1..] {
+                    print_elts(w, &store.heap[*ptr], store, &parents)?;
+                    write!(w, "]")?;
+                }
+            }
+            // a constructor without fields is printed as just its name
+            SnakeVal::Data(id, ptr) => {
+                write!(w, "{}", store.ctors[*id].printed_name())?;
+                let vs = &store.heap[*ptr];
+                if !vs.is_empty() {
+                    write!(w, "(")?;
+                    print_elts(w, vs, store, &parents)?;
+                    write!(w, ")")?;
+                }
+            }
+            SnakeVal::Record(shape, ptr) => {
+                if parents.contains(ptr) {
+                    write!(w, "<loop>")?
+                } else {
+                    parents.insert(*ptr);
+                    write!(w, "{{")?;
+                    let fields = store.shapes[*shape].iter();
+                    for (i, (f, v)) in fields.zip(store.heap[*ptr].iter()).enumerate() {
+                        if i > 0 {
                             write!(w, ", ")?;
-                            print_loop(w, v, h, parents.clone())?;
                         }
+                        write!(w, "{}: ", store.fields[*f])?;
+                        print_loop(w, v, store, parents.clone())?;
                     }
-                    write!(w, "]")?;
+                    write!(w, "}}")?;
                 }
             }
             SnakeVal::Closure { .. } => {
                 write!(w, "<closure>")?;
             }
+            SnakeVal::Generator { .. } => {
+                write!(w, "<generator>")?;
+            }
+            SnakeVal::Channel { .. } => {
+                write!(w, "<channel>")?;
+            }
+            // the value is only printed once it was computed
+            SnakeVal::Thunk(ptr) => match store.heap[*ptr][..] {
+                [_, SnakeVal::Bool(false)] => write!(w, "<thunk>")?,
+                _ if parents.contains(ptr) => write!(w, "<loop>")?,
+                [v, _] => {
+                    parents.insert(*ptr);
+                    write!(w, "<thunk: ")?;
+                    print_loop(w, &v, store, parents)?;
+                    write!(w, ">")?;
+                }
+                _ => unreachable!(),
+            },
+            SnakeVal::Dict(ptr) => {
+                if parents.contains(ptr) {
+                    write!(w, "<loop>")?
+                } else {
+                    parents.insert(*ptr);
+                    write!(w, "dict(")?;
+                    for (i, entry) in store.heap[*ptr].chunks(2).enumerate() {
+                        if i > 0 {
+                            write!(w, ", ")?;
+                        }
+                        print_loop(w, &entry[0], store, parents.clone())?;
+                        write!(w, ": ")?;
+                        print_loop(w, &entry[1], store, parents.clone())?;
+                    }
+                    write!(w, ")")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print_elts<W, Ann>(
+        w: &mut W,
+        vs: &[SnakeVal],
+        store: &State<Ann>,
+        parents: &HashSet<usize>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: std::io::Write,
+    {
+        for (i, v) in vs.iter().enumerate() {
+            if i > 0 {
+                write!(w, ", ")?;
+            }
+            print_loop(w, v, store, parents.clone())?;
         }
         Ok(())
     }
 
-    print_loop(w, &v, h, HashSet::new()).map_err(fixup_err)?;
+    print_loop(w, &v, store, HashSet::new()).map_err(fixup_err)?;
     writeln!(w).map_err(fixup_err)?;
     Ok(v)
 }
 
+// [v] as print shows it
+fn sprint_snake_val<Ann>(v: SnakeVal, store: &State<Ann>) -> String {
+    let mut buf = vec![];
+    let _ = print_snake_val(&mut buf, v, store);
+    String::from_utf8_lossy(&buf).trim_end().to_string()
+}
+
+// The number of words in the heap of the compiled code, which also holds
+// the length of an array
+static HEAP_WORDS: i64 = 1 << 20;
+
 static MAX_INT: i64 = 2i64.pow(62) - 1;
 static MIN_INT: i64 = -(2i64.pow(62));
 fn out_of_bounds(n: i64) -> bool {
     n > MAX_INT || n < MIN_INT
 }
 
-fn snake_arith<F>(v1: SnakeVal, v2: SnakeVal, arith: F, op: &str) -> Interp<SnakeVal>
+// Tries the 63-bit operation first and falls back to bignum arithmetic
+// when either operand is a bignum or the result overflows, mirroring
+// the fast/slow paths of the compiled code.
+fn snake_arith<F, G, Ann>(
+    v1: SnakeVal,
+    v2: SnakeVal,
+    arith: F,
+    big_arith: G,
+    op: &str,
+    store: &mut State<Ann>,
+) -> Interp<SnakeVal>
 where
     F: Fn(i64, i64) -> (i64, bool),
+    G: Fn(&BigInt, &BigInt) -> BigInt,
 {
-    let n1 = prj_num(v1, "arithmetic", op)?;
-    let n2 = prj_num(v2, "arithmetic", op)?;
-    let (n3, overflow) = arith(n1, n2);
-    if overflow || out_of_bounds(n3) {
-        Err(InterpErr::Overflow {
-            msg: format!("{} {} {} = {}", n1, op, n2, n3),
-        })
-    } else {
-        Ok(SnakeVal::Num(n3))
+    if let (SnakeVal::Num(n1), SnakeVal::Num(n2)) = (v1, v2) {
+        let (n3, overflow) = arith(n1, n2);
+        if !overflow && !out_of_bounds(n3) {
+            return Ok(SnakeVal::Num(n3));
+        }
     }
+    let n1 = store.big_of(v1, "arithmetic", op)?;
+    let n2 = store.big_of(v2, "arithmetic", op)?;
+    Ok(store.alloc_num(big_arith(&n1, &n2)))
 }
 
-fn snake_log<F>(v1: SnakeVal, v2: SnakeVal, log: F, op: &str) -> Interp<SnakeVal>
-where
-    F: Fn(bool, bool) -> bool,
-{
-    Ok(SnakeVal::Bool(log(
-        prj_bool(v1, "logic", op)?,
+// Structural equality. Pairs of arrays already being compared are
+// assumed equal, so cyclic arrays with the same shape are equal.
+fn snake_equal<Ann>(
+    v1: SnakeVal,
+    v2: SnakeVal,
+    store: &State<Ann>,
+    visited: &mut HashSet<(usize, usize)>,
+) -> bool {
+    match (v1, v2) {
+        (SnakeVal::Array(p1), SnakeVal::Array(p2))
+        | (SnakeVal::Vector(p1), SnakeVal::Vector(p2)) => {
+            if p1 == p2 || !visited.insert((p1, p2)) {
+                return true;
+            }
+            let (vs1, vs2) = (&store.heap[p1], &store.heap[p2]);
+            vs1.len() == vs2.len()
+                && vs1
+                    .iter()
+                    .zip(vs2.iter())
+                    .all(|(x, y)| snake_equal(*x, *y, store, visited))
+        }
+        (SnakeVal::Data(c1, p1), SnakeVal::Data(c2, p2))
+        | (SnakeVal::Record(c1, p1), SnakeVal::Record(c2, p2)) => {
+            if p1 == p2 || !visited.insert((p1, p2)) {
+                return true;
+            }
+            c1 == c2
+                && store.heap[p1]
+                    .iter()
+                    .zip(store.heap[p2].iter())
+                    .all(|(x, y)| snake_equal(*x, *y, store, visited))
+        }
+        _ => store.num_eq(v1, v2),
+    }
+}
+
+fn snake_log<F>(v1: SnakeVal, v2: SnakeVal, log: F, op: &str) -> Interp<SnakeVal>
+where
+    F: Fn(bool, bool) -> bool,
+{
+    Ok(SnakeVal::Bool(log(
+        prj_bool(v1, "logic", op)?,
         prj_bool(v2, "logic", op)?,
     )))
 }
 
-fn snake_cmp<F>(v1: SnakeVal, v2: SnakeVal, cmp: F, op: &str) -> Interp<SnakeVal>
+fn snake_cmp<F, Ann>(
+    v1: SnakeVal,
+    v2: SnakeVal,
+    cmp: F,
+    op: &str,
+    store: &State<Ann>,
+) -> Interp<SnakeVal>
 where
-    F: Fn(i64, i64) -> bool,
+    F: Fn(&BigInt, &BigInt) -> bool,
 {
     Ok(SnakeVal::Bool(cmp(
-        prj_num(v1, "comparison", op)?,
-        prj_num(v2, "comparison", op)?,
+        &store.big_of(v1, "comparison", op)?,
+        &store.big_of(v2, "comparison", op)?,
     )))
 }
 
@@ -442,7 +1259,51 @@ where
         | Prim::IsNum
         | Prim::Length
         | Prim::IsArray
-        | Prim::IsFun => interpret_prim1(op, vs[0], w, &store.heap),
+        | Prim::IsFun
+        | Prim::Copy
+        | Prim::Pop
+        | Prim::DictKeys => interpret_prim1(op, vs[0], w, store),
+        Prim::Raise => Err(InterpErr::Raised { got: vs[0] }),
+        Prim::Generator => store.alloc_generator(vs[0]),
+        Prim::Spawn => store.spawn(vs[0]),
+        Prim::AssertFailed(comparison, line, col) => {
+            let (operands, code) = vs.split_at(if comparison.is_some() { 2 } else { 0 });
+            let mut msg = String::new();
+            if let Some(code) = code.first() {
+                msg += &format!("with code {} ", sprint_snake_val(*code, store));
+            }
+            msg += &format!("at line {}, column {}", line, col);
+            if let Some(comparison) = comparison {
+                let l = sprint_snake_val(operands[0], store);
+                let r = sprint_snake_val(operands[1], store);
+                msg += &match comparison {
+                    Comparison::Equal => format!(": equal({}, {})", l, r),
+                    _ => format!(": {} {} {}", l, comparison, r),
+                };
+            }
+            Err(InterpErr::AssertionFailed {
+                code: code.first().copied(),
+                msg,
+            })
+        }
+        Prim::Delay => store.alloc_thunk(vs[0]),
+        Prim::Forced => Ok(store.heap[prj_thunk(vs[0])?][1]),
+        Prim::ThunkValue => Ok(store.heap[prj_thunk(vs[0])?][0]),
+        Prim::SetThunk => {
+            let thunk = &mut store.heap[prj_thunk(vs[0])?];
+            if thunk[1] == SnakeVal::Bool(false) {
+                *thunk = vec![vs[1], SnakeVal::Bool(true)];
+            }
+            Ok(thunk[0])
+        }
+        Prim::Send => store.send(vs[0], vs[1]),
+        Prim::NewChannel => {
+            store.channels.push(Channel {
+                values: VecDeque::new(),
+                receivers: VecDeque::new(),
+            });
+            Ok(SnakeVal::Channel(store.channels.len() - 1))
+        }
         Prim::Add
         | Prim::Sub
         | Prim::Mul
@@ -454,53 +1315,146 @@ where
         | Prim::Ge
         | Prim::Eq
         | Prim::Neq
-        | Prim::ArrayGet => interpret_prim2(op, vs[0], vs[1], &store.heap),
+        | Prim::Equal
+        | Prim::ArrayGet
+        | Prim::NewArray
+        | Prim::Concat
+        | Prim::Push
+        | Prim::DictHas => interpret_prim2(op, vs[0], vs[1], store),
         Prim::ArraySet => {
             let array = vs[0];
             let index = vs[1];
             let new_val = vs[2];
             let arr = prj_array_mut(array, "array set", &mut store.heap)?;
             let ix = prj_num(index, "array set", "")?;
-            match TryInto::<usize>::try_into(ix) {
-                Err(_) => Err(InterpErr::ArrayOutOfBounds {}),
-                Ok(ptr) => match arr.get_mut(ptr) {
-                    None => Err(InterpErr::ArrayOutOfBounds {}),
-                    Some(loc) => {
-                        *loc = new_val;
-                        Ok(array)
-                    }
-                },
+            match arr.get_mut(valid_index(ix)?) {
+                None => Err(InterpErr::ArrayOutOfBounds { index: ix }),
+                Some(loc) => {
+                    *loc = new_val;
+                    Ok(array)
+                }
             }
         }
         Prim::MakeArray => {
             let ptr = store.alloc_array(vs);
             Ok(SnakeVal::Array(ptr))
         }
-        Prim::GetCode | Prim::GetEnv | Prim::CheckArityAndUntag(..) => {
+        Prim::NewVector => Ok(SnakeVal::Vector(store.alloc_array(vec![]))),
+        Prim::NewDict => Ok(SnakeVal::Dict(store.alloc_array(vec![]))),
+        Prim::DictGet => {
+            let d = prj_dict(vs[0])?;
+            Ok(match dict_find(d, vs[1], store) {
+                Some(i) => store.heap[d][i],
+                None => vs[2],
+            })
+        }
+        Prim::DictPut => {
+            let d = prj_dict(vs[0])?;
+            match dict_find(d, vs[1], store) {
+                Some(i) => store.heap[d][i] = vs[2],
+                None => store.heap[d].extend_from_slice(&[vs[1], vs[2]]),
+            }
+            Ok(vs[0])
+        }
+        Prim::CheckLength(len, line, col) => match vs[0] {
+            SnakeVal::Array(ptr) if store.heap[ptr].len() == len => Ok(vs[0]),
+            got => Err(InterpErr::DestructureLength {
+                expected: len,
+                got,
+                line,
+                col,
+            }),
+        },
+        Prim::Cast(expected, line, col) => {
+            let holds = match expected {
+                TypeAnn::Num => matches!(vs[0], SnakeVal::Num(_) | SnakeVal::BigNum(_)),
+                TypeAnn::Bool => matches!(vs[0], SnakeVal::Bool(_)),
+                TypeAnn::Array => matches!(vs[0], SnakeVal::Array(_)),
+            };
+            if holds {
+                Ok(vs[0])
+            } else {
+                Err(InterpErr::AnnotationFailed {
+                    expected,
+                    got: vs[0],
+                    line,
+                    col,
+                })
+            }
+        }
+        Prim::MakeRecord(shape) => Ok(SnakeVal::Record(shape, store.alloc_array(vs))),
+        Prim::GetField(field) => {
+            let (ptr, i) = store.field_index(vs[0], field)?;
+            Ok(store.heap[ptr][i])
+        }
+        Prim::SetField(field) => {
+            let (ptr, i) = store.field_index(vs[0], field)?;
+            store.heap[ptr][i] = vs[1];
+            Ok(vs[1])
+        }
+        Prim::Slice => {
+            let arr = prj_array(vs[0], "slice", &store.heap)?;
+            // as in the compiled code, the end is checked to be a number
+            // first, and is only reported past the array for a valid start
+            let end = prj_num(vs[2], "slice", "end")?;
+            let start = prj_num(vs[1], "slice", "start")?;
+            if start < 0 || start > end {
+                return Err(InterpErr::ArrayOutOfBounds { index: start });
+            }
+            if end > arr.len() as i64 {
+                return Err(InterpErr::ArrayOutOfBounds { index: end });
+            }
+            let elts = arr[start as usize..end as usize].to_vec();
+            Ok(SnakeVal::Array(store.alloc_array(elts)))
+        }
+        Prim::GetCode(..)
+        | Prim::GetEnv
+        | Prim::UntagClosure
+        | Prim::MakeKeywords(..)
+        | Prim::CheckIterable
+        | Prim::Advance
+        | Prim::LastYielded
+        | Prim::MatchFail
+        | Prim::UncheckedArrayGet
+        | Prim::IsData(..)
+        | Prim::DataGet
+        | Prim::MakeData(..)
+        | Prim::GetFieldAt(..)
+        | Prim::SetFieldAt(..)
+        | Prim::Force
+        | Prim::Assert => {
             unreachable!()
         }
+        // they switch between generators, see the machine
+        Prim::Yield | Prim::Resume | Prim::Recv => unreachable!(),
     }
 }
 
-fn interpret_prim1<W>(p: Prim, v: SnakeVal, w: &mut W, h: &Heap) -> Interp<SnakeVal>
+fn interpret_prim1<W, Ann>(
+    p: Prim,
+    v: SnakeVal,
+    w: &mut W,
+    store: &mut State<Ann>,
+) -> Interp<SnakeVal>
 where
     W: std::io::Write,
 {
+    let one = SnakeVal::Num(1);
     match p {
-        Prim::Add1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_add(n2), "add1"),
-        Prim::Sub1 => snake_arith(v, SnakeVal::Num(1), |n1, n2| n1.overflowing_sub(n2), "sub1"),
+        Prim::Add1 => snake_arith(v, one, i64::overflowing_add, BigInt::add, "add1", store),
+        Prim::Sub1 => snake_arith(v, one, i64::overflowing_sub, BigInt::sub, "sub1", store),
         Prim::Not => Ok(SnakeVal::Bool(!prj_bool(v, "logic", "!")?)),
-        Prim::Print => print_snake_val(w, v, h),
+        Prim::Print => print_snake_val(w, v, store),
         Prim::IsBool => match v {
             SnakeVal::Bool(_) => Ok(SnakeVal::Bool(true)),
             _ => Ok(SnakeVal::Bool(false)),
         },
         Prim::Length => {
-            let v = prj_array(v, "length", h)?;
+            let v = prj_array(v, "length", &store.heap)?;
             Ok(SnakeVal::Num(v.len().try_into().unwrap()))
         }
         Prim::IsNum => match v {
-            SnakeVal::Num(_) => Ok(SnakeVal::Bool(true)),
+            SnakeVal::Num(_) | SnakeVal::BigNum(_) => Ok(SnakeVal::Bool(true)),
             _ => Ok(SnakeVal::Bool(false)),
         },
         Prim::IsArray => match v {
@@ -511,36 +1465,81 @@ where
             SnakeVal::Closure(_) => Ok(SnakeVal::Bool(true)),
             _ => Ok(SnakeVal::Bool(false)),
         },
+        Prim::Copy => {
+            let elts = prj_array(v, "copy", &store.heap)?.to_vec();
+            Ok(SnakeVal::Array(store.alloc_array(elts)))
+        }
+        Prim::Pop => prj_vector(v, "pop", &mut store.heap)?
+            .pop()
+            .ok_or(InterpErr::PopEmptyVector { got: v }),
+        Prim::DictKeys => {
+            let d = prj_dict(v)?;
+            let keys = store.heap[d].iter().step_by(2).copied().collect();
+            Ok(SnakeVal::Array(store.alloc_array(keys)))
+        }
         _ => unreachable!(),
     }
 }
 
-fn interpret_prim2(p: Prim, v1: SnakeVal, v2: SnakeVal, heap: &Heap) -> Interp<SnakeVal>
-where
-{
+fn interpret_prim2<Ann>(
+    p: Prim,
+    v1: SnakeVal,
+    v2: SnakeVal,
+    store: &mut State<Ann>,
+) -> Interp<SnakeVal> {
     match p {
-        Prim::Add => snake_arith(v1, v2, |n1, n2| n1.overflowing_add(n2), "+"),
-        Prim::Sub => snake_arith(v1, v2, |n1, n2| n1.overflowing_sub(n2), "-"),
-        Prim::Mul => snake_arith(v1, v2, |n1, n2| n1.overflowing_mul(n2), "*"),
+        Prim::Add => snake_arith(v1, v2, i64::overflowing_add, BigInt::add, "+", store),
+        Prim::Sub => snake_arith(v1, v2, i64::overflowing_sub, BigInt::sub, "-", store),
+        Prim::Mul => snake_arith(v1, v2, i64::overflowing_mul, BigInt::mul, "*", store),
 
         Prim::And => snake_log(v1, v2, |b1, b2| b1 && b2, "&&"),
         Prim::Or => snake_log(v1, v2, |b1, b2| b1 || b2, "||"),
 
-        Prim::Lt => snake_cmp(v1, v2, |n1, n2| n1 < n2, "<"),
-        Prim::Le => snake_cmp(v1, v2, |n1, n2| n1 <= n2, "<="),
-        Prim::Gt => snake_cmp(v1, v2, |n1, n2| n1 > n2, ">"),
-        Prim::Ge => snake_cmp(v1, v2, |n1, n2| n1 >= n2, ">="),
+        Prim::Lt => snake_cmp(v1, v2, |n1, n2| n1 < n2, "<", store),
+        Prim::Le => snake_cmp(v1, v2, |n1, n2| n1 <= n2, "<=", store),
+        Prim::Gt => snake_cmp(v1, v2, |n1, n2| n1 > n2, ">", store),
+        Prim::Ge => snake_cmp(v1, v2, |n1, n2| n1 >= n2, ">=", store),
 
-        Prim::Eq => Ok(SnakeVal::Bool(v1 == v2)),
-        Prim::Neq => Ok(SnakeVal::Bool(v1 != v2)),
+        Prim::Eq => Ok(SnakeVal::Bool(store.num_eq(v1, v2))),
+        Prim::Neq => Ok(SnakeVal::Bool(!store.num_eq(v1, v2))),
+        Prim::Equal => Ok(SnakeVal::Bool(snake_equal(
+            v1,
+            v2,
+            store,
+            &mut HashSet::new(),
+        ))),
         Prim::ArrayGet => {
-            let vs = prj_array(v1, "array index", heap)?;
+            let vs = prj_array(v1, "array index", &store.heap)?;
             let n = valid_index(prj_num(v2, "index", "")?)?;
             match vs.get(n) {
-                None => Err(InterpErr::ArrayOutOfBounds {}),
+                None => Err(InterpErr::ArrayOutOfBounds { index: n as i64 }),
                 Some(v) => Ok(*v),
             }
         }
+        Prim::NewArray => {
+            let size = prj_num(v1, "array", "array size")?;
+            if size < 0 {
+                return Err(InterpErr::NegativeArraySize { size });
+            }
+            if size >= HEAP_WORDS - store.used {
+                return Err(InterpErr::OutOfMemory { size });
+            }
+            let ptr = store.alloc_array(vec![v2; size as usize]);
+            Ok(SnakeVal::Array(ptr))
+        }
+        Prim::Concat => {
+            let mut elts = prj_array(v1, "concat", &store.heap)?.to_vec();
+            elts.extend_from_slice(prj_array(v2, "concat", &store.heap)?);
+            Ok(SnakeVal::Array(store.alloc_array(elts)))
+        }
+        Prim::Push => {
+            prj_vector(v1, "push", &mut store.heap)?.push(v2);
+            Ok(v1)
+        }
+        Prim::DictHas => {
+            let d = prj_dict(v1)?;
+            Ok(SnakeVal::Bool(dict_find(d, v2, store).is_some()))
+        }
         _ => unreachable!(),
     }
 }
@@ -557,47 +1556,384 @@ where
     W: std::io::Write,
     Ann: Clone,
 {
+    let mut machine = Machine::Descending {
+        e,
+        stk: Stack::Done,
+        env: Env::new(),
+    };
+    loop {
+        let err = match run(machine, buf, store) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let v = match err.caught(store) {
+            Some(v) => v,
+            None => return Err(err),
+        };
+        // an error the running generator doesn't catch finishes it and is
+        // raised again in the code that resumed it
+        let mut err = err;
+        while store.handlers.is_empty() {
+            match store.resumers.pop() {
+                Some(resumer) => {
+                    store.generators[resumer.generator] = Generator::Finished;
+                    store.handlers = resumer.handlers;
+                    err = InterpErr::Raised { got: v };
+                }
+                None => return Err(err),
+            }
+        }
+        // an error in the body of a try drops the rest of the body's stack
+        // and runs the innermost handler instead
+        let handler = store.handlers.pop().unwrap();
+        machine = Machine::Descending {
+            e: handler.handler,
+            env: handler.env.push_local(handler.var.to_string(), v),
+            stk: handler.stk,
+        }
+    }
+}
+
+// Runs [machine] until it is done or an error is raised
+fn run<'exp, Ann, W>(
+    mut machine: Machine<'exp, Ann>,
+    buf: &mut W,
+    store: &mut State<'exp, Ann>,
+) -> Interp<()>
+where
+    W: std::io::Write,
+    Ann: Clone,
+{
+    // Matches [args] to the parameters [names] of [f]. The last
+    // keywords.len() arguments are passed by name, the parameters left out
+    // take their defaults and a variadic function packs the extra arguments.
+    fn bind_arguments<'exp, Ann>(
+        f: SnakeVal,
+        names: &[String],
+        variadic: bool,
+        defaults: &[SnakeVal],
+        mut args: Vec<SnakeVal>,
+        keywords: &[&str],
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Vec<SnakeVal>> {
+        let fixed = names.len() - variadic as usize;
+        let keyword_args = args.split_off(args.len() - keywords.len());
+        if args.len() > fixed && !variadic {
+            return Err(InterpErr::ArityErr {
+                expected_arity: fixed,
+                num_provided: args.len() + keywords.len(),
+                // the compiled code binds keywords in the runtime, which
+                // doesn't see the closure
+                got: if keywords.is_empty() { f } else { SnakeVal::Bool(false) },
+            });
+        }
+        let rest = args.split_off(args.len().min(fixed));
+        let mut bound: Vec<Option<SnakeVal>> = args.into_iter().map(Some).collect();
+        bound.resize(fixed, None);
+        for (name, v) in keywords.iter().zip(keyword_args) {
+            match names[..fixed].iter().position(|p| p == name) {
+                None => {
+                    return Err(InterpErr::UnknownKeyword {
+                        name: name.to_string(),
+                    })
+                }
+                Some(i) if bound[i].is_some() => {
+                    return Err(InterpErr::DuplicateArgument {
+                        param: name.to_string(),
+                    })
+                }
+                Some(i) => bound[i] = Some(v),
+            }
+        }
+        let required = fixed - defaults.len();
+        let mut values = vec![];
+        for (i, v) in bound.into_iter().enumerate() {
+            match v {
+                Some(v) => values.push(v),
+                None if i >= required => values.push(defaults[i - required]),
+                None => {
+                    return Err(InterpErr::MissingArgument {
+                        param: names[i].clone(),
+                    })
+                }
+            }
+        }
+        if variadic {
+            values.push(SnakeVal::Array(store.alloc_array(rest)));
+        }
+        Ok(values)
+    }
+
     fn call<'exp, Ann>(
         fun_ptr: usize,
         args: Vec<SnakeVal>,
+        keywords: Vec<&str>,
         stk: Stack<'exp, Ann>,
-        store: &State<'exp, Ann>,
+        store: &mut State<'exp, Ann>,
     ) -> Interp<Machine<'exp, Ann>>
     where
         Ann: Clone,
     {
-        let fun = &store.funs[fun_ptr];
-        let mut env = fun.closure.env.clone();
-
-        if args.len() != fun.parameters.len() {
-            return Err(InterpErr::ArityErr {
-                expected_arity: fun.parameters.len(),
-                num_provided: args.len(),
-            });
-        }
+        let (parameters, variadic, defaults, closure) = match &store.funs[fun_ptr] {
+            SemFun::Lambda {
+                parameters,
+                variadic,
+                defaults,
+                closure,
+            } => (*parameters, *variadic, defaults.clone(), closure.clone()),
+            SemFun::Constructor(id) => {
+                let id = *id;
+                let ctor = store.ctors[id];
+                let arity = ctor.fields.len();
+                if keywords.is_empty() && args.len() != arity {
+                    return Err(InterpErr::ArityErr {
+                        expected_arity: arity,
+                        num_provided: args.len(),
+                        got: SnakeVal::Closure(fun_ptr),
+                    });
+                }
+                let fields = bind_arguments(
+                    SnakeVal::Closure(fun_ptr),
+                    &ctor.fields,
+                    false,
+                    &[],
+                    args,
+                    &keywords,
+                    store,
+                )?;
+                return Ok(Machine::Returning {
+                    v: SnakeVal::Data(id, store.alloc_array(fields)),
+                    stk,
+                });
+            }
+        };
+        let args = bind_arguments(
+            SnakeVal::Closure(fun_ptr),
+            parameters,
+            variadic,
+            &defaults,
+            args,
+            &keywords,
+            store,
+        )?;
         // environment for the body should consist of the captured env
         // extended with the new parameters
-        for (v, x) in args.iter().zip(fun.parameters.iter()) {
+        let mut env = closure.env;
+        for (v, x) in args.iter().zip(parameters.iter()) {
             env = env.push_local(x.to_string(), *v)
         }
         Ok(Machine::Descending {
-            e: fun.closure.exp,
+            e: closure.exp,
             env,
             stk,
         })
     }
 
-    let mut machine = Machine::Descending {
-        e,
-        stk: Stack::Done,
-        env: Env::new(),
-    };
+    // Runs the body of the loop in [frame], a For, once more if its
+    // index is below the stop, otherwise the loop evaluates to false
+    fn for_step<'exp, Ann>(
+        frame: Stack<'exp, Ann>,
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Machine<'exp, Ann>> {
+        let (var, index, stop, array, body, env, stk) = match frame {
+            Stack::For {
+                var,
+                index,
+                stop,
+                array,
+                body,
+                env,
+                stk,
+            } => (var, index, stop, array, body, env, stk),
+            _ => unreachable!(),
+        };
+        if interpret_prim2(Prim::Lt, index, stop, store)? == SnakeVal::Bool(false) {
+            return Ok(Machine::Returning {
+                v: SnakeVal::Bool(false),
+                stk: *stk,
+            });
+        }
+        let elt = match array {
+            None => index,
+            Some(ptr) => store.heap[ptr][prj_num(index, "for", "index")? as usize],
+        };
+        Ok(Machine::Descending {
+            e: body,
+            env: env.push_local(var.to_string(), elt),
+            stk: Stack::For {
+                var,
+                index,
+                stop,
+                array,
+                body,
+                env,
+                stk,
+            },
+        })
+    }
+
+    // Runs [generator] until it yields, then returns the value to [stk].
+    // If it has finished, continues with [stk] right away
+    fn resume<'exp, Ann>(
+        generator: usize,
+        stk: Stack<'exp, Ann>,
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Machine<'exp, Ann>>
+    where
+        Ann: Clone,
+    {
+        match std::mem::replace(&mut store.generators[generator], Generator::Running) {
+            Generator::Fresh(f) => {
+                let handlers = std::mem::take(&mut store.handlers);
+                store.resumers.push(Resumer {
+                    generator,
+                    stk,
+                    handlers,
+                });
+                call(f, vec![], vec![], Stack::GeneratorDone, store)
+            }
+            Generator::Suspended {
+                v,
+                stk: rest,
+                handlers,
+            } => {
+                let handlers = std::mem::replace(&mut store.handlers, handlers);
+                store.resumers.push(Resumer {
+                    generator,
+                    stk,
+                    handlers,
+                });
+                Ok(Machine::Returning { v, stk: rest })
+            }
+            Generator::Running => Err(InterpErr::GeneratorRunning {
+                got: SnakeVal::Generator(generator),
+            }),
+            Generator::Finished => {
+                store.generators[generator] = Generator::Finished;
+                finished(generator, stk)
+            }
+        }
+    }
+
+    // Continues with [stk] once the [generator] it resumed has finished: a
+    // for over the generator ends, a resume fails
+    fn finished<'exp, Ann>(generator: usize, stk: Stack<'exp, Ann>) -> Interp<Machine<'exp, Ann>> {
+        match stk {
+            Stack::ForGenerator { stk, .. } => Ok(Machine::Returning {
+                v: SnakeVal::Bool(false),
+                stk: *stk,
+            }),
+            _ => Err(InterpErr::GeneratorExhausted {
+                got: SnakeVal::Generator(generator),
+            }),
+        }
+    }
+
+    // Suspends the running generator at a yield of [v], continuing with
+    // [stk] once it is resumed
+    fn suspend<'exp, Ann>(
+        v: SnakeVal,
+        stk: Stack<'exp, Ann>,
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Machine<'exp, Ann>> {
+        let resumer = match store.resumers.pop() {
+            Some(resumer) => resumer,
+            None => return Err(InterpErr::YieldOutsideGenerator { got: v }),
+        };
+        let handlers = std::mem::replace(&mut store.handlers, resumer.handlers);
+        store.generators[resumer.generator] = Generator::Suspended { v, stk, handlers };
+        Ok(Machine::Returning {
+            v,
+            stk: resumer.stk,
+        })
+    }
+
+    // Runs the next ready thread, failing if there is none as every thread
+    // is then waiting on a channel
+    fn run_next_thread<'exp, Ann>(store: &mut State<'exp, Ann>) -> Interp<Machine<'exp, Ann>>
+    where
+        Ann: Clone,
+    {
+        let next = store.ready.pop_front().ok_or(InterpErr::Deadlock {})?;
+        store.current_thread = next;
+        match std::mem::replace(&mut store.threads[next], Thread::Running) {
+            Thread::Fresh(f) => call(f, vec![], vec![], Stack::ThreadDone, store),
+            Thread::Ready {
+                v,
+                stk,
+                handlers,
+                resumers,
+            } => {
+                store.handlers = handlers;
+                store.resumers = resumers;
+                Ok(Machine::Returning { v, stk })
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Receives the first value sent on [c] and returns it to [stk]. If
+    // there is none, the current thread waits on [c] and the next ready
+    // thread runs instead
+    fn recv<'exp, Ann>(
+        c: SnakeVal,
+        stk: Stack<'exp, Ann>,
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Machine<'exp, Ann>>
+    where
+        Ann: Clone,
+    {
+        let channel = &mut store.channels[prj_channel(c)?];
+        if let Some(v) = channel.values.pop_front() {
+            return Ok(Machine::Returning { v, stk });
+        }
+        channel.receivers.push_back(store.current_thread);
+        store.threads[store.current_thread] = Thread::Waiting {
+            stk,
+            handlers: std::mem::take(&mut store.handlers),
+            resumers: std::mem::take(&mut store.resumers),
+        };
+        run_next_thread(store)
+    }
+
+    // Evaluates the arguments of [op] from left to right, then [op]
+    fn start_prim<'exp, Ann, W>(
+        op: Prim,
+        mut es: Vec<&'exp Exp<Ann>>,
+        env: Env,
+        stk: Stack<'exp, Ann>,
+        buf: &mut W,
+        store: &mut State<'exp, Ann>,
+    ) -> Interp<Machine<'exp, Ann>>
+    where
+        W: std::io::Write,
+    {
+        es.reverse();
+        Ok(match es.pop() {
+            None => Machine::Returning {
+                v: interpret_prim(op, vec![], buf, store)?,
+                stk,
+            },
+            Some(e) => Machine::Descending {
+                e,
+                stk: Stack::Prim {
+                    op,
+                    evaled_parts: Vec::new(),
+                    env: env.clone(),
+                    remaining_parts: es,
+                    stk: Box::new(stk),
+                },
+                env,
+            },
+        })
+    }
+
     loop {
         match machine {
             Machine::Descending { e, stk, env } => match e {
                 Exp::Num(n, _) => {
                     machine = Machine::Returning {
-                        v: SnakeVal::Num(*n),
+                        v: store.alloc_num(BigInt::from_i64(*n)),
                         stk,
                     }
                 }
@@ -609,33 +1945,31 @@ where
                 }
                 Exp::Var(x, _) => {
                     let v = env.lookup(x).expect("Unbound variable in interpreter! You should catch this in the check function!");
-                    machine = Machine::Returning { v: *v, stk }
+                    machine = Machine::Returning { v, stk }
                 }
                 Exp::Prim(op, es, _) => {
-                    let mut r_es: Vec<&Exp<_>> = es.iter().map(|e| &**e).rev().collect();
-                    machine = match r_es.pop() {
-                        None => match op {
-                            Prim::MakeArray => {
-                                let ptr = store.alloc_array(vec![]);
-                                Machine::Returning {
-                                    v: SnakeVal::Array(ptr),
-                                    stk,
-                                }
-                            }
-                            _ => unreachable!(),
-                        },
-                        Some(e) => Machine::Descending {
-                            e,
-                            stk: Stack::Prim {
-                                op: *op,
-                                evaled_parts: Vec::new(),
-                                env: env.clone(),
-                                remaining_parts: r_es,
-                                stk: Box::new(stk),
-                            },
-                            env,
-                        },
-                    }
+                    let es = es.iter().map(|e| &**e).collect();
+                    machine = start_prim(*op, es, env, stk, buf, store)?
+                }
+                // records are evaluated like the primitives they are
+                // compiled to
+                Exp::Record { fields, .. } => {
+                    let op = Prim::MakeRecord(store.shape_id(fields));
+                    let es = fields.iter().map(|(_, e)| e).collect();
+                    machine = start_prim(op, es, env, stk, buf, store)?
+                }
+                Exp::GetField { record, field, .. } => {
+                    let op = Prim::GetField(store.field_id(field));
+                    machine = start_prim(op, vec![record], env, stk, buf, store)?
+                }
+                Exp::SetField {
+                    record,
+                    field,
+                    value,
+                    ..
+                } => {
+                    let op = Prim::SetField(store.field_id(field));
+                    machine = start_prim(op, vec![record, value], env, stk, buf, store)?
                 }
                 Exp::Let { bindings, body, .. } => {
                     let mut rbindings: Vec<&(String, Exp<Ann>)> = bindings.iter().rev().collect();
@@ -680,12 +2014,115 @@ where
                         },
                     }
                 }
+                Exp::While { cond, body, .. } => {
+                    machine = Machine::Descending {
+                        e: cond,
+                        env: env.clone(),
+                        stk: Stack::While {
+                            lp: e,
+                            body,
+                            env,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
+                Exp::Try {
+                    body, var, handler, ..
+                } => {
+                    store.handlers.push(Handler {
+                        var,
+                        handler,
+                        env: env.clone(),
+                        stk,
+                    });
+                    machine = Machine::Descending {
+                        e: body,
+                        env,
+                        stk: Stack::Catch,
+                    }
+                }
+                Exp::ForRange {
+                    var,
+                    start,
+                    end,
+                    body,
+                    ..
+                } => {
+                    machine = Machine::Descending {
+                        e: start,
+                        env: env.clone(),
+                        stk: Stack::ForStart {
+                            var,
+                            end,
+                            body,
+                            env,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
+                Exp::ForEach {
+                    var, array, body, ..
+                } => {
+                    machine = Machine::Descending {
+                        e: array,
+                        env: env.clone(),
+                        stk: Stack::ForArray {
+                            var,
+                            body,
+                            env,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
+                Exp::Match {
+                    scrutinee, arms, ..
+                } => {
+                    machine = Machine::Descending {
+                        e: scrutinee,
+                        env: env.clone(),
+                        stk: Stack::Match {
+                            arms,
+                            env,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
+                Exp::Assign { var, value, .. } => {
+                    machine = Machine::Descending {
+                        e: value,
+                        env: env.clone(),
+                        stk: Stack::Assign {
+                            var,
+                            env,
+                            stk: Box::new(stk),
+                        },
+                    }
+                }
 
                 Exp::Call(fun, args, _) => {
                     machine = Machine::Descending {
                         e: fun,
                         stk: Stack::CallFun {
                             args: args.iter().collect(),
+                            keywords: vec![],
+                            env: env.clone(),
+                            stk: Box::new(stk),
+                        },
+                        env,
+                    }
+                }
+                // the keyword arguments are evaluated after the others
+                Exp::KeywordCall {
+                    fun,
+                    args,
+                    keywords,
+                    ..
+                } => {
+                    machine = Machine::Descending {
+                        e: fun,
+                        stk: Stack::CallFun {
+                            args: args.iter().chain(keywords.iter().map(|(_, e)| e)).collect(),
+                            keywords: keywords.iter().map(|(name, _)| name.as_str()).collect(),
                             env: env.clone(),
                             stk: Box::new(stk),
                         },
@@ -700,15 +2137,26 @@ where
                         stk,
                     }
                 }
+                Exp::DataDefs { ctors, body, .. } => {
+                    let env = store.alloc_ctors(ctors, env);
+                    machine = Machine::Descending { e: body, env, stk }
+                }
                 Exp::Lambda {
-                    parameters, body, ..
+                    parameters,
+                    variadic,
+                    defaults,
+                    body,
+                    ..
                 } => {
-                    let fun_ptr = store.alloc_fun(parameters, body, env);
+                    let fun_ptr = store.alloc_fun(parameters, *variadic, defaults, body, env);
                     machine = Machine::Returning {
                         v: SnakeVal::Closure(fun_ptr),
                         stk,
                     }
                 }
+                Exp::LetPattern { .. } => {
+                    panic!("Shouldn't happen: destructuring lets are rewritten by desugar")
+                }
                 Exp::MakeClosure { .. }
                 | Exp::ClosureCall(..)
                 | Exp::DirectCall(..)
@@ -719,9 +2167,61 @@ where
             },
             Machine::Returning { v, stk } => match stk {
                 Stack::Done => {
-                    print_snake_val(buf, v, &store.heap)?;
+                    print_snake_val(buf, v, store)?;
                     return Ok(());
                 }
+                Stack::ThreadDone => {
+                    store.threads[store.current_thread] = Thread::Finished;
+                    machine = run_next_thread(store)?
+                }
+                Stack::GeneratorDone => {
+                    let resumer = store.resumers.pop().unwrap();
+                    store.generators[resumer.generator] = Generator::Finished;
+                    store.handlers = resumer.handlers;
+                    machine = finished(resumer.generator, resumer.stk)?
+                }
+                Stack::ForGenerator {
+                    var,
+                    generator,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    machine = Machine::Descending {
+                        e: body,
+                        env: env.push_local(var.to_string(), v),
+                        stk: Stack::ForResume {
+                            var,
+                            generator,
+                            body,
+                            env,
+                            stk,
+                        },
+                    }
+                }
+                Stack::ForResume {
+                    var,
+                    generator,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    let frame = Stack::ForGenerator {
+                        var,
+                        generator,
+                        body,
+                        env,
+                        stk,
+                    };
+                    machine = resume(generator, frame, store)?
+                }
+                Stack::Catch => {
+                    let handler = store.handlers.pop().unwrap();
+                    machine = Machine::Returning {
+                        v,
+                        stk: handler.stk,
+                    }
+                }
                 Stack::Prim {
                     op,
                     mut evaled_parts,
@@ -730,12 +2230,18 @@ where
                     stk,
                 } => {
                     evaled_parts.push(v);
-                    machine = match remaining_parts.pop() {
-                        None => {
+                    machine = match (remaining_parts.pop(), op) {
+                        (None, Prim::Resume) => match evaled_parts[0] {
+                            SnakeVal::Generator(generator) => resume(generator, *stk, store)?,
+                            got => return Err(InterpErr::ExpectedGenerator { got }),
+                        },
+                        (None, Prim::Yield) => suspend(evaled_parts[0], *stk, store)?,
+                        (None, Prim::Recv) => recv(evaled_parts[0], *stk, store)?,
+                        (None, _) => {
                             let v = interpret_prim(op, evaled_parts, buf, store)?;
                             Machine::Returning { v, stk: *stk }
                         }
-                        Some(e) => Machine::Descending {
+                        (Some(e), _) => Machine::Descending {
                             e,
                             env: env.clone(),
                             stk: Stack::Prim {
@@ -785,12 +2291,13 @@ where
                     mut evaled_args,
                     env,
                     mut remaining_args,
+                    keywords,
                     stk,
                 } => {
                     evaled_args.push(v);
                     match remaining_args.pop() {
                         None => {
-                            machine = call(prj_fun(fun_v)?, evaled_args, *stk, store)?;
+                            machine = call(prj_fun(fun_v)?, evaled_args, keywords, *stk, store)?;
                         }
                         Some(e) => {
                             machine = Machine::Descending {
@@ -801,18 +2308,24 @@ where
                                     evaled_args,
                                     env,
                                     remaining_args,
+                                    keywords,
                                     stk,
                                 },
                             }
                         }
                     }
                 }
-                Stack::CallFun { env, args, stk } => {
+                Stack::CallFun {
+                    env,
+                    args,
+                    keywords,
+                    stk,
+                } => {
                     let mut remaining_args = args;
                     remaining_args.reverse();
                     match remaining_args.pop() {
                         None => {
-                            machine = call(prj_fun(v)?, Vec::new(), *stk, store)?;
+                            machine = call(prj_fun(v)?, Vec::new(), keywords, *stk, store)?;
                         }
                         Some(e) => {
                             machine = Machine::Descending {
@@ -823,6 +2336,7 @@ where
                                     evaled_args: Vec::new(),
                                     env,
                                     remaining_args,
+                                    keywords,
                                     stk,
                                 },
                             }
@@ -836,6 +2350,139 @@ where
                         stk: *stk,
                     }
                 }
+                Stack::While { lp, body, env, stk } => {
+                    machine = if prj_bool(v, "while", "while")? {
+                        // run the body, then the whole loop again
+                        Machine::Descending {
+                            e: body,
+                            env: env.clone(),
+                            stk: Stack::Semicolon {
+                                next: Closure { exp: lp, env },
+                                stk,
+                            },
+                        }
+                    } else {
+                        Machine::Returning { v, stk: *stk }
+                    }
+                }
+                Stack::Assign { var, env, stk } => {
+                    env.assign(var, v).expect("Unbound variable in interpreter! You should catch this in the check function!");
+                    machine = Machine::Returning { v, stk: *stk }
+                }
+                Stack::Match { arms, env, stk } => {
+                    let mut arms = arms.iter();
+                    machine = loop {
+                        match arms.next() {
+                            None => return Err(InterpErr::MatchFailure { got: v }),
+                            Some((pattern, body)) => {
+                                let mut env = env.clone();
+                                if match_pattern(pattern, v, &mut env, store) {
+                                    break Machine::Descending {
+                                        e: body,
+                                        env,
+                                        stk: *stk,
+                                    };
+                                }
+                            }
+                        }
+                    }
+                }
+                Stack::ForStart {
+                    var,
+                    end,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    machine = Machine::Descending {
+                        e: end,
+                        env: env.clone(),
+                        stk: Stack::ForEnd {
+                            var,
+                            start: v,
+                            body,
+                            env,
+                            stk,
+                        },
+                    }
+                }
+                Stack::ForEnd {
+                    var,
+                    start,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    let frame = Stack::For {
+                        var,
+                        index: start,
+                        stop: v,
+                        array: None,
+                        body,
+                        env,
+                        stk,
+                    };
+                    machine = for_step(frame, store)?
+                }
+                Stack::ForArray {
+                    var,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    // vectors can change length, so only arrays and
+                    // generators are allowed
+                    let ptr = match v {
+                        SnakeVal::Array(ptr) => ptr,
+                        SnakeVal::Generator(generator) => {
+                            let frame = Stack::ForGenerator {
+                                var,
+                                generator,
+                                body,
+                                env,
+                                stk,
+                            };
+                            machine = resume(generator, frame, store)?;
+                            continue;
+                        }
+                        _ => {
+                            return Err(InterpErr::ExpectedArray {
+                                got: v,
+                                msg: "for".to_string(),
+                            })
+                        }
+                    };
+                    let frame = Stack::For {
+                        var,
+                        index: SnakeVal::Num(0),
+                        stop: SnakeVal::Num(store.heap[ptr].len().try_into().unwrap()),
+                        array: Some(ptr),
+                        body,
+                        env,
+                        stk,
+                    };
+                    machine = for_step(frame, store)?
+                }
+                Stack::For {
+                    var,
+                    index,
+                    stop,
+                    array,
+                    body,
+                    env,
+                    stk,
+                } => {
+                    let frame = Stack::For {
+                        var,
+                        index: interpret_prim2(Prim::Add, index, SnakeVal::Num(1), store)?,
+                        stop,
+                        array,
+                        body,
+                        env,
+                        stk,
+                    };
+                    machine = for_step(frame, store)?
+                }
             },
         }
     }
@@ -847,7 +2494,8 @@ where
     Ann: Clone,
     W: std::io::Write,
 {
-    machine(e, w, &mut State::new())
+    let runtime_error = runtime_error_ctor();
+    machine(e, w, &mut State::new(&runtime_error))
 }
 
 pub fn prog<Ann, W>(p: &SurfProg<Ann>, w: &mut W) -> Interp<()>
@@ -855,5 +2503,6 @@ where
     W: std::io::Write,
     Ann: Clone,
 {
-    machine(p, w, &mut State::new())
+    let runtime_error = runtime_error_ctor();
+    machine(p, w, &mut State::new(&runtime_error))
 }
@@ -6,7 +6,48 @@ use std::{
 
 use crate::syntax::*;
 
-fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mut u32) -> Exp<()> {
+// Everything the compiled program has a static descriptor for, the ids
+// the internal prims refer to are indexes into these
+#[derive(Default)]
+pub struct Tables {
+    // with the unique name each constructor is bound to
+    pub ctors: Vec<(String, Constructor)>,
+    pub fields: Vec<String>,
+    // the field ids of each record shape, in order
+    pub shapes: Vec<Vec<usize>>,
+    // the name in the source of each parameter, which keyword arguments
+    // refer to
+    pub params: HashMap<String, String>,
+}
+
+impl Tables {
+    fn field_id(&mut self, field: &str) -> usize {
+        match self.fields.iter().position(|f| f == field) {
+            Some(id) => id,
+            None => {
+                self.fields.push(field.to_string());
+                self.fields.len() - 1
+            }
+        }
+    }
+
+    fn shape_id(&mut self, fields: Vec<usize>) -> usize {
+        match self.shapes.iter().position(|s| *s == fields) {
+            Some(id) => id,
+            None => {
+                self.shapes.push(fields);
+                self.shapes.len() - 1
+            }
+        }
+    }
+}
+
+fn uniquify<Span>(
+    e: &Exp<Span>,
+    mapping: &HashMap<String, String>,
+    counter: &mut u32,
+    tables: &mut Tables,
+) -> Exp<()> {
     match e {
         Exp::Let {
             bindings,
@@ -19,14 +60,14 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
                 .map(|(var, value)| {
                     *counter += 1;
                     let new_var = format!("{}", counter);
-                    let mut_exp = uniquify(value, &scoped_mapping, counter);
+                    let mut_exp = uniquify(value, &scoped_mapping, counter, tables);
                     scoped_mapping.insert(var.to_string(), new_var.clone());
                     return (new_var, mut_exp);
                 })
                 .collect();
             Exp::Let {
                 bindings: mut_bind,
-                body: Box::new(uniquify(&body, &scoped_mapping, counter)),
+                body: Box::new(uniquify(&body, &scoped_mapping, counter, tables)),
                 ann: (),
             }
         }
@@ -42,6 +83,7 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
                 for param in &decl.parameters {
                     *counter += 1;
                     func_scope_map.insert(param.to_string(), format!("{}", counter));
+                    tables.params.insert(format!("{}", counter), param.to_string());
                 }
                 uniq_decls.push(FunDecl {
                     name: scoped_mapping[&decl.name].clone(),
@@ -50,23 +92,112 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
                         .iter()
                         .map(|param| func_scope_map[param].clone())
                         .collect(),
-                    body: uniquify(&decl.body, &func_scope_map, counter),
+                    variadic: decl.variadic,
+                    defaults: decl
+                        .defaults
+                        .iter()
+                        .map(|d| uniquify(d, mapping, counter, tables))
+                        .collect(),
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
+                    body: uniquify(&decl.body, &func_scope_map, counter, tables),
                     ann: (),
                 })
             }
             Exp::FunDefs {
                 decls: uniq_decls,
-                body: Box::new(uniquify(&body, &scoped_mapping, counter)),
+                body: Box::new(uniquify(&body, &scoped_mapping, counter, tables)),
                 ann: (),
             }
         }
+        // def C(x, y): MakeData(id)(x, y) and ... in
+        // let D = MakeData(id)(), ... in body
+        Exp::DataDefs { ctors, body, .. } => {
+            let mut scoped_mapping = mapping.clone();
+            let mut funs = vec![];
+            let mut values = vec![];
+            for ctor in ctors {
+                *counter += 1;
+                let new_var = format!("{}", counter);
+                scoped_mapping.insert(ctor.name.clone(), new_var.clone());
+                let make = Prim::MakeData(tables.ctors.len());
+                tables.ctors.push((new_var.clone(), ctor.clone()));
+                if ctor.fields.is_empty() {
+                    values.push((new_var, Exp::Prim(make, vec![], ())));
+                    continue;
+                }
+                let mut parameters = vec![];
+                for field in &ctor.fields {
+                    *counter += 1;
+                    parameters.push(format!("{}", counter));
+                    tables.params.insert(format!("{}", counter), field.clone());
+                }
+                let fields = parameters
+                    .iter()
+                    .map(|p| Box::new(Exp::Var(p.clone(), ())))
+                    .collect();
+                funs.push(FunDecl {
+                    name: new_var,
+                    parameters,
+                    variadic: false,
+                    defaults: vec![],
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
+                    body: Exp::Prim(make, fields, ()),
+                    ann: (),
+                });
+            }
+            let mut e = uniquify(body, &scoped_mapping, counter, tables);
+            if !values.is_empty() {
+                e = Exp::Let {
+                    bindings: values,
+                    body: Box::new(e),
+                    ann: (),
+                };
+            }
+            if !funs.is_empty() {
+                e = Exp::FunDefs {
+                    decls: funs,
+                    body: Box::new(e),
+                    ann: (),
+                };
+            }
+            e
+        }
+        Exp::Record { fields, .. } => {
+            let ids = fields.iter().map(|(f, _)| tables.field_id(f)).collect();
+            let shape = tables.shape_id(ids);
+            let values = fields
+                .iter()
+                .map(|(_, value)| Box::new(uniquify(value, mapping, counter, tables)))
+                .collect();
+            Exp::Prim(Prim::MakeRecord(shape), values, ())
+        }
+        Exp::GetField { record, field, .. } => {
+            let record = uniquify(record, mapping, counter, tables);
+            let get = Prim::GetField(tables.field_id(field));
+            Exp::Prim(get, vec![Box::new(record)], ())
+        }
+        Exp::SetField {
+            record,
+            field,
+            value,
+            ..
+        } => {
+            let record = uniquify(record, mapping, counter, tables);
+            let value = uniquify(value, mapping, counter, tables);
+            let set = Prim::SetField(tables.field_id(field));
+            Exp::Prim(set, vec![Box::new(record), Box::new(value)], ())
+        }
         Exp::Var(v, _) => Exp::Var(mapping[v].clone(), ()),
         Exp::Num(i, _) => Exp::Num(*i, ()),
         Exp::Bool(b, _) => Exp::Bool(*b, ()),
         Exp::Prim(op, subjects, _) => {
             let uniq_sub = subjects
                 .iter()
-                .map(|s| Box::new(uniquify(s, mapping, counter)))
+                .map(|s| Box::new(uniquify(s, mapping, counter, tables)))
                 .collect();
             Exp::Prim(*op, uniq_sub, ())
         }
@@ -76,19 +207,34 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
             els,
             ann: _,
         } => Exp::If {
-            cond: Box::new(uniquify(&cond, mapping, counter)),
-            thn: Box::new(uniquify(&thn, mapping, counter)),
-            els: Box::new(uniquify(&els, mapping, counter)),
+            cond: Box::new(uniquify(&cond, mapping, counter, tables)),
+            thn: Box::new(uniquify(&thn, mapping, counter, tables)),
+            els: Box::new(uniquify(&els, mapping, counter, tables)),
             ann: (),
         },
         Exp::Call(func, params, _) => Exp::Call(
-            Box::new(uniquify(&func, mapping, counter)),
+            Box::new(uniquify(&func, mapping, counter, tables)),
             params
                 .iter()
-                .map(|param| uniquify(param, mapping, counter))
+                .map(|param| uniquify(param, mapping, counter, tables))
                 .collect(),
             (),
         ),
+        // the keywords are names of parameters, not variables
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ..
+        } => Exp::KeywordCall {
+            fun: Box::new(uniquify(fun, mapping, counter, tables)),
+            args: args.iter().map(|a| uniquify(a, mapping, counter, tables)).collect(),
+            keywords: keywords
+                .iter()
+                .map(|(x, e)| (x.clone(), uniquify(e, mapping, counter, tables)))
+                .collect(),
+            ann: (),
+        },
         Exp::InternalTailCall(_, _, _) => todo!(),
         Exp::ExternalCall {
             args: _,
@@ -96,16 +242,140 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
             ann: _,
             fun,
         } => todo!(),
+        Exp::LetPattern { .. } => unreachable!("rewritten into a Let by desugar"),
         Exp::Semicolon { e1, e2, ann } => {
             *counter += 1;
             Exp::Let {
-                bindings: vec![(counter.to_string(), uniquify(e1, mapping, counter))],
-                body: Box::new(uniquify(e2, mapping, counter)),
+                bindings: vec![(
+                    counter.to_string(),
+                    uniquify(e1, mapping, counter, tables),
+                )],
+                body: Box::new(uniquify(e2, mapping, counter, tables)),
+                ann: (),
+            }
+        }
+        Exp::Assign { var, value, .. } => Exp::Assign {
+            var: mapping[var].clone(),
+            value: Box::new(uniquify(value, mapping, counter, tables)),
+            ann: (),
+        },
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: Box::new(uniquify(cond, mapping, counter, tables)),
+            body: Box::new(uniquify(body, mapping, counter, tables)),
+            ann: (),
+        },
+        Exp::Try {
+            body, var, handler, ..
+        } => {
+            let body = uniquify(body, mapping, counter, tables);
+            let (var, handler) = uniquify_in_scope(var, handler, mapping, counter, tables);
+            Exp::Try {
+                body: Box::new(body),
+                var,
+                handler: Box::new(handler),
+                ann: (),
+            }
+        }
+        // let i = start, stop = end in <counted loop binding var to i>
+        Exp::ForRange {
+            var,
+            start,
+            end,
+            body,
+            ..
+        } => {
+            let start = uniquify(start, mapping, counter, tables);
+            let end = uniquify(end, mapping, counter, tables);
+            *counter += 1;
+            let index = format!("{}", counter);
+            *counter += 1;
+            let stop = format!("{}", counter);
+            let elt = Exp::Var(index.clone(), ());
+            let cond = Exp::Prim(
+                Prim::Lt,
+                vec![
+                    Box::new(Exp::Var(index.clone(), ())),
+                    Box::new(Exp::Var(stop.clone(), ())),
+                ],
+                (),
+            );
+            let (var, body) = uniquify_in_scope(var, body, mapping, counter, tables);
+            let lp = counted_loop(&index, cond, var, elt, body, counter);
+            Exp::Let {
+                bindings: vec![(index, start), (stop, end)],
+                body: Box::new(lp),
+                ann: (),
+            }
+        }
+        // let it = array, stop = CheckIterable(it), i = 0 in
+        // <counted loop binding var to it[i] while i < stop, for an array,
+        //  or to the value the generator yielded while it yields one>
+        // The array is checked once, so the loop can index it unchecked
+        Exp::ForEach { var, array, body, .. } => {
+            let array = uniquify(array, mapping, counter, tables);
+            *counter += 1;
+            let it = format!("{}", counter);
+            *counter += 1;
+            let index = format!("{}", counter);
+            *counter += 1;
+            let stop = format!("{}", counter);
+            let var_exp = |x: &str| Box::new(Exp::Var(x.to_string(), ()));
+            let is_generator = || {
+                Box::new(Exp::Prim(
+                    Prim::Lt,
+                    vec![var_exp(&stop), Box::new(Exp::Num(0, ()))],
+                    (),
+                ))
+            };
+            let cond = Exp::If {
+                cond: is_generator(),
+                thn: Box::new(Exp::Prim(Prim::Advance, vec![var_exp(&it)], ())),
+                els: Box::new(Exp::Prim(Prim::Lt, vec![var_exp(&index), var_exp(&stop)], ())),
+                ann: (),
+            };
+            let elt = Exp::If {
+                cond: is_generator(),
+                thn: Box::new(Exp::Prim(Prim::LastYielded, vec![var_exp(&it)], ())),
+                els: Box::new(Exp::Prim(
+                    Prim::UncheckedArrayGet,
+                    vec![var_exp(&it), var_exp(&index)],
+                    (),
+                )),
+                ann: (),
+            };
+            let (var, body) = uniquify_in_scope(var, body, mapping, counter, tables);
+            let lp = counted_loop(&index, cond, var, elt, body, counter);
+            let length = Exp::Prim(Prim::CheckIterable, vec![var_exp(&it)], ());
+            Exp::Let {
+                bindings: vec![(it, array), (stop, length), (index, Exp::Num(0, ()))],
+                body: Box::new(lp),
+                ann: (),
+            }
+        }
+        Exp::Match { scrutinee, arms, .. } => {
+            let scrutinee = uniquify(scrutinee, mapping, counter, tables);
+            *counter += 1;
+            let occurrence = format!("{}", counter);
+            let rows = arms
+                .iter()
+                .map(|(pattern, body)| MatchRow {
+                    patterns: vec![pattern.clone()],
+                    bound: vec![],
+                    body,
+                })
+                .collect();
+            let occurrences = vec![occurrence.clone()];
+            let tree = match_tree(&occurrence, occurrences, rows, mapping, counter, tables);
+            Exp::Let {
+                bindings: vec![(occurrence, scrutinee)],
+                body: Box::new(tree),
                 ann: (),
             }
         }
         Exp::Lambda {
             parameters,
+            variadic,
+            defaults,
             body,
             ann,
         } => {
@@ -113,24 +383,423 @@ fn uniquify<Span>(e: &Exp<Span>, mapping: &HashMap<String, String>, counter: &mu
             for param in parameters {
                 *counter += 1;
                 scoped_mapping.insert(param.to_string(), format!("{}", counter));
+                tables.params.insert(format!("{}", counter), param.to_string());
             }
             Exp::Lambda {
                 parameters: parameters.iter().map(|p| scoped_mapping[p].clone()).collect(),
-                body: Box::new(uniquify(&body, &scoped_mapping, counter)),
+                variadic: *variadic,
+                defaults: defaults.iter().map(|d| uniquify(d, mapping, counter, tables)).collect(),
+                body: Box::new(uniquify(&body, &scoped_mapping, counter, tables)),
                 ann: (),
             }
         }
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => todo!(),
+        Exp::MakeClosure { .. } => todo!(),
         Exp::ClosureCall(_, _, _) => todo!(),
         Exp::DirectCall(_, _, _) => todo!(),
     }
 }
 
+// Uniquifies [body] with [var] in scope, returns the unique name of [var]
+// along with it
+fn uniquify_in_scope<Span>(
+    var: &str,
+    body: &Exp<Span>,
+    mapping: &HashMap<String, String>,
+    counter: &mut u32,
+    tables: &mut Tables,
+) -> (String, Exp<()>) {
+    let mut scoped_mapping = mapping.clone();
+    *counter += 1;
+    let new_var = format!("{}", counter);
+    scoped_mapping.insert(var.to_string(), new_var.clone());
+    (new_var, uniquify(body, &scoped_mapping, counter, tables))
+}
+
+// while cond: (let var = elt in body); index := add1(index) end
+fn counted_loop(
+    index: &str,
+    cond: Exp<()>,
+    var: String,
+    elt: Exp<()>,
+    body: Exp<()>,
+    counter: &mut u32,
+) -> Exp<()> {
+    let index_var = || Box::new(Exp::Var(index.to_string(), ()));
+    *counter += 1;
+    Exp::While {
+        cond: Box::new(cond),
+        body: Box::new(Exp::Let {
+            bindings: vec![(
+                counter.to_string(),
+                Exp::Let {
+                    bindings: vec![(var, elt)],
+                    body: Box::new(body),
+                    ann: (),
+                },
+            )],
+            body: Box::new(Exp::Assign {
+                var: index.to_string(),
+                value: Box::new(Exp::Prim(Prim::Add1, vec![index_var()], ())),
+                ann: (),
+            }),
+            ann: (),
+        }),
+        ann: (),
+    }
+}
+
+// One arm of a match while it is compiled: the patterns left to test,
+// one per occurrence, and the pattern variables bound so far, each
+// with the occurrence it stands for
+struct MatchRow<'e, Span> {
+    patterns: Vec<Pattern>,
+    bound: Vec<(String, String)>,
+    body: &'e Exp<Span>,
+}
+
+fn refutable(p: &Pattern) -> bool {
+    !matches!(p, Pattern::Wildcard | Pattern::Var(_))
+}
+
+// Compiles the rows, the arms that can still match, into a decision
+// tree testing the occurrences, variables bound to the scrutinee and
+// its parts. The first column the first row tests decides the next
+// test: a tag check and length switch for array patterns, a check of
+// the constructor for constructor patterns, equality for literals, and
+// the rows that don't care about the column as the fallback. Without
+// any rows left the match fails on the scrutinee.
+fn match_tree<Span>(
+    scrutinee: &str,
+    occurrences: Vec<String>,
+    rows: Vec<MatchRow<Span>>,
+    mapping: &HashMap<String, String>,
+    counter: &mut u32,
+    tables: &mut Tables,
+) -> Exp<()> {
+    let var = |x: &str| Box::new(Exp::Var(x.to_string(), ()));
+    let first = match rows.first() {
+        None => return Exp::Prim(Prim::MatchFail, vec![var(scrutinee)], ()),
+        Some(row) => row,
+    };
+    let col = match first.patterns.iter().position(refutable) {
+        Some(col) => col,
+        None => {
+            // the first row matches, bind its variables and run its body
+            let row = rows.into_iter().next().unwrap();
+            let mut bound = row.bound;
+            for (p, occurrence) in row.patterns.iter().zip(occurrences.iter()) {
+                if let Pattern::Var(x) = p {
+                    bound.push((x.clone(), occurrence.clone()));
+                }
+            }
+            let mut scoped_mapping = mapping.clone();
+            let mut bindings = vec![];
+            for (x, occurrence) in bound {
+                *counter += 1;
+                let new_var = format!("{}", counter);
+                scoped_mapping.insert(x, new_var.clone());
+                bindings.push((new_var, Exp::Var(occurrence, ())));
+            }
+            let body = uniquify(row.body, &scoped_mapping, counter, tables);
+            if bindings.is_empty() {
+                return body;
+            }
+            return Exp::Let {
+                bindings,
+                body: Box::new(body),
+                ann: (),
+            };
+        }
+    };
+    let occurrence = occurrences[col].clone();
+    let mut rest = occurrences.clone();
+    rest.remove(col);
+
+    // the rows for when the column is [ctor], a literal, a constructor
+    // pattern or an array pattern standing for its length, with [width]
+    // new columns
+    let specialize = |ctor: &Pattern, width: usize| -> Vec<MatchRow<Span>> {
+        let mut specialized = vec![];
+        for row in &rows {
+            let mut patterns = row.patterns.clone();
+            let mut bound = row.bound.clone();
+            let p = patterns.remove(col);
+            match (&p, ctor) {
+                (Pattern::Array(ps), Pattern::Array(_)) if ps.len() == width => {
+                    patterns.extend(ps.iter().cloned())
+                }
+                (Pattern::Array(_), _) => continue,
+                (Pattern::Constructor(c, ps), Pattern::Constructor(d, _)) if c == d => {
+                    patterns.extend(ps.iter().cloned())
+                }
+                (Pattern::Constructor(..), _) => continue,
+                (Pattern::Num(_), _) | (Pattern::Bool(_), _) if &p == ctor => (),
+                (Pattern::Num(_), _) | (Pattern::Bool(_), _) => continue,
+                (Pattern::Var(x), _) => {
+                    bound.push((x.clone(), occurrence.clone()));
+                    patterns.extend(vec![Pattern::Wildcard; width]);
+                }
+                (Pattern::Wildcard, _) => patterns.extend(vec![Pattern::Wildcard; width]),
+            }
+            specialized.push(MatchRow {
+                patterns,
+                bound,
+                body: row.body,
+            });
+        }
+        specialized
+    };
+    let mut literals = vec![];
+    let mut lengths = vec![];
+    let mut constructors = vec![];
+    for row in &rows {
+        match &row.patterns[col] {
+            Pattern::Array(ps) if !lengths.contains(&ps.len()) => lengths.push(ps.len()),
+            p @ Pattern::Constructor(..) if !constructors.contains(p) => {
+                constructors.push(p.clone())
+            }
+            p @ Pattern::Num(_) | p @ Pattern::Bool(_) if !literals.contains(p) => {
+                literals.push(p.clone())
+            }
+            _ => (),
+        }
+    }
+    // the fallback can end up in several branches, it is compiled
+    // again for each so that all of its names stay unique
+    let default = |counter: &mut u32, tables: &mut Tables| {
+        let rows = specialize(&Pattern::Wildcard, 0);
+        match_tree(scrutinee, rest.clone(), rows, mapping, counter, tables)
+    };
+
+    let mut tree = default(counter, tables);
+    // if isdata_id(o): let o_0 = field 0 of o, ... in ... else: ...
+    for ctor in constructors.iter().rev() {
+        let (name, width) = match ctor {
+            Pattern::Constructor(name, ps) => (name, ps.len()),
+            _ => unreachable!(),
+        };
+        let id = tables.ctors.iter().position(|(x, _)| *x == mapping[name]).unwrap();
+        let mut fields = vec![];
+        for i in 0..width {
+            *counter += 1;
+            let field = format!("{}", counter);
+            let get = Exp::Prim(
+                Prim::DataGet,
+                vec![var(&occurrence), Box::new(Exp::Num(i as i64, ()))],
+                (),
+            );
+            fields.push((field, get));
+        }
+        let mut occurrences = rest.clone();
+        occurrences.extend(fields.iter().map(|(field, _)| field.clone()));
+        let rows = specialize(ctor, width);
+        let mut thn = match_tree(scrutinee, occurrences, rows, mapping, counter, tables);
+        if !fields.is_empty() {
+            thn = Exp::Let {
+                bindings: fields,
+                body: Box::new(thn),
+                ann: (),
+            };
+        }
+        tree = Exp::If {
+            cond: Box::new(Exp::Prim(Prim::IsData(id), vec![var(&occurrence)], ())),
+            thn: Box::new(thn),
+            els: Box::new(tree),
+            ann: (),
+        };
+    }
+    for literal in literals.iter().rev() {
+        let lit = match literal {
+            Pattern::Num(n) => Exp::Num(*n, ()),
+            Pattern::Bool(b) => Exp::Bool(*b, ()),
+            _ => unreachable!(),
+        };
+        let rows = specialize(literal, 0);
+        let thn = match_tree(scrutinee, rest.clone(), rows, mapping, counter, tables);
+        tree = Exp::If {
+            cond: Box::new(Exp::Prim(Prim::Eq, vec![var(&occurrence), Box::new(lit)], ())),
+            thn: Box::new(thn),
+            els: Box::new(tree),
+            ann: (),
+        };
+    }
+    if lengths.is_empty() {
+        return tree;
+    }
+
+    // if isarray(o): let n = length(o) in
+    //   if n == k: let o_0 = o[0], ..., o_k-1 = o[k-1] in ... else: ...
+    *counter += 1;
+    let length = format!("{}", counter);
+    let mut switch = default(counter, tables);
+    for width in lengths.into_iter().rev() {
+        let mut elts = vec![];
+        for i in 0..width {
+            *counter += 1;
+            let elt = format!("{}", counter);
+            let get = Exp::Prim(
+                Prim::UncheckedArrayGet,
+                vec![var(&occurrence), Box::new(Exp::Num(i as i64, ()))],
+                (),
+            );
+            elts.push((elt, get));
+        }
+        let mut occurrences = rest.clone();
+        occurrences.extend(elts.iter().map(|(elt, _)| elt.clone()));
+        let rows = specialize(&Pattern::Array(vec![]), width);
+        let mut thn = match_tree(scrutinee, occurrences, rows, mapping, counter, tables);
+        if !elts.is_empty() {
+            thn = Exp::Let {
+                bindings: elts,
+                body: Box::new(thn),
+                ann: (),
+            };
+        }
+        switch = Exp::If {
+            cond: Box::new(Exp::Prim(
+                Prim::Eq,
+                vec![var(&length), Box::new(Exp::Num(width as i64, ()))],
+                (),
+            )),
+            thn: Box::new(thn),
+            els: Box::new(switch),
+            ann: (),
+        };
+    }
+    Exp::If {
+        cond: Box::new(Exp::Prim(Prim::IsArray, vec![var(&occurrence)], ())),
+        thn: Box::new(Exp::Let {
+            bindings: vec![(
+                length,
+                Exp::Prim(Prim::Length, vec![var(&occurrence)], ()),
+            )],
+            body: Box::new(switch),
+            ann: (),
+        }),
+        els: Box::new(tree),
+        ann: (),
+    }
+}
+
+// A variable that is bound to a record literal and never assigned
+// always holds a record of that shape, so the fields of the record are
+// accessed by index instead of searched for. [known] maps these
+// variables to their shape.
+fn resolve_fields(
+    e: &Exp<()>,
+    known: &mut HashMap<String, usize>,
+    assigned: &HashSet<String>,
+    tables: &Tables,
+) -> Exp<()> {
+    let resolve = |e: &Exp<()>, known: &mut HashMap<String, usize>| {
+        Box::new(resolve_fields(e, known, assigned, tables))
+    };
+    match e {
+        Exp::Prim(op, exps, _) => {
+            // the index of the field in the record's shape
+            let index = |field: usize| match &*exps[0] {
+                Exp::Var(x, _) => known
+                    .get(x)
+                    .and_then(|shape| tables.shapes[*shape].iter().position(|f| *f == field)),
+                _ => None,
+            };
+            let op = match *op {
+                Prim::GetField(field) => index(field).map_or(*op, Prim::GetFieldAt),
+                Prim::SetField(field) => index(field).map_or(*op, Prim::SetFieldAt),
+                _ => *op,
+            };
+            let exps = exps.iter().map(|e| resolve(e, known)).collect();
+            Exp::Prim(op, exps, ())
+        }
+        Exp::Let { bindings, body, .. } => {
+            let mut resolved = vec![];
+            for (x, bind) in bindings {
+                if let Exp::Prim(Prim::MakeRecord(shape), _, _) = bind {
+                    if !assigned.contains(x) {
+                        known.insert(x.clone(), *shape);
+                    }
+                }
+                resolved.push((x.clone(), *resolve(bind, known)));
+            }
+            Exp::Let {
+                bindings: resolved,
+                body: resolve(body, known),
+                ann: (),
+            }
+        }
+        Exp::If { cond, thn, els, .. } => Exp::If {
+            cond: resolve(cond, known),
+            thn: resolve(thn, known),
+            els: resolve(els, known),
+            ann: (),
+        },
+        Exp::FunDefs { decls, body, .. } => Exp::FunDefs {
+            decls: decls
+                .iter()
+                .map(|decl| FunDecl {
+                    name: decl.name.clone(),
+                    parameters: decl.parameters.clone(),
+                    variadic: decl.variadic,
+                    defaults: decl.defaults.clone(),
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
+                    body: *resolve(&decl.body, known),
+                    ann: (),
+                })
+                .collect(),
+            body: resolve(body, known),
+            ann: (),
+        },
+        Exp::Lambda {
+            parameters,
+            variadic,
+            defaults,
+            body,
+            ..
+        } => Exp::Lambda {
+            parameters: parameters.clone(),
+            variadic: *variadic,
+            defaults: defaults.clone(),
+            body: resolve(body, known),
+            ann: (),
+        },
+        Exp::Call(func, args, _) => {
+            Exp::Call(resolve(func, known), args.iter().map(|a| *resolve(a, known)).collect(), ())
+        }
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ..
+        } => Exp::KeywordCall {
+            fun: resolve(fun, known),
+            args: args.iter().map(|a| *resolve(a, known)).collect(),
+            keywords: keywords.iter().map(|(x, e)| (x.clone(), *resolve(e, known))).collect(),
+            ann: (),
+        },
+        Exp::Assign { var, value, .. } => Exp::Assign {
+            var: var.clone(),
+            value: resolve(value, known),
+            ann: (),
+        },
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: resolve(cond, known),
+            body: resolve(body, known),
+            ann: (),
+        },
+        Exp::Try {
+            body, var, handler, ..
+        } => Exp::Try {
+            body: resolve(body, known),
+            var: var.clone(),
+            handler: resolve(handler, known),
+            ann: (),
+        },
+        _ => e.clone(),
+    }
+}
+
 fn rewrite_call_params(
     e: &Exp<()>,
     globals: &HashMap<String, FunDecl<Exp<()>, ()>>,
@@ -173,6 +842,11 @@ fn rewrite_call_params(
                 .map(|decl| FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    variadic: decl.variadic,
+                    defaults: decl.defaults.clone(),
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
                     body: rewrite_call_params(&decl.body, globals, true),
                     ann: (),
                 })
@@ -201,13 +875,14 @@ fn rewrite_call_params(
                 ann: (),
             }
         }
+        // the keyword arguments, if any, are the last argument
         Exp::ClosureCall(func, args, _) => Exp::Let {
             bindings: vec![
-                ("#lambda".to_string(), *func.clone()),
+                ("#lambda".to_string(), rewrite_call_params(func, globals, false)),
                 (
                     "#untagged".to_string(),
                     Exp::Prim(
-                        Prim::CheckArityAndUntag(args.len()),
+                        Prim::UntagClosure,
                         vec![Box::new(Exp::Var("#lambda".to_string(), ()))],
                         (),
                     ),
@@ -215,7 +890,10 @@ fn rewrite_call_params(
                 (
                     "#code_ptr".to_string(),
                     Exp::Prim(
-                        Prim::GetCode,
+                        match args.last() {
+                            Some(Exp::Prim(Prim::MakeKeywords(_), _, _)) => Prim::GetCode(None),
+                            _ => Prim::GetCode(Some(args.len())),
+                        },
                         vec![Box::new(Exp::Var("#untagged".to_string(), ()))],
                         (),
                     ),
@@ -241,17 +919,8 @@ fn rewrite_call_params(
             }),
             ann: (),
         },
-        Exp::Lambda {
-            parameters,
-            body,
-            ann,
-        } => panic!(),
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => e.clone(),
+        Exp::Lambda { .. } => panic!(),
+        Exp::MakeClosure { .. } => e.clone(),
         Exp::InternalTailCall(_, _, _) => todo!(),
         Exp::ExternalCall {
             fun,
@@ -261,32 +930,110 @@ fn rewrite_call_params(
         } => todo!(),
         Exp::Call(_, _, _) => todo!(),
         Exp::Semicolon { e1, e2, ann } => todo!(),
+        Exp::Assign { var, value, .. } => Exp::Assign {
+            var: var.clone(),
+            value: Box::new(rewrite_call_params(value, globals, false)),
+            ann: (),
+        },
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: Box::new(rewrite_call_params(cond, globals, false)),
+            body: Box::new(rewrite_call_params(body, globals, false)),
+            ann: (),
+        },
+        // the handler has to be uninstalled after the body
+        Exp::Try {
+            body, var, handler, ..
+        } => Exp::Try {
+            body: Box::new(rewrite_call_params(body, globals, false)),
+            var: var.clone(),
+            handler: Box::new(rewrite_call_params(handler, globals, is_tail)),
+            ann: (),
+        },
         _ => e.clone(),
     }
 }
 
+// the record shape of the source names of the parameters, for keyword arguments
+fn keyword_shape(params: &[String], tables: &mut Tables) -> usize {
+    let fields = params
+        .iter()
+        .map(|p| {
+            let name = tables.params.get(p).cloned().unwrap_or_else(|| p.clone());
+            tables.field_id(&name)
+        })
+        .collect();
+    tables.shape_id(fields)
+}
+
 fn lift_functions(
     e: &Exp<()>,
     vars: &HashSet<String>,
     globals: &mut HashMap<String, FunDecl<Exp<()>, ()>>,
     need_lift: &HashSet<String>,
+    boxed: &HashSet<String>,
+    tables: &mut Tables,
+    captured: &mut HashMap<String, usize>,
 ) -> Exp<()> {
     match e {
         Exp::Var(v, _) => {
-            if globals.contains_key(v) {
+            if let Some(decl) = globals.get(v) {
+                // a function that captures variables takes them after its
+                // parameters, so it is wrapped in a lambda that passes them
+                if captured.get(v).is_some_and(|&n| n > 0) {
+                    let n = decl.parameters.len() - captured[v];
+                    let parameters: Vec<String> = decl.parameters[..n]
+                        .iter()
+                        .map(|p| format!("{}'", p))
+                        .collect();
+                    for (p, q) in decl.parameters.iter().zip(&parameters) {
+                        let name = tables.params.get(p).cloned().unwrap_or_else(|| p.clone());
+                        tables.params.insert(q.clone(), name);
+                    }
+                    let wrapper = Exp::Lambda {
+                        parameters: parameters.clone(),
+                        variadic: decl.variadic,
+                        defaults: decl.defaults.clone(),
+                        body: Box::new(Exp::DirectCall(
+                            v.clone(),
+                            parameters.iter().map(|p| Exp::Var(p.clone(), ())).collect(),
+                            (),
+                        )),
+                        ann: (),
+                    };
+                    return lift_functions(
+                        &wrapper, vars, globals, need_lift, boxed, tables, captured,
+                    );
+                }
+                let arity = decl.parameters.len() - decl.variadic as usize;
                 return Exp::MakeClosure {
-                    arity: globals[v].parameters.len(),
-                    label: globals[v].name.clone(),
-                    env: Box::new(Exp::Prim(Prim::MakeArray, vec![], ())),
+                    arity,
+                    variadic: decl.variadic,
+                    label: decl.name.clone(),
+                    keywords: keyword_shape(&decl.parameters[..arity], tables),
+                    defaults: decl.defaults.len(),
+                    env: Box::new(Exp::Prim(
+                        Prim::MakeArray,
+                        decl.defaults.iter().map(|d| Box::new(d.clone())).collect(),
+                        (),
+                    )),
                     ann: (),
                 };
             }
+            if boxed.contains(v) {
+                return Exp::Prim(
+                    Prim::ArrayGet,
+                    vec![Box::new(e.clone()), Box::new(Exp::Num(0, ()))],
+                    (),
+                );
+            }
             return e.clone();
         }
         Exp::Prim(p, exps, _) => {
             let mut new_exps = vec![];
             for exp in exps {
-                new_exps.push(Box::new(lift_functions(&exp, vars, globals, need_lift)));
+                new_exps.push(Box::new(lift_functions(
+                    &exp, vars, globals, need_lift, boxed, tables, captured,
+                )));
             }
             Exp::Prim(*p, new_exps, ())
         }
@@ -300,12 +1047,31 @@ fn lift_functions(
                 bindings: bindings
                     .iter()
                     .map(|bind| {
-                        let new_bind = lift_functions(&bind.1, &scoped_vars, globals, need_lift);
+                        let mut new_bind = lift_functions(
+                            &bind.1,
+                            &scoped_vars,
+                            globals,
+                            need_lift,
+                            boxed,
+                            tables,
+                            captured,
+                        );
+                        if boxed.contains(&bind.0) {
+                            new_bind = Exp::Prim(Prim::MakeArray, vec![Box::new(new_bind)], ());
+                        }
                         scoped_vars.insert(bind.0.clone());
                         (bind.0.clone(), new_bind)
                     })
                     .collect(),
-                body: Box::new(lift_functions(&body, &scoped_vars, globals, need_lift)),
+                body: Box::new(lift_functions(
+                    &body,
+                    &scoped_vars,
+                    globals,
+                    need_lift,
+                    boxed,
+                    tables,
+                    captured,
+                )),
                 ann: (),
             }
         }
@@ -315,18 +1081,54 @@ fn lift_functions(
             els,
             ann,
         } => Exp::If {
-            cond: Box::new(lift_functions(&cond, vars, globals, need_lift)),
-            thn: Box::new(lift_functions(&thn, vars, globals, need_lift)),
-            els: Box::new(lift_functions(&els, vars, globals, need_lift)),
+            cond: Box::new(lift_functions(
+                &cond, vars, globals, need_lift, boxed, tables, captured,
+            )),
+            thn: Box::new(lift_functions(
+                &thn, vars, globals, need_lift, boxed, tables, captured,
+            )),
+            els: Box::new(lift_functions(
+                &els, vars, globals, need_lift, boxed, tables, captured,
+            )),
             ann: (),
         },
         Exp::FunDefs { decls, body, ann } => {
+            // declare the lifted functions first, so that their bodies can
+            // refer to each other as values
+            for decl in decls.iter().filter(|d| need_lift.contains(&d.name)) {
+                captured.insert(decl.name.clone(), vars.len());
+                globals.insert(
+                    decl.name.clone(),
+                    FunDecl {
+                        name: decl.name.clone(),
+                        parameters: [decl.parameters.clone(), Vec::from_iter(vars.clone())]
+                            .concat(),
+                        variadic: decl.variadic,
+                        defaults: decl.defaults.clone(),
+                        param_types: vec![],
+                        result_type: None,
+                        doc: None,
+                        body: Exp::Bool(false, ()),
+                        ann: (),
+                    },
+                );
+            }
             let mut new_local = vec![];
             for decl in decls {
+                // the parameters can be captured by lambdas in the body
+                let mut body_vars = vars.clone();
+                body_vars.extend(decl.parameters.iter().cloned());
                 let mut new_decl = FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
-                    body: lift_functions(&decl.body, vars, globals, need_lift),
+                    variadic: decl.variadic,
+                    defaults: decl.defaults.clone(),
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
+                    body: lift_functions(
+                        &decl.body, &body_vars, globals, need_lift, boxed, tables, captured,
+                    ),
                     ann: (),
                 };
                 if !need_lift.contains(&decl.name) {
@@ -338,7 +1140,7 @@ fn lift_functions(
                     [decl.parameters.clone(), Vec::from_iter(vars.clone())].concat();
                 globals.insert(decl.name.clone(), new_decl);
             }
-            let new_bod = lift_functions(&body, vars, globals, need_lift);
+            let new_bod = lift_functions(&body, vars, globals, need_lift, boxed, tables, captured);
             if !new_local.is_empty() {
                 return Exp::FunDefs {
                     decls: new_local,
@@ -351,20 +1153,28 @@ fn lift_functions(
         Exp::DirectCall(func, params, _) => {
             let new_params = params
                 .iter()
-                .map(|param| lift_functions(param, vars, globals, need_lift))
+                .map(|param| {
+                    lift_functions(param, vars, globals, need_lift, boxed, tables, captured)
+                })
                 .collect();
             Exp::DirectCall(func.clone(), new_params, ())
         }
         Exp::ClosureCall(exp, params, _) => Exp::ClosureCall(
-            Box::new(lift_functions(exp, vars, globals, need_lift)),
+            Box::new(lift_functions(
+                exp, vars, globals, need_lift, boxed, tables, captured,
+            )),
             params
                 .iter()
-                .map(|param| lift_functions(param, vars, globals, need_lift))
+                .map(|param| {
+                    lift_functions(param, vars, globals, need_lift, boxed, tables, captured)
+                })
                 .collect(),
             (),
         ),
         Exp::Lambda {
             parameters,
+            variadic,
+            defaults,
             body,
             ann,
         } => {
@@ -382,37 +1192,49 @@ fn lift_functions(
                     ),
                 ));
             }
+            let mut body_vars = vars.clone();
+            body_vars.extend(parameters.iter().cloned());
+            // lift the lambdas in the body first so that they are named apart
+            let body = lift_functions(
+                body, &body_vars, globals, need_lift, boxed, tables, captured,
+            );
             let decl = FunDecl {
                 // use globals.len() as counter
                 name: format!("lambda_{}", globals.len()),
                 parameters: [parameters.clone(), vec!["#env".to_string()]].concat(),
+                variadic: *variadic,
+                defaults: defaults.clone(),
+                param_types: vec![],
+                result_type: None,
+                doc: None,
                 body: Exp::Let {
                     bindings: env_bindings,
-                    body: Box::new(lift_functions(body, vars, globals, need_lift)),
+                    body: Box::new(body),
                     ann: (),
                 },
                 ann: (),
             };
             globals.insert(decl.name.clone(), decl.clone());
+            // the defaults go after the captured variables
+            let arity = parameters.len() - *variadic as usize;
             Exp::MakeClosure {
-                arity: parameters.len(),
+                arity,
+                variadic: *variadic,
                 label: decl.name.clone(),
+                keywords: keyword_shape(&parameters[..arity], tables),
+                defaults: defaults.len(),
                 env: Box::new(Exp::Prim(
                     Prim::MakeArray,
                     vars.iter()
                         .map(|v| Box::new(Exp::Var(v.clone(), ())))
+                        .chain(defaults.iter().map(|d| Box::new(d.clone())))
                         .collect(),
                     (),
                 )),
                 ann: (),
             }
         }
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => todo!(),
+        Exp::MakeClosure { .. } => todo!(),
         Exp::InternalTailCall(_, _, _) => todo!(),
         Exp::ExternalCall {
             fun,
@@ -422,10 +1244,303 @@ fn lift_functions(
         } => todo!(),
         Exp::Call(_, _, _) => todo!(),
         Exp::Semicolon { e1, e2, ann } => todo!(),
+        Exp::Assign { var, value, .. } => {
+            let new_value =
+                lift_functions(value, vars, globals, need_lift, boxed, tables, captured);
+            if !boxed.contains(var) {
+                return Exp::Assign {
+                    var: var.clone(),
+                    value: Box::new(new_value),
+                    ann: (),
+                };
+            }
+            // the set returns the box, read the new value back out of it
+            let set = Exp::Prim(
+                Prim::ArraySet,
+                vec![
+                    Box::new(Exp::Var(var.clone(), ())),
+                    Box::new(Exp::Num(0, ())),
+                    Box::new(new_value),
+                ],
+                (),
+            );
+            Exp::Prim(
+                Prim::ArrayGet,
+                vec![Box::new(set), Box::new(Exp::Num(0, ()))],
+                (),
+            )
+        }
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: Box::new(lift_functions(
+                cond, vars, globals, need_lift, boxed, tables, captured,
+            )),
+            body: Box::new(lift_functions(
+                body, vars, globals, need_lift, boxed, tables, captured,
+            )),
+            ann: (),
+        },
+        Exp::Try {
+            body, var, handler, ..
+        } => {
+            let mut scoped_vars = vars.clone();
+            scoped_vars.insert(var.clone());
+            Exp::Try {
+                body: Box::new(lift_functions(
+                    body, vars, globals, need_lift, boxed, tables, captured,
+                )),
+                var: var.clone(),
+                handler: Box::new(lift_functions(
+                    handler,
+                    &scoped_vars,
+                    globals,
+                    need_lift,
+                    boxed,
+                    tables,
+                    captured,
+                )),
+                ann: (),
+            }
+        }
         _ => e.clone(),
     }
 }
 
+// Variables that are the target of an assignment
+fn assigned_vars(e: &Exp<()>, acc: &mut HashSet<String>) {
+    match e {
+        Exp::Assign { var, value, .. } => {
+            acc.insert(var.clone());
+            assigned_vars(value, acc);
+        }
+        Exp::Prim(_, exps, _) => {
+            for exp in exps {
+                assigned_vars(exp, acc);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            for (_, bind) in bindings {
+                assigned_vars(bind, acc);
+            }
+            assigned_vars(body, acc);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            assigned_vars(cond, acc);
+            assigned_vars(thn, acc);
+            assigned_vars(els, acc);
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                assigned_vars(&decl.body, acc);
+            }
+            assigned_vars(body, acc);
+        }
+        Exp::Lambda { body, .. } => assigned_vars(body, acc),
+        Exp::While { cond, body, .. } => {
+            assigned_vars(cond, acc);
+            assigned_vars(body, acc);
+        }
+        Exp::Try { body, handler, .. } => {
+            assigned_vars(body, acc);
+            assigned_vars(handler, acc);
+        }
+        Exp::DirectCall(_, args, _) => {
+            for arg in args {
+                assigned_vars(arg, acc);
+            }
+        }
+        Exp::ClosureCall(func, args, _) | Exp::Call(func, args, _) => {
+            assigned_vars(func, acc);
+            for arg in args {
+                assigned_vars(arg, acc);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Variables and functions that [e] refers to
+fn used_names(e: &Exp<()>, acc: &mut HashSet<String>) {
+    match e {
+        Exp::Var(x, _) => {
+            acc.insert(x.clone());
+        }
+        Exp::Assign { var, value, .. } => {
+            acc.insert(var.clone());
+            used_names(value, acc);
+        }
+        Exp::Prim(_, exps, _) => {
+            for exp in exps {
+                used_names(exp, acc);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            for (_, bind) in bindings {
+                used_names(bind, acc);
+            }
+            used_names(body, acc);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            used_names(cond, acc);
+            used_names(thn, acc);
+            used_names(els, acc);
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                for default in &decl.defaults {
+                    used_names(default, acc);
+                }
+                used_names(&decl.body, acc);
+            }
+            used_names(body, acc);
+        }
+        Exp::Lambda { defaults, body, .. } => {
+            for default in defaults {
+                used_names(default, acc);
+            }
+            used_names(body, acc)
+        }
+        Exp::While { cond, body, .. } => {
+            used_names(cond, acc);
+            used_names(body, acc);
+        }
+        Exp::Try { body, handler, .. } => {
+            used_names(body, acc);
+            used_names(handler, acc);
+        }
+        Exp::DirectCall(f, args, _) => {
+            acc.insert(f.clone());
+            for arg in args {
+                used_names(arg, acc);
+            }
+        }
+        Exp::ClosureCall(func, args, _) | Exp::Call(func, args, _) => {
+            used_names(func, acc);
+            for arg in args {
+                used_names(arg, acc);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Drops the functions declared at the top level, e.g. by the prelude,
+// that the program never refers to, directly or through the functions
+// it uses
+fn drop_unused_functions(e: &Exp<()>) -> Exp<()> {
+    match e {
+        Exp::Let { bindings, body, .. } => Exp::Let {
+            bindings: bindings.clone(),
+            body: Box::new(drop_unused_functions(body)),
+            ann: (),
+        },
+        Exp::FunDefs { decls, body, .. } => {
+            let body = drop_unused_functions(body);
+            let mut used = HashSet::new();
+            used_names(&body, &mut used);
+            let mut kept = vec![false; decls.len()];
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for (i, decl) in decls.iter().enumerate() {
+                    if !kept[i] && used.contains(&decl.name) {
+                        kept[i] = true;
+                        changed = true;
+                        for default in &decl.defaults {
+                            used_names(default, &mut used);
+                        }
+                        used_names(&decl.body, &mut used);
+                    }
+                }
+            }
+            let decls: Vec<_> = decls
+                .iter()
+                .zip(kept)
+                .filter(|(_, kept)| *kept)
+                .map(|(decl, _)| decl.clone())
+                .collect();
+            if decls.is_empty() {
+                body
+            } else {
+                Exp::FunDefs {
+                    decls,
+                    body: Box::new(body),
+                    ann: (),
+                }
+            }
+        }
+        _ => e.clone(),
+    }
+}
+
+// Variables that lift_functions copies into the environment of a
+// closure or into the extra parameters of a lifted function, i.e.
+// everything in scope of a lambda or of a lifted definition
+fn captured_vars(
+    e: &Exp<()>,
+    vars: &HashSet<String>,
+    need_lift: &HashSet<String>,
+    acc: &mut HashSet<String>,
+) {
+    match e {
+        Exp::Assign { value, .. } => captured_vars(value, vars, need_lift, acc),
+        Exp::Prim(_, exps, _) => {
+            for exp in exps {
+                captured_vars(exp, vars, need_lift, acc);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            let mut scoped_vars = vars.clone();
+            for (v, bind) in bindings {
+                captured_vars(bind, &scoped_vars, need_lift, acc);
+                scoped_vars.insert(v.clone());
+            }
+            captured_vars(body, &scoped_vars, need_lift, acc);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            captured_vars(cond, vars, need_lift, acc);
+            captured_vars(thn, vars, need_lift, acc);
+            captured_vars(els, vars, need_lift, acc);
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                if need_lift.contains(&decl.name) {
+                    acc.extend(vars.iter().cloned());
+                }
+                captured_vars(&decl.body, vars, need_lift, acc);
+            }
+            captured_vars(body, vars, need_lift, acc);
+        }
+        Exp::Lambda { body, .. } => {
+            acc.extend(vars.iter().cloned());
+            captured_vars(body, vars, need_lift, acc);
+        }
+        Exp::While { cond, body, .. } => {
+            captured_vars(cond, vars, need_lift, acc);
+            captured_vars(body, vars, need_lift, acc);
+        }
+        Exp::Try {
+            body, var, handler, ..
+        } => {
+            captured_vars(body, vars, need_lift, acc);
+            let mut scoped_vars = vars.clone();
+            scoped_vars.insert(var.clone());
+            captured_vars(handler, &scoped_vars, need_lift, acc);
+        }
+        Exp::DirectCall(_, args, _) => {
+            for arg in args {
+                captured_vars(arg, vars, need_lift, acc);
+            }
+        }
+        Exp::ClosureCall(func, args, _) => {
+            captured_vars(func, vars, need_lift, acc);
+            for arg in args {
+                captured_vars(arg, vars, need_lift, acc);
+            }
+        }
+        _ => (),
+    }
+}
+
 // returns name of functions to lift
 fn should_lift(p: &Exp<()>, funcs: &HashSet<String>, is_tail: bool) -> HashSet<String> {
     let mut set = HashSet::new();
@@ -484,19 +1599,22 @@ fn should_lift(p: &Exp<()>, funcs: &HashSet<String>, is_tail: bool) -> HashSet<S
                 set.extend(should_lift(param, funcs, false));
             }
         }
-        Exp::Lambda {
-            parameters,
-            body,
-            ann,
-        } => {
-            set.extend(should_lift(body, funcs, true));
+        Exp::Lambda { body, .. } => {
+            // the lambda becomes its own function, so nothing it calls is a tail call here
+            set.extend(should_lift(body, funcs, false));
         }
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => todo!(),
+        Exp::Assign { value, .. } => {
+            set.extend(should_lift(value, funcs, false));
+        }
+        Exp::While { cond, body, .. } => {
+            set.extend(should_lift(cond, funcs, false));
+            set.extend(should_lift(body, funcs, false));
+        }
+        Exp::Try { body, handler, .. } => {
+            set.extend(should_lift(body, funcs, false));
+            set.extend(should_lift(handler, funcs, is_tail));
+        }
+        Exp::MakeClosure { .. } => todo!(),
         Exp::Call(_, _, _) => todo!(),
         Exp::InternalTailCall(_, _, _) => todo!(),
         Exp::ExternalCall {
@@ -510,7 +1628,63 @@ fn should_lift(p: &Exp<()>, funcs: &HashSet<String>, is_tail: bool) -> HashSet<S
     set
 }
 
-fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
+// What eliminate_closures knows about a function in scope to match the
+// arguments of a direct call with its parameters
+#[derive(Clone)]
+struct Signature {
+    names: Vec<String>, // in the source, of the parameters before the rest parameter
+    defaults: Vec<Exp<()>>,
+    variadic: bool,
+}
+
+// The arguments of a direct call in the order of the parameters: the
+// default values of the ones left out are filled in, and the extra
+// arguments of a variadic function are passed as an array. None if the
+// arguments don't match the parameters, the call is then made through a
+// closure, which reports what is wrong when it's called
+fn direct_args(
+    sig: &Signature,
+    mut args: Vec<Exp<()>>,
+    keywords: &[(String, Exp<()>)],
+) -> Option<Vec<Exp<()>>> {
+    let fixed = sig.names.len();
+    let mut rest = vec![];
+    if args.len() > fixed {
+        if !sig.variadic {
+            return None;
+        }
+        rest = args.split_off(fixed);
+    }
+    let mut slots: Vec<_> = args.into_iter().map(Some).collect();
+    slots.resize(fixed, None);
+    for (x, e) in keywords {
+        let i = sig.names.iter().position(|name| name == x)?;
+        if slots[i].replace(e.clone()).is_some() {
+            return None;
+        }
+    }
+    let required = fixed - sig.defaults.len();
+    let mut res = vec![];
+    for (i, slot) in slots.into_iter().enumerate() {
+        match slot {
+            Some(e) => res.push(e),
+            None if i >= required => res.push(sig.defaults[i - required].clone()),
+            None => return None,
+        }
+    }
+    if sig.variadic {
+        res.push(Exp::Prim(Prim::MakeArray, rest.into_iter().map(Box::new).collect(), ()));
+    }
+    Some(res)
+}
+
+// [funcs] are the functions in scope. The keyword arguments of a closure
+// call are passed last, in a record of the shape of their names
+fn eliminate_closures(
+    e: &Exp<()>,
+    funcs: &HashMap<String, Signature>,
+    tables: &mut Tables,
+) -> Exp<()> {
     match e {
         Exp::Num(_, _) => e.clone(),
         Exp::Bool(_, _) => e.clone(),
@@ -518,7 +1692,7 @@ fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
         Exp::Prim(p, exps, _) => Exp::Prim(
             p.clone(),
             exps.iter()
-                .map(|exp| Box::new(eliminate_closures(exp, funcs)))
+                .map(|exp| Box::new(eliminate_closures(exp, funcs, tables)))
                 .collect(),
             (),
         ),
@@ -529,9 +1703,9 @@ fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
         } => Exp::Let {
             bindings: bindings
                 .iter()
-                .map(|bind| (bind.0.clone(), eliminate_closures(&bind.1, funcs)))
+                .map(|bind| (bind.0.clone(), eliminate_closures(&bind.1, funcs, tables)))
                 .collect(),
-            body: Box::new(eliminate_closures(&body, funcs)),
+            body: Box::new(eliminate_closures(&body, funcs, tables)),
             ann: (),
         },
         Exp::If {
@@ -540,16 +1714,50 @@ fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
             els,
             ann,
         } => Exp::If {
-            cond: Box::new(eliminate_closures(&cond, funcs)),
-            thn: Box::new(eliminate_closures(&thn, funcs)),
-            els: Box::new(eliminate_closures(els, funcs)),
+            cond: Box::new(eliminate_closures(&cond, funcs, tables)),
+            thn: Box::new(eliminate_closures(&thn, funcs, tables)),
+            els: Box::new(eliminate_closures(els, funcs, tables)),
             ann: (),
         },
         Exp::Semicolon { e1, e2, ann } => todo!(), // already eliminated
+        // lowered by uniquify
+        Exp::ForRange { .. }
+        | Exp::ForEach { .. }
+        | Exp::Match { .. }
+        | Exp::DataDefs { .. }
+        | Exp::Record { .. }
+        | Exp::GetField { .. }
+        | Exp::SetField { .. } => todo!(),
+        Exp::LetPattern { .. } => unreachable!("rewritten into a Let by desugar"),
+        Exp::Assign { var, value, .. } => Exp::Assign {
+            var: var.clone(),
+            value: Box::new(eliminate_closures(value, funcs, tables)),
+            ann: (),
+        },
+        Exp::While { cond, body, .. } => Exp::While {
+            cond: Box::new(eliminate_closures(cond, funcs, tables)),
+            body: Box::new(eliminate_closures(body, funcs, tables)),
+            ann: (),
+        },
+        Exp::Try {
+            body, var, handler, ..
+        } => Exp::Try {
+            body: Box::new(eliminate_closures(body, funcs, tables)),
+            var: var.clone(),
+            handler: Box::new(eliminate_closures(handler, funcs, tables)),
+            ann: (),
+        },
         Exp::FunDefs { decls, body, ann } => {
             let mut scoped_funcs = funcs.clone();
             for decl in decls {
-                scoped_funcs.insert(decl.name.clone());
+                let fixed = decl.parameters.len() - decl.variadic as usize;
+                let names = decl.parameters[..fixed].iter().map(|p| tables.params[p].clone());
+                let sig = Signature {
+                    names: names.collect(),
+                    defaults: decl.defaults.clone(),
+                    variadic: decl.variadic,
+                };
+                scoped_funcs.insert(decl.name.clone(), sig);
             }
             Exp::FunDefs {
                 decls: decls
@@ -557,41 +1765,40 @@ fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
                     .map(|decl| FunDecl {
                         name: decl.name.clone(),
                         parameters: decl.parameters.clone(),
-                        body: eliminate_closures(&decl.body, &scoped_funcs),
+                        variadic: decl.variadic,
+                        defaults: decl.defaults.clone(),
+                        param_types: vec![],
+                        result_type: None,
+                        doc: None,
+                        body: eliminate_closures(&decl.body, &scoped_funcs, tables),
                         ann: (),
                     })
                     .collect(),
-                body: Box::new(eliminate_closures(body, &scoped_funcs)),
+                body: Box::new(eliminate_closures(body, &scoped_funcs, tables)),
                 ann: (),
             }
         }
         Exp::Lambda {
             parameters,
+            variadic,
+            defaults,
             body,
             ann,
         } => Exp::Lambda {
             parameters: parameters.clone(),
-            body: Box::new(eliminate_closures(&body, funcs)),
+            variadic: *variadic,
+            defaults: defaults.clone(),
+            body: Box::new(eliminate_closures(&body, funcs, tables)),
             ann: (),
         },
-        Exp::MakeClosure {
-            arity,
-            label,
-            env,
-            ann,
-        } => todo!(),
-        Exp::Call(v, params, _) => {
-            let new_params = params
-                .iter()
-                .map(|param| eliminate_closures(param, funcs))
-                .collect();
-            if let Exp::Var(func, _) = *v.clone() {
-                if funcs.contains(&func) {
-                    return Exp::DirectCall(func, new_params, ());
-                }
-            }
-            Exp::ClosureCall(Box::new(eliminate_closures(&v, funcs)), new_params, ())
-        }
+        Exp::MakeClosure { .. } => todo!(),
+        Exp::Call(fun, args, _) => eliminate_call(fun, args, &[], funcs, tables),
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ..
+        } => eliminate_call(fun, args, keywords, funcs, tables),
         Exp::ClosureCall(_, _, _) => todo!(),
         Exp::DirectCall(_, _, _) => todo!(),
         Exp::InternalTailCall(_, _, _) => todo!(),
@@ -604,11 +1811,52 @@ fn eliminate_closures(e: &Exp<()>, funcs: &HashSet<String>) -> Exp<()> {
     }
 }
 
+fn eliminate_call(
+    fun: &Exp<()>,
+    args: &[Exp<()>],
+    keywords: &[(String, Exp<()>)],
+    funcs: &HashMap<String, Signature>,
+    tables: &mut Tables,
+) -> Exp<()> {
+    let mut args: Vec<_> = args.iter().map(|arg| eliminate_closures(arg, funcs, tables)).collect();
+    let keywords: Vec<_> = keywords
+        .iter()
+        .map(|(x, e)| (x.clone(), eliminate_closures(e, funcs, tables)))
+        .collect();
+    if let Exp::Var(func, _) = fun {
+        let direct = funcs.get(func).and_then(|sig| direct_args(sig, args.clone(), &keywords));
+        if let Some(direct) = direct {
+            return Exp::DirectCall(func.clone(), direct, ());
+        }
+    }
+    if !keywords.is_empty() {
+        let ids = keywords.iter().map(|(x, _)| tables.field_id(x)).collect();
+        let shape = tables.shape_id(ids);
+        let values = keywords.into_iter().map(|(_, e)| Box::new(e)).collect();
+        args.push(Exp::Prim(Prim::MakeKeywords(shape), values, ()));
+    }
+    Exp::ClosureCall(Box::new(eliminate_closures(fun, funcs, tables)), args, ())
+}
+
+// The global functions, the main expression and the tables of the
+// constructors, fields and record shapes of the program
+type Lifted = (Vec<FunDecl<Exp<()>, ()>>, Exp<()>, Tables);
+
 // Lift some functions to global definitions
-pub fn lambda_lift<Ann>(p: &Exp<Ann>) -> (Vec<FunDecl<Exp<()>, ()>>, Exp<()>) {
-    let mut unique_p = uniquify(&p, &mut HashMap::new(), &mut 0);
+pub fn lambda_lift<Ann>(p: &Exp<Ann>) -> Lifted {
+    let mut tables = Tables::default();
+    // constructor 0 is the one of runtime errors, which only the runtime
+    // builds. Its name is its unique name, only patterns refer to it
+    let error = String::from(RUNTIME_ERROR_CTOR);
+    tables.ctors.push((error.clone(), runtime_error_ctor()));
+    let mapping = HashMap::from([(error.clone(), error)]);
+    let mut unique_p = uniquify(&p, &mapping, &mut 0, &mut tables);
     println!("after uniquify: {:#?}", unique_p);
-    unique_p = eliminate_closures(&unique_p, &HashSet::new());
+    let mut assigned = HashSet::new();
+    assigned_vars(&unique_p, &mut assigned);
+    unique_p = resolve_fields(&unique_p, &mut HashMap::new(), &assigned, &tables);
+    unique_p = eliminate_closures(&unique_p, &HashMap::new(), &mut tables);
+    unique_p = drop_unused_functions(&unique_p);
     let mut globals = HashMap::new();
     let to_lift = should_lift(&unique_p, &HashSet::new(), true);
     println!(
@@ -616,18 +1864,36 @@ pub fn lambda_lift<Ann>(p: &Exp<Ann>) -> (Vec<FunDecl<Exp<()>, ()>>, Exp<()>) {
         to_lift.len(),
         to_lift
     );
-    let main = lift_functions(&unique_p, &HashSet::new(), &mut globals, &to_lift);
+    // assigned variables that are captured live in a one-element array
+    let mut captured = HashSet::new();
+    captured_vars(&unique_p, &HashSet::new(), &to_lift, &mut captured);
+    let boxed = assigned.intersection(&captured).cloned().collect();
+    let main = lift_functions(
+        &unique_p,
+        &HashSet::new(),
+        &mut globals,
+        &to_lift,
+        &boxed,
+        &mut tables,
+        &mut HashMap::new(),
+    );
     (
         globals
             .values()
             .map(|decl| FunDecl {
                 name: decl.name.clone(),
                 parameters: decl.parameters.clone(),
+                variadic: decl.variadic,
+                defaults: decl.defaults.clone(),
+                param_types: vec![],
+                result_type: None,
+                doc: None,
                 body: rewrite_call_params(&decl.body, &globals, true),
                 ann: (),
             })
             .collect(),
         rewrite_call_params(&main, &globals, true),
+        tables,
     )
     // TODO: add parameter optimization pass
 }
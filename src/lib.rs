@@ -2,10 +2,17 @@ pub mod asm;
 pub mod compile;
 pub mod interp;
 pub mod parser;
+pub mod comments;
 pub mod runner;
 pub mod span;
 pub mod syntax;
 pub mod lambda_lift;
 pub mod checker;
+pub mod desugar;
+pub mod modules;
+pub mod typecheck;
 pub mod sequentializer;
 pub mod error_handler;
+pub mod generator;
+pub mod thread;
+pub mod bignum;
@@ -14,13 +14,22 @@ To run the reference interpreter use
 
     snake --interp INPUT_FILE
 
+Any of these can be preceded by --typecheck to infer the types of the
+program first and fail on a type error, e.g.
+
+    snake --typecheck --run INPUT_FILE
+
 To see this usage message run
 
     snake --help
 ";
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 && args[1] == "--typecheck" {
+        args.remove(1);
+        check_types(Path::new(&args[args.len() - 1]));
+    }
     if args.len() == 2 {
         match args[1].as_str() {
             "--interp" | "--run" => usage(Some("interp and run comands require an input file")),
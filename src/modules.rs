@@ -0,0 +1,514 @@
+use crate::compile::CompileErr;
+use crate::syntax::{Constructor, Exp, FunDecl, Import, Pattern, SurfProg};
+use std::collections::{HashMap, HashSet};
+
+/* A program is its main file and the modules it imports, directly or
+ * not. They are linked into one program: the top-level declarations of
+ * each module wrap the modules that import it and, innermost, the main
+ * program. The body of a module is dropped. The names a module declares
+ * at the top level are qualified with the name of the module, so a module
+ * only sees the names it imports. That includes the constructors of its
+ * top-level data declarations, which are printed without the module.
+ *
+ * The first module is the prelude. Every other module sees its names
+ * without importing them, unless it declares or imports the same names.
+ * A for over a call to the prelude's range is a for over the range, so
+ * it doesn't build the array. A range declared elsewhere is an ordinary
+ * function.
+ */
+pub struct Module<Ann> {
+    pub name: String,
+    // with the index of the module each import refers to
+    pub imports: Vec<(usize, Import<Ann>)>,
+    pub prog: SurfProg<Ann>,
+}
+
+// The qualified name of range in the prelude
+static PRELUDE_RANGE: &str = "prelude::range";
+
+// [modules] are in dependency order, a module only imports the ones
+// before it. The first one is the prelude and the last one is the main
+// program
+pub fn link<Ann>(modules: &[Module<Ann>]) -> Result<SurfProg<Ann>, CompileErr<Ann>>
+where
+    Ann: Clone,
+{
+    let exports: Vec<HashMap<String, String>> = modules
+        .iter()
+        .map(|m| {
+            let mut names = vec![];
+            top_level(&m.prog, &mut names);
+            names
+                .into_iter()
+                .map(|x| (x.clone(), format!("{}::{}", m.name, x)))
+                .collect()
+        })
+        .collect();
+    let mut linked = None;
+    for (i, m) in modules.iter().enumerate().rev() {
+        let mut scope = if i == 0 {
+            HashMap::new()
+        } else {
+            exports[0].clone()
+        };
+        let mut imported = HashSet::new();
+        for (j, import) in &m.imports {
+            for name in &import.names {
+                let qualified = match exports[*j].get(name) {
+                    Some(qualified) => qualified.clone(),
+                    None => {
+                        return Err(CompileErr::UnknownImport {
+                            name: name.clone(),
+                            module: import.path.clone(),
+                            location: import.ann.clone(),
+                        })
+                    }
+                };
+                scope.insert(name.clone(), qualified);
+                if !imported.insert(name.clone()) {
+                    return Err(CompileErr::DuplicateImport {
+                        name: name.clone(),
+                        location: import.ann.clone(),
+                    });
+                }
+            }
+        }
+        linked = Some(match linked {
+            None => rename(&m.prog, &scope),
+            Some(inner) => qualify(&m.prog, &mut scope, &exports[i], inner),
+        });
+    }
+    Ok(linked.expect("a program has a main module"))
+}
+
+// The declarations a module starts with, up to its body
+fn top_level<Ann>(e: &Exp<Ann>, names: &mut Vec<String>) {
+    match e {
+        Exp::Let { bindings, body, .. } => {
+            names.extend(bindings.iter().map(|(x, _)| x.clone()));
+            top_level(body, names)
+        }
+        Exp::LetPattern { bindings, body, .. } => {
+            for (p, _) in bindings {
+                pattern_vars(p, names);
+            }
+            top_level(body, names)
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            names.extend(decls.iter().map(|d| d.name.clone()));
+            top_level(body, names)
+        }
+        Exp::DataDefs { ctors, body, .. } => {
+            names.extend(ctors.iter().map(|c| c.name.clone()));
+            top_level(body, names)
+        }
+        Exp::Semicolon { e2, .. } => top_level(e2, names),
+        _ => (),
+    }
+}
+
+fn pattern_vars(p: &Pattern, vars: &mut Vec<String>) {
+    match p {
+        Pattern::Var(x) => vars.push(x.clone()),
+        Pattern::Array(ps) | Pattern::Constructor(_, ps) => {
+            for p in ps {
+                pattern_vars(p, vars);
+            }
+        }
+        Pattern::Num(_) | Pattern::Bool(_) | Pattern::Wildcard => (),
+    }
+}
+
+// Renames the variables of a top-level pattern to their qualified names
+// in [exports] and its constructors to the ones in [scope]
+fn qualify_pattern(
+    p: &Pattern,
+    scope: &HashMap<String, String>,
+    exports: &HashMap<String, String>,
+) -> Pattern {
+    match p {
+        Pattern::Var(x) => Pattern::Var(exports[x].clone()),
+        Pattern::Array(ps) => Pattern::Array(
+            ps.iter()
+                .map(|p| qualify_pattern(p, scope, exports))
+                .collect(),
+        ),
+        Pattern::Constructor(c, ps) => Pattern::Constructor(
+            scope.get(c).unwrap_or(c).clone(),
+            ps.iter()
+                .map(|p| qualify_pattern(p, scope, exports))
+                .collect(),
+        ),
+        _ => p.clone(),
+    }
+}
+
+// Renames the constructors of [p] that are in [scope]
+fn rename_pattern(p: &Pattern, scope: &HashMap<String, String>) -> Pattern {
+    match p {
+        Pattern::Array(ps) => Pattern::Array(ps.iter().map(|p| rename_pattern(p, scope)).collect()),
+        Pattern::Constructor(c, ps) => Pattern::Constructor(
+            scope.get(c).unwrap_or(c).clone(),
+            ps.iter().map(|p| rename_pattern(p, scope)).collect(),
+        ),
+        _ => p.clone(),
+    }
+}
+
+// Renames the top-level declarations of a module to their qualified names
+// in [exports] and replaces its body with [inner]
+fn qualify<Ann>(
+    e: &Exp<Ann>,
+    scope: &mut HashMap<String, String>,
+    exports: &HashMap<String, String>,
+    inner: Exp<Ann>,
+) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    match e {
+        Exp::Let {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut new_bindings = vec![];
+            for (x, value) in bindings {
+                new_bindings.push((exports[x].clone(), rename(value, scope)));
+                scope.insert(x.clone(), exports[x].clone());
+            }
+            Exp::Let {
+                bindings: new_bindings,
+                body: Box::new(qualify(body, scope, exports, inner)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::LetPattern {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut new_bindings = vec![];
+            for (p, value) in bindings {
+                new_bindings.push((qualify_pattern(p, scope, exports), rename(value, scope)));
+                let mut vars = vec![];
+                pattern_vars(p, &mut vars);
+                for x in vars {
+                    scope.insert(x.clone(), exports[&x].clone());
+                }
+            }
+            Exp::LetPattern {
+                bindings: new_bindings,
+                body: Box::new(qualify(body, scope, exports, inner)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::FunDefs { decls, body, ann } => {
+            // the defaults are evaluated outside of the functions
+            let defaults: Vec<Vec<Exp<Ann>>> = decls
+                .iter()
+                .map(|d| d.defaults.iter().map(|e| rename(e, scope)).collect())
+                .collect();
+            for d in decls {
+                scope.insert(d.name.clone(), exports[&d.name].clone());
+            }
+            let decls = decls
+                .iter()
+                .zip(defaults)
+                .map(|(d, defaults)| FunDecl {
+                    name: exports[&d.name].clone(),
+                    defaults,
+                    body: rename(&d.body, &shadow(scope, &d.parameters)),
+                    ..d.clone()
+                })
+                .collect();
+            Exp::FunDefs {
+                decls,
+                body: Box::new(qualify(body, scope, exports, inner)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::DataDefs {
+            name,
+            ctors,
+            body,
+            ann,
+        } => {
+            for c in ctors {
+                scope.insert(c.name.clone(), exports[&c.name].clone());
+            }
+            let ctors = ctors
+                .iter()
+                .map(|c| Constructor {
+                    name: exports[&c.name].clone(),
+                    fields: c.fields.clone(),
+                })
+                .collect();
+            Exp::DataDefs {
+                name: name.clone(),
+                ctors,
+                body: Box::new(qualify(body, scope, exports, inner)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: Box::new(rename(e1, scope)),
+            e2: Box::new(qualify(e2, scope, exports, inner)),
+            ann: ann.clone(),
+        },
+        _ => inner,
+    }
+}
+
+// [scope] without the variables [xs] bound inside of it
+fn shadow(scope: &HashMap<String, String>, xs: &[String]) -> HashMap<String, String> {
+    let mut scope = scope.clone();
+    for x in xs {
+        scope.remove(x);
+    }
+    scope
+}
+
+// Renames the free variables of [e] that are in [scope]
+fn rename<Ann>(e: &Exp<Ann>, scope: &HashMap<String, String>) -> Exp<Ann>
+where
+    Ann: Clone,
+{
+    let go = |e: &Exp<Ann>| Box::new(rename(e, scope));
+    match e {
+        Exp::Num(..) | Exp::Bool(..) => e.clone(),
+        Exp::Var(x, ann) => Exp::Var(scope.get(x).unwrap_or(x).clone(), ann.clone()),
+        Exp::Prim(op, es, ann) => Exp::Prim(*op, es.iter().map(|e| go(e)).collect(), ann.clone()),
+        Exp::Let {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut scope = scope.clone();
+            let bindings = bindings
+                .iter()
+                .map(|(x, value)| {
+                    let value = rename(value, &scope);
+                    scope.remove(x);
+                    (x.clone(), value)
+                })
+                .collect();
+            Exp::Let {
+                bindings,
+                body: Box::new(rename(body, &scope)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::LetPattern {
+            bindings,
+            body,
+            ann,
+        } => {
+            let mut scope = scope.clone();
+            let bindings = bindings
+                .iter()
+                .map(|(p, value)| {
+                    let value = rename(value, &scope);
+                    let p = rename_pattern(p, &scope);
+                    let mut vars = vec![];
+                    pattern_vars(&p, &mut vars);
+                    scope = shadow(&scope, &vars);
+                    (p, value)
+                })
+                .collect();
+            Exp::LetPattern {
+                bindings,
+                body: Box::new(rename(body, &scope)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::If {
+            cond,
+            thn,
+            els,
+            ann,
+        } => Exp::If {
+            cond: go(cond),
+            thn: go(thn),
+            els: go(els),
+            ann: ann.clone(),
+        },
+        Exp::Semicolon { e1, e2, ann } => Exp::Semicolon {
+            e1: go(e1),
+            e2: go(e2),
+            ann: ann.clone(),
+        },
+        Exp::Assign { var, value, ann } => Exp::Assign {
+            var: scope.get(var).unwrap_or(var).clone(),
+            value: go(value),
+            ann: ann.clone(),
+        },
+        Exp::While { cond, body, ann } => Exp::While {
+            cond: go(cond),
+            body: go(body),
+            ann: ann.clone(),
+        },
+        Exp::ForRange {
+            var,
+            start,
+            end,
+            body,
+            ann,
+        } => Exp::ForRange {
+            var: var.clone(),
+            start: go(start),
+            end: go(end),
+            body: Box::new(rename(body, &shadow(scope, std::slice::from_ref(var)))),
+            ann: ann.clone(),
+        },
+        Exp::ForEach {
+            var,
+            array,
+            body,
+            ann,
+        } => {
+            let body = Box::new(rename(body, &shadow(scope, std::slice::from_ref(var))));
+            match *go(array) {
+                Exp::Call(f, mut args, _)
+                    if matches!(*f, Exp::Var(ref x, _) if x == PRELUDE_RANGE)
+                        && args.len() == 2 =>
+                {
+                    let end = Box::new(args.pop().unwrap());
+                    let start = Box::new(args.pop().unwrap());
+                    Exp::ForRange {
+                        var: var.clone(),
+                        start,
+                        end,
+                        body,
+                        ann: ann.clone(),
+                    }
+                }
+                array => Exp::ForEach {
+                    var: var.clone(),
+                    array: Box::new(array),
+                    body,
+                    ann: ann.clone(),
+                },
+            }
+        }
+        Exp::Try {
+            body,
+            var,
+            handler,
+            ann,
+        } => Exp::Try {
+            body: go(body),
+            var: var.clone(),
+            handler: Box::new(rename(handler, &shadow(scope, std::slice::from_ref(var)))),
+            ann: ann.clone(),
+        },
+        Exp::Match {
+            scrutinee,
+            arms,
+            ann,
+        } => Exp::Match {
+            scrutinee: go(scrutinee),
+            arms: arms
+                .iter()
+                .map(|(p, e)| {
+                    let mut vars = vec![];
+                    pattern_vars(p, &mut vars);
+                    (rename_pattern(p, scope), rename(e, &shadow(scope, &vars)))
+                })
+                .collect(),
+            ann: ann.clone(),
+        },
+        Exp::FunDefs { decls, body, ann } => {
+            let names: Vec<String> = decls.iter().map(|d| d.name.clone()).collect();
+            let inner = shadow(scope, &names);
+            Exp::FunDefs {
+                decls: decls
+                    .iter()
+                    .map(|d| FunDecl {
+                        defaults: d.defaults.iter().map(|e| rename(e, scope)).collect(),
+                        body: rename(&d.body, &shadow(&inner, &d.parameters)),
+                        ..d.clone()
+                    })
+                    .collect(),
+                body: Box::new(rename(body, &inner)),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Record { fields, ann } => Exp::Record {
+            fields: fields
+                .iter()
+                .map(|(f, e)| (f.clone(), rename(e, scope)))
+                .collect(),
+            ann: ann.clone(),
+        },
+        Exp::GetField { record, field, ann } => Exp::GetField {
+            record: go(record),
+            field: field.clone(),
+            ann: ann.clone(),
+        },
+        Exp::SetField {
+            record,
+            field,
+            value,
+            ann,
+        } => Exp::SetField {
+            record: go(record),
+            field: field.clone(),
+            value: go(value),
+            ann: ann.clone(),
+        },
+        Exp::DataDefs {
+            name,
+            ctors,
+            body,
+            ann,
+        } => {
+            let names: Vec<String> = ctors.iter().map(|c| c.name.clone()).collect();
+            Exp::DataDefs {
+                name: name.clone(),
+                ctors: ctors.clone(),
+                body: Box::new(rename(body, &shadow(scope, &names))),
+                ann: ann.clone(),
+            }
+        }
+        Exp::Lambda {
+            parameters,
+            variadic,
+            defaults,
+            body,
+            ann,
+        } => Exp::Lambda {
+            parameters: parameters.clone(),
+            variadic: *variadic,
+            defaults: defaults.iter().map(|e| rename(e, scope)).collect(),
+            body: Box::new(rename(body, &shadow(scope, parameters))),
+            ann: ann.clone(),
+        },
+        Exp::Call(fun, args, ann) => Exp::Call(
+            go(fun),
+            args.iter().map(|e| rename(e, scope)).collect(),
+            ann.clone(),
+        ),
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ann,
+        } => Exp::KeywordCall {
+            fun: go(fun),
+            args: args.iter().map(|e| rename(e, scope)).collect(),
+            keywords: keywords
+                .iter()
+                .map(|(x, e)| (x.clone(), rename(e, scope)))
+                .collect(),
+            ann: ann.clone(),
+        },
+        Exp::MakeClosure { .. }
+        | Exp::ClosureCall(..)
+        | Exp::DirectCall(..)
+        | Exp::InternalTailCall(..)
+        | Exp::ExternalCall { .. } => {
+            panic!("Shouldn't happen: modules are linked before lambda lifting")
+        }
+    }
+}
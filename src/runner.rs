@@ -6,12 +6,18 @@ use std::process::{Command, Stdio};
 
 use std::fmt::{Display, Formatter};
 
+use crate::comments::strip;
 use crate::compile;
 use crate::compile::{compile_to_string, CompileErr};
+use crate::desugar::desugar_prog;
 use crate::interp;
 use crate::interp::InterpErr;
+use crate::modules::{link, Module};
 use crate::parser::ProgParser;
-use crate::syntax::SurfProg;
+use crate::syntax::{Exp, Import, SurfProg};
+use crate::typecheck::infer_prog;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
 mod span {
     use crate::span::{Span1, Span2};
@@ -19,12 +25,14 @@ mod span {
 
     #[derive(Clone, Debug)]
     pub struct FileInfo {
+        name: String,
         newlines: Vec<usize>,
         len: usize,
     }
 
-    pub fn file_info(s: &str) -> FileInfo {
+    pub fn file_info(name: &str, s: &str) -> FileInfo {
         FileInfo {
+            name: name.to_string(),
             newlines: s
                 .char_indices()
                 .filter(|(_i, c)| *c == '\n')
@@ -42,6 +50,7 @@ mod span {
         let (start_line, start_col) = offset_to_line_col(&v, offsets.start_ix);
         let (end_line, end_col) = offset_to_line_col(&v, offsets.end_ix - 1);
         Span2 {
+            file: info.name.clone(),
             start_line,
             start_col,
             end_line,
@@ -105,6 +114,96 @@ mod span {
                     duplicated_name,
                     location: f(&location),
                 },
+
+                CompileErr::AssignToNonLocal { name, location } => CompileErr::AssignToNonLocal {
+                    name,
+                    location: f(&location),
+                },
+                CompileErr::DuplicatePatternVar {
+                    duplicated_name,
+                    location,
+                } => CompileErr::DuplicatePatternVar {
+                    duplicated_name,
+                    location: f(&location),
+                },
+                CompileErr::DuplicateConstructor {
+                    duplicated_name,
+                    location,
+                } => CompileErr::DuplicateConstructor {
+                    duplicated_name,
+                    location: f(&location),
+                },
+                CompileErr::DuplicateField {
+                    duplicated_name,
+                    location,
+                } => CompileErr::DuplicateField {
+                    duplicated_name,
+                    location: f(&location),
+                },
+                CompileErr::UnknownConstructor { name, location } => {
+                    CompileErr::UnknownConstructor {
+                        name,
+                        location: f(&location),
+                    }
+                }
+                CompileErr::ConstructorArity {
+                    name,
+                    expected,
+                    got,
+                    location,
+                } => CompileErr::ConstructorArity {
+                    name,
+                    expected,
+                    got,
+                    location: f(&location),
+                },
+                CompileErr::UnknownImport {
+                    name,
+                    module,
+                    location,
+                } => CompileErr::UnknownImport {
+                    name,
+                    module,
+                    location: f(&location),
+                },
+                CompileErr::DuplicateImport { name, location } => CompileErr::DuplicateImport {
+                    name,
+                    location: f(&location),
+                },
+                CompileErr::ImportCycle { module, location } => CompileErr::ImportCycle {
+                    module,
+                    location: f(&location),
+                },
+                CompileErr::TypeMismatch {
+                    expected,
+                    found,
+                    location,
+                } => CompileErr::TypeMismatch {
+                    expected,
+                    found,
+                    location: f(&location),
+                },
+                CompileErr::InfiniteType { var, ty, location } => CompileErr::InfiniteType {
+                    var,
+                    ty,
+                    location: f(&location),
+                },
+                CompileErr::Untypable {
+                    construct,
+                    location,
+                } => CompileErr::Untypable {
+                    construct,
+                    location: f(&location),
+                },
+                CompileErr::AnnotationMismatch {
+                    expected,
+                    found,
+                    location,
+                } => CompileErr::AnnotationMismatch {
+                    expected,
+                    found,
+                    location: f(&location),
+                },
             }
         }
     }
@@ -113,8 +212,8 @@ mod span {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             write!(
                 f,
-                "line {}, column {} to line {}, column {}",
-                self.start_line, self.start_col, self.end_line, self.end_col
+                "line {}, column {} to line {}, column {} of {}",
+                self.start_line, self.start_col, self.end_line, self.end_col, self.file
             )
         }
     }
@@ -179,6 +278,95 @@ where
                 "multiple defined functions named \"{}\" at {}",
                 duplicated_name, location
             ),
+
+            CompileErr::AssignToNonLocal { name, location } => write!(
+                f,
+                "cannot assign to \"{}\" at {}, only let-bound variables can be assigned",
+                name, location
+            ),
+            CompileErr::DuplicatePatternVar {
+                duplicated_name,
+                location,
+            } => write!(
+                f,
+                "Variable {} bound twice in one pattern at {}",
+                duplicated_name, location
+            ),
+            CompileErr::DuplicateConstructor {
+                duplicated_name,
+                location,
+            } => write!(
+                f,
+                "multiple constructors named \"{}\" at {}",
+                duplicated_name, location
+            ),
+            CompileErr::DuplicateField {
+                duplicated_name,
+                location,
+            } => write!(
+                f,
+                "multiple fields named \"{}\" in record at {}",
+                duplicated_name, location
+            ),
+            CompileErr::UnknownConstructor { name, location } => {
+                write!(f, "Unknown constructor {} in pattern at {}", name, location)
+            }
+            CompileErr::ConstructorArity {
+                name,
+                expected,
+                got,
+                location,
+            } => write!(
+                f,
+                "constructor {} expects {} fields but the pattern has {} at {}",
+                name, expected, got, location
+            ),
+            CompileErr::UnknownImport {
+                name,
+                module,
+                location,
+            } => write!(
+                f,
+                "module \"{}\" doesn't declare {} at the top level, imported at {}",
+                module, name, location
+            ),
+            CompileErr::DuplicateImport { name, location } => {
+                write!(f, "{} imported twice at {}", name, location)
+            }
+            CompileErr::ImportCycle { module, location } => write!(
+                f,
+                "importing \"{}\" at {} makes a cycle of imports",
+                module, location
+            ),
+            CompileErr::TypeMismatch {
+                expected,
+                found,
+                location,
+            } => write!(
+                f,
+                "expected type {} but found {} at {}",
+                expected, found, location
+            ),
+            CompileErr::InfiniteType { var, ty, location } => {
+                write!(f, "infinite type {} = {} at {}", var, ty, location)
+            }
+            CompileErr::Untypable {
+                construct,
+                location,
+            } => write!(
+                f,
+                "the typechecker doesn't support {}, used at {}",
+                construct, location
+            ),
+            CompileErr::AnnotationMismatch {
+                expected,
+                found,
+                location,
+            } => write!(
+                f,
+                "the annotation {} doesn't hold for a value of type {} at {}",
+                expected, found, location
+            ),
         }
     }
 }
@@ -240,13 +428,20 @@ where
     }
 }
 
+// Fails if the program in [p] doesn't typecheck
+pub fn check_types(p: &Path) {
+    if let Err(e) = typecheck_file(p) {
+        fail(e)
+    }
+}
+
 pub fn interpret_file<W>(p: &Path, w: &mut W) -> Result<(), RunnerErr<Span2>>
 where
     W: std::io::Write,
 {
-    let (info, prog) = parse_file(p)?;
+    let (files, prog) = parse_file(p)?;
     compile::check_prog(&prog)
-        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&info, *s))))?;
+        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&files[s.file], *s))))?;
 
     interp::prog(&prog, w).map_err(|e| RunnerErr::Interp(e))?;
     Ok(())
@@ -260,10 +455,38 @@ where
     link_and_run(&asm, dir, out)
 }
 
-fn compile_file(p: &Path) -> Result<String, RunnerErr<Span2>> {
-    let (info, prog) = parse_file(p)?;
+// The type inferred for the program in [p], see typecheck
+pub fn typecheck_file(p: &Path) -> Result<String, RunnerErr<Span2>> {
+    let (files, prog) = load_file(p)?;
+    let t = infer_prog(&prog)
+        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&files[s.file], *s))))?;
+    Ok(t.to_string())
+}
+
+// The assembly of the program in [p]
+pub fn compile_file(p: &Path) -> Result<String, RunnerErr<Span2>> {
+    let (files, prog) = parse_file(p)?;
     compile_to_string(&prog)
-        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&info, *s))))
+        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&files[s.file], *s))))
+}
+
+// The doc comments of the functions the program in [p] defines at its top
+// level, by name
+pub fn docs_file(p: &Path) -> Result<Vec<(String, String)>, RunnerErr<Span2>> {
+    let s = read_file(p)?;
+    let info = file_info(&p.display().to_string(), &s);
+    let (_, mut e) = parse_source(0, &info, &s).map_err(RunnerErr::Parse)?;
+    let mut docs = vec![];
+    loop {
+        e = match e {
+            Exp::FunDefs { decls, body, .. } => {
+                docs.extend(decls.into_iter().filter_map(|d| Some((d.name, d.doc?))));
+                *body
+            }
+            Exp::DataDefs { body, .. } => *body,
+            _ => return Ok(docs),
+        }
+    }
 }
 
 fn read_file<Span>(p: &Path) -> Result<String, RunnerErr<Span>> {
@@ -274,12 +497,140 @@ fn read_file<Span>(p: &Path) -> Result<String, RunnerErr<Span>> {
     Ok(buf)
 }
 
-fn parse_file(p: &Path) -> Result<(FileInfo, SurfProg<Span1>), RunnerErr<Span2>> {
-    let s = read_file(p)?;
-    let e = ProgParser::new()
-        .parse(&s)
-        .map_err(|e| RunnerErr::Parse(e.to_string()))?;
-    Ok((file_info(&s), e))
+// Parses [p] and the modules it imports, directly or not, into one
+// desugared program. Returns the files of the modules in the order of
+// their ids
+fn parse_file(p: &Path) -> Result<(Vec<FileInfo>, SurfProg<Span1>), RunnerErr<Span2>> {
+    let (files, e) = load_file(p)?;
+    let location = |s: &Span1| {
+        let s = span1_to_span2(&files[s.file], *s);
+        (s.start_line, s.start_col)
+    };
+    let e = desugar_prog(&e, &location);
+    Ok((files, e))
+}
+
+// Like parse_file, but the program isn't desugared yet
+fn load_file(p: &Path) -> Result<(Vec<FileInfo>, SurfProg<Span1>), RunnerErr<Span2>> {
+    let mut loader = Loader {
+        files: vec![],
+        modules: vec![],
+        loaded: HashMap::new(),
+        importing: vec![],
+    };
+    loader.load_prelude();
+    loader.load(p)?;
+    let Loader { files, modules, .. } = loader;
+    let e = link(&modules)
+        .map_err(|e| RunnerErr::CodeGen(e.map_span(|s| span1_to_span2(&files[s.file], *s))))?;
+    Ok((files, e))
+}
+
+// Parses the source [s] of the file with id [file] and [info], comments
+// included
+fn parse_source(
+    file: usize,
+    info: &FileInfo,
+    s: &str,
+) -> Result<(Vec<Import<Span1>>, SurfProg<Span1>), String> {
+    let stripped = strip(s).map_err(|ix| {
+        let span = Span1 {
+            file,
+            start_ix: ix,
+            end_ix: ix + 2,
+        };
+        let s = span1_to_span2(info, span);
+        format!(
+            "Unterminated block comment starting at line {}, column {}",
+            s.start_line, s.start_col
+        )
+    })?;
+    ProgParser::new()
+        .parse(file, &stripped.docs, &stripped.source)
+        .map_err(|e| e.to_string())
+}
+
+struct Loader {
+    files: Vec<FileInfo>,
+    // in dependency order
+    modules: Vec<Module<Span1>>,
+    // the index of each module that was loaded, by canonical path
+    loaded: HashMap<PathBuf, usize>,
+    // the modules whose imports are being loaded
+    importing: Vec<PathBuf>,
+}
+
+// The functions every program sees without importing them
+const PRELUDE: &str = include_str!("prelude.snake");
+
+impl Loader {
+    // Loads the prelude, which is the first module
+    fn load_prelude(&mut self) {
+        let info = file_info("<prelude>", PRELUDE);
+        let (_, prog) = parse_source(self.files.len(), &info, PRELUDE).expect("the prelude parses");
+        self.files.push(info);
+        self.modules.push(Module {
+            name: String::from("prelude"),
+            imports: vec![],
+            prog,
+        });
+    }
+
+    // Loads the module at [p] after the modules it imports, once. Returns
+    // its index
+    fn load(&mut self, p: &Path) -> Result<usize, RunnerErr<Span2>> {
+        let canonical = p
+            .canonicalize()
+            .map_err(|e| RunnerErr::FileOpen(format!("{}: {}", p.display(), e)))?;
+        if let Some(i) = self.loaded.get(&canonical) {
+            return Ok(*i);
+        }
+        let s = read_file(p)?;
+        let info = file_info(&p.display().to_string(), &s);
+        let (imports, prog) = parse_source(self.files.len(), &info, &s).map_err(|e| {
+            if self.importing.is_empty() {
+                RunnerErr::Parse(e)
+            } else {
+                RunnerErr::Parse(format!("{}: {}", p.display(), e))
+            }
+        })?;
+        self.files.push(info);
+        self.importing.push(canonical.clone());
+        let dir = p.parent().unwrap_or_else(|| Path::new(""));
+        let mut resolved = vec![];
+        for import in imports {
+            let path = dir.join(&import.path);
+            if let Ok(dep) = path.canonicalize() {
+                if self.importing.contains(&dep) {
+                    let e = CompileErr::ImportCycle {
+                        module: import.path,
+                        location: import.ann,
+                    };
+                    return Err(RunnerErr::CodeGen(
+                        e.map_span(|s| span1_to_span2(&self.files[s.file], *s)),
+                    ));
+                }
+            }
+            resolved.push((self.load(&path)?, import));
+        }
+        self.importing.pop();
+        // the names of the modules qualify their top-level names
+        let stem = p
+            .file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        let name = if self.modules.iter().any(|m| m.name == stem) {
+            format!("{}#{}", stem, self.modules.len())
+        } else {
+            stem
+        };
+        self.modules.push(Module {
+            name,
+            imports: resolved,
+            prog,
+        });
+        self.loaded.insert(canonical, self.modules.len() - 1);
+        Ok(self.modules.len() - 1)
+    }
 }
 
 fn link_and_run<W>(assembly: &str, dir: &Path, out: &mut W) -> Result<(), RunnerErr<Span2>>
@@ -6,10 +6,14 @@ fn parse_param_exps(
     let mut let_bindings = vec![];
     let imm_params = params
         .iter()
-        .map(|param| {
+        .enumerate()
+        .map(|(ix, param)| {
             let seq_param = sequentialize(param, counter);
-            if let SeqExp::Imm(i, _) = seq_param {
-                return i;
+            match seq_param {
+                // a variable has to be read before a later argument assigns it
+                SeqExp::Imm(ImmExp::Var(_), _) if params[ix + 1..].iter().any(assigns) => (),
+                SeqExp::Imm(i, _) => return i,
+                _ => (),
             }
             let var = format!("#var_{}", counter);
             let_bindings.push((var.clone(), seq_param));
@@ -20,6 +24,24 @@ fn parse_param_exps(
     (imm_params, let_bindings)
 }
 
+fn assigns(e: &Exp<()>) -> bool {
+    match e {
+        Exp::Assign { .. } => true,
+        Exp::Prim(_, exps, _) => exps.iter().any(|e| assigns(e)),
+        Exp::Let { bindings, body, .. } => {
+            bindings.iter().any(|(_, e)| assigns(e)) || assigns(body)
+        }
+        Exp::If { cond, thn, els, .. } => assigns(cond) || assigns(thn) || assigns(els),
+        Exp::While { cond, body, .. } => assigns(cond) || assigns(body),
+        Exp::Try { body, handler, .. } => assigns(body) || assigns(handler),
+        Exp::FunDefs { body, .. } => assigns(body),
+        Exp::InternalTailCall(_, args, _) | Exp::ExternalCall { args, .. } => {
+            args.iter().any(assigns)
+        }
+        _ => false,
+    }
+}
+
 fn generate_nested_let(bindings: &[(String, SeqExp<()>)], body: SeqExp<()>) -> SeqExp<()> {
     if bindings.is_empty() {
         return body;
@@ -32,9 +54,31 @@ fn generate_nested_let(bindings: &[(String, SeqExp<()>)], body: SeqExp<()>) -> S
     }
 }
 
+static I63_MAX: i64 = 0x3F_FF_FF_FF_FF_FF_FF_FF;
+static I63_MIN: i64 = -0x40_00_00_00_00_00_00_00;
+
+// A literal that doesn't fit into 63 bits can't be an immediate.
+// Build it as (i / 2) * 2 + i % 2 instead and let the overflowing
+// multiplication promote it to a bignum at runtime.
+fn big_literal(i: i64) -> Exp<()> {
+    Exp::Prim(
+        Prim::Add,
+        vec![
+            Box::new(Exp::Prim(
+                Prim::Mul,
+                vec![Box::new(Exp::Num(i / 2, ())), Box::new(Exp::Num(2, ()))],
+                (),
+            )),
+            Box::new(Exp::Num(i % 2, ())),
+        ],
+        (),
+    )
+}
+
 fn sequentialize(e: &Exp<()>, counter: &mut u32) -> SeqExp<()> {
     match e {
         Exp::Bool(b, _) => SeqExp::Imm(ImmExp::Bool(*b), ()),
+        Exp::Num(i, _) if *i > I63_MAX || *i < I63_MIN => sequentialize(&big_literal(*i), counter),
         Exp::Num(i, _) => SeqExp::Imm(ImmExp::Num(*i), ()),
         Exp::Var(s, _) => SeqExp::Imm(ImmExp::Var(s.clone()), ()),
         Exp::Prim(p, exps, ann) => {
@@ -84,6 +128,11 @@ fn sequentialize(e: &Exp<()>, counter: &mut u32) -> SeqExp<()> {
                 .map(|decl| SeqFunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    variadic: decl.variadic,
+                    defaults: vec![],
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
                     body: sequentialize(&decl.body, counter),
                     ann: (),
                 })
@@ -122,14 +171,58 @@ fn sequentialize(e: &Exp<()>, counter: &mut u32) -> SeqExp<()> {
             )
         }
         Exp::Semicolon { e1, e2, ann } => todo!(),
-        Exp::Lambda {
-            parameters,
-            body,
-            ann,
-        } => todo!(),
+        Exp::ForRange { .. }
+        | Exp::ForEach { .. }
+        | Exp::Match { .. }
+        | Exp::DataDefs { .. }
+        | Exp::Record { .. }
+        | Exp::GetField { .. }
+        | Exp::SetField { .. }
+        | Exp::LetPattern { .. } => {
+            todo!()
+        }
+        Exp::While { cond, body, .. } => SeqExp::While {
+            cond: Box::new(sequentialize(cond, counter)),
+            body: Box::new(sequentialize(body, counter)),
+            ann: (),
+        },
+        Exp::Try {
+            body, var, handler, ..
+        } => SeqExp::Try {
+            body: Box::new(sequentialize(body, counter)),
+            var: var.clone(),
+            handler: Box::new(sequentialize(handler, counter)),
+            ann: (),
+        },
+        Exp::Assign { var, value, .. } => match sequentialize(value, counter) {
+            SeqExp::Imm(imm, _) => SeqExp::Assign {
+                var: var.clone(),
+                value: imm,
+                ann: (),
+            },
+            seq_value => {
+                *counter += 1;
+                let var_name = format!("#assign_{}", counter);
+                SeqExp::Let {
+                    var: var_name.clone(),
+                    bound_exp: Box::new(seq_value),
+                    body: Box::new(SeqExp::Assign {
+                        var: var.clone(),
+                        value: ImmExp::Var(var_name),
+                        ann: (),
+                    }),
+                    ann: (),
+                }
+            }
+        },
+        Exp::Lambda { .. } => todo!(),
+        Exp::KeywordCall { .. } => todo!(),
         Exp::MakeClosure {
             arity,
+            variadic,
             label,
+            keywords,
+            defaults,
             env,
             ann,
         } => {
@@ -140,7 +233,10 @@ fn sequentialize(e: &Exp<()>, counter: &mut u32) -> SeqExp<()> {
                 bound_exp: Box::new(sequentialize(env, counter)),
                 body: Box::new(SeqExp::MakeClosure {
                     arity: arity.clone(),
+                    variadic: *variadic,
                     label: label.clone(),
+                    keywords: *keywords,
+                    defaults: *defaults,
                     env: ImmExp::Var(var_name),
                     ann: (),
                 }),
@@ -162,6 +258,11 @@ pub fn seq_prog(decls: &[FunDecl<Exp<()>, ()>], p: &Exp<()>) -> SeqProg<()> {
                 FunDecl {
                     name: decl.name.clone(),
                     parameters: decl.parameters.clone(),
+                    variadic: decl.variadic,
+                    defaults: vec![],
+                    param_types: vec![],
+                    result_type: None,
+                    doc: None,
                     body: seq_body,
                     ann: (),
                 }
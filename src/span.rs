@@ -4,14 +4,16 @@
 // This is what the parser outputs.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Span1 {
+    pub file: usize, // the index of the file among the modules of the program
     pub start_ix: usize,
     pub end_ix: usize, // exclusive
 }
 
 // 2-dimensional span of source locations
 // This is what we use in error messages.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Span2 {
+    pub file: String,
     pub start_line: usize,
     pub start_col: usize,
     pub end_line: usize, // inclusive
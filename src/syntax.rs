@@ -5,10 +5,107 @@ pub type SurfFunDecl<Ann> = FunDecl<Exp<Ann>, Ann>;
 pub struct FunDecl<E, Ann> {
     pub name: String,
     pub parameters: Vec<String>,
+    // the last parameter is bound to an array of the extra arguments
+    pub variadic: bool,
+    // the values of the last defaults.len() parameters before the rest
+    // parameter, for the calls that leave them out. Constants or variables
+    // after desugar
+    pub defaults: Vec<E>,
+    // the annotated types of the parameters, None for the ones without an
+    // annotation, and of the result, each with where it was written. Empty
+    // for the functions made by lambda lifting
+    pub param_types: Vec<Option<(TypeAnn, Ann)>>,
+    pub result_type: Option<(TypeAnn, Ann)>,
+    // the doc comment right before the def, for tools, see comments.rs
+    pub doc: Option<String>,
     pub body: E,
     pub ann: Ann,
 }
 
+// The type of a parameter or result annotation, e.g. def f(x: Num): Bool
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TypeAnn {
+    Num,
+    Bool,
+    Array,
+}
+
+impl std::fmt::Display for TypeAnn {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TypeAnn::Num => write!(f, "Num"),
+            TypeAnn::Bool => write!(f, "Bool"),
+            TypeAnn::Array => write!(f, "Array"),
+        }
+    }
+}
+
+// The comparison an assertion made, whose operands are reported when it
+// fails. The runtime lists them in this order
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Neq,
+    Equal,
+}
+
+impl Comparison {
+    pub fn of(p: Prim) -> Option<Comparison> {
+        match p {
+            Prim::Lt => Some(Comparison::Lt),
+            Prim::Gt => Some(Comparison::Gt),
+            Prim::Le => Some(Comparison::Le),
+            Prim::Ge => Some(Comparison::Ge),
+            Prim::Eq => Some(Comparison::Eq),
+            Prim::Neq => Some(Comparison::Neq),
+            Prim::Equal => Some(Comparison::Equal),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Comparison::Lt => write!(f, "<"),
+            Comparison::Gt => write!(f, ">"),
+            Comparison::Le => write!(f, "<="),
+            Comparison::Ge => write!(f, ">="),
+            Comparison::Eq => write!(f, "=="),
+            Comparison::Neq => write!(f, "!="),
+            Comparison::Equal => write!(f, "equal"),
+        }
+    }
+}
+
+// One alternative of a data declaration, e.g. Node(l, v, r)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Constructor {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl Constructor {
+    // The name values of the constructor are printed with, without the
+    // module a top-level constructor is qualified with once linked
+    pub fn printed_name(&self) -> &str {
+        self.name.rsplit("::").next().unwrap()
+    }
+}
+
+// import "util.snake" (f, g), at the top of a file. The path is relative
+// to the directory of the importing file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Import<Ann> {
+    pub path: String,
+    pub names: Vec<String>,
+    pub ann: Ann,
+}
+
 /* Expressions */
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Exp<Ann> {
@@ -33,19 +130,109 @@ pub enum Exp<Ann> {
         e2: Box<Exp<Ann>>,
         ann: Ann,
     },
+    // x := e, only for let-bound variables. Evaluates to the new value
+    Assign {
+        var: String,
+        value: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // Evaluates to false, the value of the condition that ended the loop
+    While {
+        cond: Box<Exp<Ann>>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // for var in range(start, end): body end, where range is the
+    // prelude's once the modules are linked. var counts up from start to
+    // end (exclusive). Evaluates to false
+    ForRange {
+        var: String,
+        start: Box<Exp<Ann>>,
+        end: Box<Exp<Ann>>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // for var in array: body end
+    // Evaluates to false
+    ForEach {
+        var: String,
+        array: Box<Exp<Ann>>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // try: body catch var: handler end
+    // Evaluates the handler with var bound to the value raised by the body,
+    // or to RuntimeError(code, value) for a runtime error in the body
+    Try {
+        body: Box<Exp<Ann>>,
+        var: String,
+        handler: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // The first arm whose pattern matches the value of scrutinee is
+    // evaluated, with the variables of the pattern bound
+    Match {
+        scrutinee: Box<Exp<Ann>>,
+        arms: Vec<(Pattern, Exp<Ann>)>,
+        ann: Ann,
+    },
 
     FunDefs {
         decls: Vec<FunDecl<Exp<Ann>, Ann>>,
         body: Box<Exp<Ann>>,
         ann: Ann,
     },
+    // let [a, [b, _]] = e in body
+    // The patterns only contain variables, wildcards and arrays. Rewritten
+    // into a Let by desugar before anything else looks at the program
+    LetPattern {
+        bindings: Vec<(Pattern, Exp<Ann>)>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // {x: e1, y: e2}, the fields keep the order they are written in
+    Record {
+        fields: Vec<(String, Exp<Ann>)>,
+        ann: Ann,
+    },
+    // record.field
+    GetField {
+        record: Box<Exp<Ann>>,
+        field: String,
+        ann: Ann,
+    },
+    // record.field := value, evaluates to the new value
+    SetField {
+        record: Box<Exp<Ann>>,
+        field: String,
+        value: Box<Exp<Ann>>,
+        ann: Ann,
+    },
+    // data name = C1 | C2(x, y) | ... in body
+    // binds each constructor with fields as a function, the others as values
+    DataDefs {
+        name: String,
+        ctors: Vec<Constructor>,
+        body: Box<Exp<Ann>>,
+        ann: Ann,
+    },
     Lambda {
         parameters: Vec<String>,
+        variadic: bool,          // like the variadic of a FunDecl
+        defaults: Vec<Exp<Ann>>, // like the defaults of a FunDecl
         body: Box<Exp<Ann>>,
         ann: Ann,
     },
+    // a variadic closure takes [arity] arguments or more, the ones after
+    // the first [arity] are passed as an array. Keyword arguments are
+    // matched against the names of the record shape [keywords], and the
+    // last [defaults] of the first [arity] parameters can be left out:
+    // their values are the last elements of the environment
     MakeClosure {
         arity: usize,
+        variadic: bool,
+        keywords: usize,
+        defaults: usize,
         label: String,
         env: Box<Exp<Ann>>,
         ann: Ann,
@@ -53,6 +240,14 @@ pub enum Exp<Ann> {
 
     // A call that may or may not require a closure
     Call(Box<Exp<Ann>>, Vec<Exp<Ann>>, Ann),
+    // f(e1, x = e2), the keyword arguments come after the positional ones
+    // and are matched with the parameters by name
+    KeywordCall {
+        fun: Box<Exp<Ann>>,
+        args: Vec<Exp<Ann>>,
+        keywords: Vec<(String, Exp<Ann>)>,
+        ann: Ann,
+    },
 
     // A call to a dynamically determined closure
     ClosureCall(Box<Exp<Ann>>, Vec<Exp<Ann>>, Ann),
@@ -71,6 +266,191 @@ pub enum Exp<Ann> {
     },
 }
 
+impl<Ann> Exp<Ann>
+where
+    Ann: Clone,
+{
+    // The literal [digits], an optional sign followed by decimal digits.
+    // One that doesn't fit into 64 bits is built out of chunks of
+    // LITERAL_CHUNK digits, and the arithmetic promotes it to a bignum
+    pub fn number(digits: &str, ann: Ann) -> Exp<Ann> {
+        if let Ok(n) = digits.parse() {
+            return Exp::Num(n, ann);
+        }
+        let (op, sign, digits) = match digits.strip_prefix('-') {
+            Some(digits) => (Prim::Sub, -1, digits),
+            None => (Prim::Add, 1, digits.trim_start_matches('+')),
+        };
+        let first = match digits.len() % LITERAL_CHUNK {
+            0 => LITERAL_CHUNK,
+            n => n,
+        };
+        let chunk = |s: &str| Box::new(Exp::Num(s.parse::<i64>().unwrap(), ann.clone()));
+        let mut e = Exp::Num(sign * digits[..first].parse::<i64>().unwrap(), ann.clone());
+        for i in (first..digits.len()).step_by(LITERAL_CHUNK) {
+            let shifted = Exp::Prim(
+                Prim::Mul,
+                vec![
+                    Box::new(e),
+                    Box::new(Exp::Num(10i64.pow(LITERAL_CHUNK as u32), ann.clone())),
+                ],
+                ann.clone(),
+            );
+            e = Exp::Prim(
+                op,
+                vec![Box::new(shifted), chunk(&digits[i..i + LITERAL_CHUNK])],
+                ann.clone(),
+            );
+        }
+        e
+    }
+}
+
+// 10 to this power fits into 63 bits
+static LITERAL_CHUNK: usize = 18;
+
+// The constructor of what a try catches for a runtime error, with the
+// ErrorCode of the error and the value it failed on, or false if there is
+// none. Only runtime errors build it, programs can only match it. It is
+// the constructor with id 0
+pub static RUNTIME_ERROR_CTOR: &str = "RuntimeError";
+
+pub fn runtime_error_ctor() -> Constructor {
+    Constructor {
+        name: String::from(RUNTIME_ERROR_CTOR),
+        fields: vec![String::from("code"), String::from("value")],
+    }
+}
+
+// The codes of the runtime errors, the same as in runtime/stub.rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    ArithType = 0,
+    CmpType = 1,
+    // 2 is unused, arithmetic that overflows promotes to bignums
+    IfType = 3,
+    LogicType = 4,
+    NonArray = 5,
+    IndexNotNumber = 6,
+    IndexOutOfBounds = 7,
+    NonClosure = 8,
+    LambdaArity = 9,
+    ArraySize = 10,
+    NegativeSize = 11,
+    OutOfMemory = 12,
+    NonVector = 13,
+    EmptyVector = 14,
+    Match = 15,
+    MissingField = 16,
+    NonRecord = 17,
+    Destructure = 18,
+    // a raised value is caught as is
+    Raise = 19,
+    NonGenerator = 20,
+    GeneratorExhausted = 21,
+    GeneratorRunning = 22,
+    Yield = 23,
+    NonChannel = 24,
+    Deadlock = 25,
+    Argument = 26,
+    NonDict = 27,
+    Cast = 28,
+    NonThunk = 29,
+    Assert = 30,
+    Stack = 99,
+}
+
+impl<Ann> Exp<Ann> {
+    pub fn ann(&self) -> &Ann {
+        match self {
+            Exp::Num(_, ann)
+            | Exp::Bool(_, ann)
+            | Exp::Var(_, ann)
+            | Exp::Prim(_, _, ann)
+            | Exp::Call(_, _, ann)
+            | Exp::ClosureCall(_, _, ann)
+            | Exp::DirectCall(_, _, ann)
+            | Exp::InternalTailCall(_, _, ann)
+            | Exp::Let { ann, .. }
+            | Exp::If { ann, .. }
+            | Exp::Semicolon { ann, .. }
+            | Exp::Assign { ann, .. }
+            | Exp::While { ann, .. }
+            | Exp::ForRange { ann, .. }
+            | Exp::ForEach { ann, .. }
+            | Exp::Try { ann, .. }
+            | Exp::Match { ann, .. }
+            | Exp::FunDefs { ann, .. }
+            | Exp::LetPattern { ann, .. }
+            | Exp::Record { ann, .. }
+            | Exp::GetField { ann, .. }
+            | Exp::SetField { ann, .. }
+            | Exp::DataDefs { ann, .. }
+            | Exp::Lambda { ann, .. }
+            | Exp::MakeClosure { ann, .. }
+            | Exp::KeywordCall { ann, .. }
+            | Exp::ExternalCall { ann, .. } => ann,
+        }
+    }
+
+    // The type of the value of the expression if it's known without
+    // looking at any variable, e.g. Num for x + 1
+    pub fn known_type(&self) -> Option<TypeAnn> {
+        match self {
+            Exp::Num(..) => Some(TypeAnn::Num),
+            Exp::Bool(..) => Some(TypeAnn::Bool),
+            Exp::Prim(p, _, _) => match p {
+                Prim::Add | Prim::Sub | Prim::Mul | Prim::Add1 | Prim::Sub1 | Prim::Length => {
+                    Some(TypeAnn::Num)
+                }
+                Prim::Not
+                | Prim::And
+                | Prim::Or
+                | Prim::Lt
+                | Prim::Gt
+                | Prim::Le
+                | Prim::Ge
+                | Prim::Eq
+                | Prim::Neq
+                | Prim::Equal
+                | Prim::IsBool
+                | Prim::IsNum
+                | Prim::IsFun
+                | Prim::IsArray
+                | Prim::DictHas => Some(TypeAnn::Bool),
+                Prim::MakeArray
+                | Prim::NewArray
+                | Prim::Copy
+                | Prim::Concat
+                | Prim::Slice
+                | Prim::DictKeys => Some(TypeAnn::Array),
+                Prim::Cast(t, _, _) => Some(*t),
+                _ => None,
+            },
+            Exp::Let { body, .. } | Exp::LetPattern { body, .. } => body.known_type(),
+            Exp::Semicolon { e2, .. } => e2.known_type(),
+            Exp::If { thn, els, .. } => match (thn.known_type(), els.known_type()) {
+                (Some(t), Some(u)) if t == u => Some(t),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    Num(i64),
+    Bool(bool),
+    Wildcard,
+    Var(String),
+    // only matches arrays of exactly this length
+    Array(Vec<Pattern>),
+    // names start with an uppercase letter, which tells a constructor
+    // without fields apart from a variable
+    Constructor(String, Vec<Pattern>),
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Prim {
     // unary
@@ -79,14 +459,63 @@ pub enum Prim {
     Not,
     Length,
     Print,
+    Copy,
+    Pop,
     IsBool,
     IsNum,
     IsFun,
     IsArray,
+    Raise,     // passes the argument to the innermost enclosing try
+    Generator, // a generator running the body of a closure without parameters
+    Yield,     // suspends the running generator, passing the argument to its resumer
+    Resume,    // runs a generator until it yields, evaluates to the yielded value
+    Spawn,     // a new thread running the body of a closure without parameters
+    Recv,      // the next value sent on a channel, waiting for one if there is none
+    DictKeys,  // a new array of the keys of a dict, in the order they were first put
+    Delay,     // a thunk running the body of a closure without parameters once forced
+    Force,     // the value of a thunk, computing it the first time
+    // fails unless the argument is true, the second argument if any is
+    // the code reported and caught instead of the one of assertions
+    Assert,
     // Internal-only unary forms
-    GetCode,
+    // the code to call a closure with this many arguments: the function
+    // itself if its arity allows it, otherwise the entry that binds the
+    // arguments to the parameters at runtime. Always the latter for a call
+    // with keyword arguments (None)
+    GetCode(Option<usize>),
     GetEnv,
-    CheckArityAndUntag(usize),
+    UntagClosure, // fails unless the argument is a closure
+    // fails unless the argument is an array or a generator, evaluates to
+    // the length of an array and to -1 for a generator
+    CheckIterable,
+    Advance,           // resumes a generator, evaluates to whether it yielded a value
+    LastYielded,       // the value a generator yielded last
+    MatchFail,         // fails with the argument, a value no arm of a match matched
+    IsData(usize),     // whether the argument was built by the constructor with this id
+    GetField(usize),   // the field with this id of a record of any shape
+    GetFieldAt(usize), // the field at this index of a record whose shape is known
+    // fails unless the argument is an array of this length, reporting the
+    // line and column of the destructuring let. Evaluates to the array
+    CheckLength(usize, usize, usize),
+    Forced, // fails unless the argument is a thunk, evaluates to whether it was forced
+    // the value of a thunk that was forced, its closure otherwise
+    ThunkValue,
+    // fails reporting the line and column of an assertion. The arguments
+    // are the operands of the comparison it made, if any, then its code,
+    // if it has one
+    AssertFailed(Option<Comparison>, usize, usize),
+    // fails unless the argument has the annotated type, reporting the line
+    // and column of the annotation. Evaluates to the argument
+    Cast(TypeAnn, usize, usize),
+
+    // Internal-only binary forms
+    UncheckedArrayGet, // like ArrayGet, for an array and index known to be valid
+    DataGet,           // first arg is data, second is the index of one of its fields
+    SetField(usize),   // first arg is a record, second is the new value of the field
+    SetFieldAt(usize), // like SetField, for a field index in a record of known shape
+    // first arg is a thunk, second is its value, kept unless it was forced
+    // in the meantime. Evaluates to the value the thunk keeps
+    SetThunk,
 
     // binary
     Add,
@@ -100,13 +529,29 @@ pub enum Prim {
     Ge,
     Eq,
     Neq,
+    Equal,    // structural equality, see also Eq for identity
     ArrayGet, // first arg is array, second is index
+    NewArray, // first arg is the length, second is the initial value of every slot
+    Concat,
+    Push,    // first arg is a vector, second is the new last element
+    Send,    // first arg is a channel, second is the value sent on it
+    DictHas, // first arg is a dict, second is a key
 
     // trinary
     ArraySet, // first arg is array, second is index, third is new value
+    Slice,    // first arg is array, second and third are the start and (exclusive) end
+    DictGet,  // first arg is a dict, second is a key, third is the value if it's missing
+    DictPut,  // first arg is a dict, second is a key, third is its new value
 
     // 0 or more arguments
     MakeArray,
+    MakeData(usize),   // the fields of a value of the constructor with this id
+    MakeRecord(usize), // the fields of a record with the shape with this id
+    // like MakeRecord, the keyword arguments passed last to a closure
+    MakeKeywords(usize),
+    NewVector,  // no arguments, an empty vector
+    NewChannel, // no arguments, a channel no value was sent on yet
+    NewDict,    // no arguments, an empty dict
 }
 
 /* Sequential Expressions */
@@ -138,6 +583,9 @@ pub enum SeqExp<Ann> {
     Prim(Prim, Vec<ImmExp>, Ann),
     MakeClosure {
         arity: usize,
+        variadic: bool,
+        keywords: usize,
+        defaults: usize,
         label: String,
         env: ImmExp,
         ann: Ann,
@@ -181,4 +629,27 @@ pub enum SeqExp<Ann> {
         e2: Box<SeqExp<Ann>>,
         ann: Ann,
     },
+
+    // Overwrites the stack slot of a variable that isn't captured
+    Assign {
+        var: String,
+        value: ImmExp,
+        ann: Ann,
+    },
+
+    // The condition is evaluated again before every iteration
+    While {
+        cond: Box<SeqExp<Ann>>,
+        body: Box<SeqExp<Ann>>,
+        ann: Ann,
+    },
+
+    // The body runs with a handler installed that restores the stack
+    // pointer and jumps to the handler with var bound to the raised value
+    Try {
+        body: Box<SeqExp<Ann>>,
+        var: String,
+        handler: Box<SeqExp<Ann>>,
+        ann: Ann,
+    },
 }
@@ -0,0 +1,75 @@
+use crate::asm::{Arg32, Arg64, BinArgs, Instr, JmpArg, MemRef, MovArgs, Offset, Reg, Reg32};
+use crate::error_handler::{restore_heap_pointer, save_heap_pointer, CURRENT_HANDLER};
+use crate::generator::CURRENT_GENERATOR;
+
+/* Threads are scheduled by the runtime, which keeps the context of each
+ * thread that isn't running as [saved rsp, innermost handler, running
+ * generator] and switches between them when the running thread waits on
+ * a channel or finishes. Every thread but the main one runs on a stack
+ * of its own, allocated by the runtime.
+ *
+ * The heap is shared by all threads: the runtime is only ever entered
+ * with the heap pointer saved, so a thread that runs again after a
+ * switch reloads it instead of going on with its own stale one.
+ */
+pub static SNAKE_SWITCH: &str = "snake_switch";
+pub static THREAD_START: &str = "snake_thread_start";
+
+// the callee-saved registers of the runtime, in the order they are pushed
+static CALLEE_SAVED: [Reg; 6] = [Reg::Rbx, Reg::Rbp, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+fn field(reg: Reg, i: i32) -> MemRef {
+    MemRef {
+        reg,
+        offset: Offset::Constant(8 * i),
+    }
+}
+
+// snake_switch is called by the runtime with the context to save the
+// running thread into in Rdi and the one of the thread to run in Rsi. It
+// returns once the thread that called it runs again.
+//
+// A thread that hasn't run yet starts in snake_thread_start with its
+// closure on top of its stack, see snake_spawn in the runtime. It calls
+// the closure outside of any try, so an error it doesn't catch ends the
+// program, and then tells the runtime it has finished.
+pub fn thread_instr() -> Vec<Instr> {
+    let mut res = vec![Instr::Label(SNAKE_SWITCH.to_string())];
+    res.extend(CALLEE_SAVED.iter().map(|reg| Instr::Push(Arg32::Reg(*reg))));
+    res.extend(vec![
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdi, 0), Reg32::Reg(Reg::Rsp))),
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_HANDLER.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdi, 1), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rsi, 1)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::R9))),
+        Instr::RelativeLoadAddress(Reg::R8, CURRENT_GENERATOR.to_string()),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::R8, 0)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rdi, 2), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rsi, 2)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::R8, 0), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rsp, Arg64::Mem(field(Reg::Rsi, 0)))),
+    ]);
+    res.extend(CALLEE_SAVED.iter().rev().map(|reg| Instr::Pop(Arg32::Reg(*reg))));
+    res.extend(vec![
+        Instr::Ret,
+        Instr::Label(THREAD_START.to_string()),
+        Instr::Pop(Arg32::Reg(Reg::Rax)),
+    ]);
+    res.extend(restore_heap_pointer());
+    // call the closure with its environment as the only argument
+    res.extend(vec![
+        Instr::Sub(BinArgs::ToReg(Reg::Rax, Arg32::Unsigned(0b11))),
+        Instr::Mov(MovArgs::ToReg(Reg::R9, Arg64::Mem(field(Reg::Rax, 2)))),
+        Instr::Mov(MovArgs::ToMem(field(Reg::Rsp, -3), Reg32::Reg(Reg::R9))),
+        Instr::Mov(MovArgs::ToReg(Reg::Rax, Arg64::Mem(field(Reg::Rax, 0)))),
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Call(JmpArg::Reg(Reg::Rax)),
+    ]);
+    res.extend(save_heap_pointer());
+    res.extend(vec![
+        Instr::Sub(BinArgs::ToReg(Reg::Rsp, Arg32::Signed(8))),
+        Instr::Call(JmpArg::Label("snake_thread_done".to_string())),
+    ]);
+    res
+}
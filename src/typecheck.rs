@@ -0,0 +1,738 @@
+use crate::compile::CompileErr;
+use crate::span::Span1;
+use crate::syntax::{Exp, Pattern, Prim, SurfFunDecl, SurfProg, TypeAnn};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/* Hindley-Milner type inference, run on the linked program before it is
+ * desugared when the compiler is given --typecheck. It covers numbers,
+ * booleans, arrays whose elements all have the same type and functions,
+ * programs using anything else are rejected.
+ *
+ * Arrays are mutable, so a let only generalizes lambdas and constants
+ * bound to variables that are never assigned. The functions defined
+ * together are generalized in groups of mutually recursive ones, each
+ * group after the ones it uses, so that a function stays polymorphic in
+ * the functions that use it.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Num,
+    Bool,
+    Var(usize),
+    Array(Box<Type>),
+    Fun(Vec<Type>, Box<Type>),
+}
+
+// A type whose variables [vars] are replaced by fresh ones at each use
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Type,
+}
+
+fn mono(ty: Type) -> Scheme {
+    Scheme { vars: vec![], ty }
+}
+
+// The innermost binding of a name is the last one
+type Env = Vec<(String, Scheme)>;
+
+type Infer<T> = Result<T, CompileErr<Span1>>;
+
+// Infers the type of the program [p]
+pub fn infer_prog(p: &SurfProg<Span1>) -> Infer<Type> {
+    let mut assigned = HashSet::new();
+    names(p, &mut HashSet::new(), &mut assigned);
+    let mut st = State {
+        subst: vec![],
+        assigned,
+    };
+    let t = st.infer(p, &mut vec![])?;
+    Ok(st.resolve(&t))
+}
+
+struct State {
+    // the type each type variable was unified with, if any
+    subst: Vec<Option<Type>>,
+    // the variables assigned anywhere in the program, never generalized
+    assigned: HashSet<String>,
+}
+
+impl State {
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        Type::Var(self.subst.len() - 1)
+    }
+
+    // [t] with every variable that was unified replaced by its type
+    fn resolve(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match &self.subst[*v] {
+                Some(t) => self.resolve(t),
+                None => t.clone(),
+            },
+            Type::Array(elt) => Type::Array(Box::new(self.resolve(elt))),
+            Type::Fun(params, ret) => Type::Fun(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            _ => t.clone(),
+        }
+    }
+
+    // [t] with the variable at its root replaced, if it was unified
+    fn shallow(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match &self.subst[*v] {
+                Some(t) => self.shallow(t),
+                None => t.clone(),
+            },
+            _ => t.clone(),
+        }
+    }
+
+    fn occurs(&self, v: usize, t: &Type) -> bool {
+        match self.shallow(t) {
+            Type::Var(w) => v == w,
+            Type::Array(elt) => self.occurs(v, &elt),
+            Type::Fun(params, ret) => {
+                params.iter().any(|p| self.occurs(v, p)) || self.occurs(v, &ret)
+            }
+            _ => false,
+        }
+    }
+
+    // Fails with None if the types differ, or with the variable and the
+    // type containing it that it would have to be unified with
+    fn unify_types(&mut self, t1: &Type, t2: &Type) -> Result<(), Option<(usize, Type)>> {
+        match (self.shallow(t1), self.shallow(t2)) {
+            (Type::Var(v), Type::Var(w)) if v == w => Ok(()),
+            (Type::Var(v), t) | (t, Type::Var(v)) => {
+                if self.occurs(v, &t) {
+                    return Err(Some((v, t)));
+                }
+                self.subst[v] = Some(t);
+                Ok(())
+            }
+            (Type::Num, Type::Num) | (Type::Bool, Type::Bool) => Ok(()),
+            (Type::Array(elt1), Type::Array(elt2)) => self.unify_types(&elt1, &elt2),
+            (Type::Fun(params1, ret1), Type::Fun(params2, ret2))
+                if params1.len() == params2.len() =>
+            {
+                for (p1, p2) in params1.iter().zip(params2.iter()) {
+                    self.unify_types(p1, p2)?;
+                }
+                self.unify_types(&ret1, &ret2)
+            }
+            _ => Err(None),
+        }
+    }
+
+    // [found] is the type of the expression at [location], which has to
+    // be [expected]
+    fn unify(&mut self, expected: &Type, found: &Type, location: &Span1) -> Infer<()> {
+        match self.unify_types(expected, found) {
+            Ok(()) => Ok(()),
+            Err(None) => {
+                let shown = show_types(&[self.resolve(expected), self.resolve(found)]);
+                Err(CompileErr::TypeMismatch {
+                    expected: shown[0].clone(),
+                    found: shown[1].clone(),
+                    location: *location,
+                })
+            }
+            Err(Some((v, t))) => {
+                let shown = show_types(&[Type::Var(v), self.resolve(&t)]);
+                Err(CompileErr::InfiniteType {
+                    var: shown[0].clone(),
+                    ty: shown[1].clone(),
+                    location: *location,
+                })
+            }
+        }
+    }
+
+    fn free_vars(&self, t: &Type, acc: &mut Vec<usize>) {
+        match self.shallow(t) {
+            Type::Var(v) if !acc.contains(&v) => acc.push(v),
+            Type::Array(elt) => self.free_vars(&elt, acc),
+            Type::Fun(params, ret) => {
+                for p in &params {
+                    self.free_vars(p, acc);
+                }
+                self.free_vars(&ret, acc)
+            }
+            _ => (),
+        }
+    }
+
+    // Quantifies the variables of [t] that aren't free in [env]
+    fn generalize(&self, env: &Env, t: &Type) -> Scheme {
+        let mut in_env = vec![];
+        for (_, scheme) in env {
+            let mut vars = vec![];
+            self.free_vars(&scheme.ty, &mut vars);
+            in_env.extend(vars.into_iter().filter(|v| !scheme.vars.contains(v)));
+        }
+        let mut vars = vec![];
+        self.free_vars(t, &mut vars);
+        vars.retain(|v| !in_env.contains(v));
+        Scheme {
+            vars,
+            ty: self.resolve(t),
+        }
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let fresh: HashMap<usize, Type> = scheme.vars.iter().map(|v| (*v, self.fresh())).collect();
+        replace(&self.resolve(&scheme.ty), &fresh)
+    }
+
+    fn lookup(&mut self, x: &str, env: &Env, location: &Span1) -> Infer<Type> {
+        match env.iter().rev().find(|(y, _)| y == x) {
+            Some((_, scheme)) => {
+                let scheme = scheme.clone();
+                Ok(self.instantiate(&scheme))
+            }
+            None => Err(CompileErr::UnboundVariable {
+                unbound: x.to_string(),
+                location: *location,
+            }),
+        }
+    }
+
+    fn infer(&mut self, e: &Exp<Span1>, env: &mut Env) -> Infer<Type> {
+        match e {
+            Exp::Num(_, _) => Ok(Type::Num),
+            Exp::Bool(_, _) => Ok(Type::Bool),
+            Exp::Var(x, ann) => self.lookup(x, env, ann),
+            Exp::Prim(op, args, ann) => {
+                let (params, ret) = self.prim_type(*op, args.len(), ann)?;
+                for (param, arg) in params.iter().zip(args.iter()) {
+                    let t = self.infer(arg, env)?;
+                    self.unify(param, &t, arg.ann())?;
+                }
+                Ok(ret)
+            }
+            Exp::Let { bindings, body, .. } => {
+                let depth = env.len();
+                for (x, value) in bindings {
+                    let t = self.infer(value, env)?;
+                    let is_value = matches!(
+                        value,
+                        Exp::Lambda { .. } | Exp::Num(..) | Exp::Bool(..) | Exp::Var(..)
+                    );
+                    let scheme = if is_value && !self.assigned.contains(x) {
+                        self.generalize(env, &t)
+                    } else {
+                        mono(t)
+                    };
+                    env.push((x.clone(), scheme));
+                }
+                let t = self.infer(body, env)?;
+                env.truncate(depth);
+                Ok(t)
+            }
+            Exp::LetPattern { bindings, body, .. } => {
+                let depth = env.len();
+                for (p, value) in bindings {
+                    let t = self.infer(value, env)?;
+                    self.bind_pattern(p, &t, value.ann(), env)?;
+                }
+                let t = self.infer(body, env)?;
+                env.truncate(depth);
+                Ok(t)
+            }
+            Exp::If { cond, thn, els, .. } => {
+                let t = self.infer(cond, env)?;
+                self.unify(&Type::Bool, &t, cond.ann())?;
+                let t1 = self.infer(thn, env)?;
+                let t2 = self.infer(els, env)?;
+                self.unify(&t1, &t2, els.ann())?;
+                Ok(t1)
+            }
+            Exp::Semicolon { e1, e2, .. } => {
+                self.infer(e1, env)?;
+                self.infer(e2, env)
+            }
+            Exp::Assign { var, value, ann } => {
+                let t = self.lookup(var, env, ann)?;
+                let found = self.infer(value, env)?;
+                self.unify(&t, &found, value.ann())?;
+                Ok(t)
+            }
+            Exp::While { cond, body, .. } => {
+                let t = self.infer(cond, env)?;
+                self.unify(&Type::Bool, &t, cond.ann())?;
+                self.infer(body, env)?;
+                Ok(Type::Bool)
+            }
+            Exp::ForRange {
+                var,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                for bound in [start, end].iter() {
+                    let t = self.infer(bound, env)?;
+                    self.unify(&Type::Num, &t, bound.ann())?;
+                }
+                env.push((var.clone(), mono(Type::Num)));
+                self.infer(body, env)?;
+                env.pop();
+                Ok(Type::Bool)
+            }
+            Exp::ForEach {
+                var, array, body, ..
+            } => {
+                let elt = self.fresh();
+                let t = self.infer(array, env)?;
+                self.unify(&Type::Array(Box::new(elt.clone())), &t, array.ann())?;
+                env.push((var.clone(), mono(elt)));
+                self.infer(body, env)?;
+                env.pop();
+                Ok(Type::Bool)
+            }
+            Exp::FunDefs { decls, body, .. } => {
+                let depth = env.len();
+                for group in groups(decls) {
+                    let base = env.len();
+                    for i in &group {
+                        let t = self.fresh();
+                        env.push((decls[*i].name.clone(), mono(t)));
+                    }
+                    for (k, i) in group.iter().enumerate() {
+                        let decl = &decls[*i];
+                        let t = self.infer_fun(
+                            &decl.parameters,
+                            decl.variadic,
+                            &decl.defaults,
+                            &decl.body,
+                            &decl.ann,
+                            env,
+                        )?;
+                        self.annotate(&t, decl)?;
+                        let own = env[base + k].1.ty.clone();
+                        self.unify(&own, &t, &decl.ann)?;
+                    }
+                    // the group is only generalized once all of it is known
+                    let types: Vec<Type> = env.drain(base..).map(|(_, s)| s.ty).collect();
+                    for (i, t) in group.iter().zip(types.iter()) {
+                        let scheme = self.generalize(env, t);
+                        env.push((decls[*i].name.clone(), scheme));
+                    }
+                }
+                let t = self.infer(body, env)?;
+                env.truncate(depth);
+                Ok(t)
+            }
+            Exp::Lambda {
+                parameters,
+                variadic,
+                defaults,
+                body,
+                ann,
+            } => self.infer_fun(parameters, *variadic, defaults, body, ann, env),
+            Exp::Call(fun, args, ann) => {
+                let t = self.infer(fun, env)?;
+                let mut found = vec![];
+                for arg in args {
+                    found.push(self.infer(arg, env)?);
+                }
+                match self.resolve(&t) {
+                    // point at the argument rather than the call when it can
+                    Type::Fun(params, ret) if params.len() == args.len() => {
+                        for ((param, t), arg) in params.iter().zip(found.iter()).zip(args) {
+                            self.unify(param, t, arg.ann())?;
+                        }
+                        Ok(*ret)
+                    }
+                    _ => {
+                        let ret = self.fresh();
+                        let called = Type::Fun(found, Box::new(ret.clone()));
+                        self.unify(&t, &called, ann)?;
+                        Ok(ret)
+                    }
+                }
+            }
+            Exp::Try { ann, .. } => Err(untypable("try", ann)),
+            Exp::Match { ann, .. } => Err(untypable("match", ann)),
+            Exp::Record { ann, .. } | Exp::GetField { ann, .. } | Exp::SetField { ann, .. } => {
+                Err(untypable("records", ann))
+            }
+            Exp::DataDefs { ann, .. } => Err(untypable("data declarations", ann)),
+            Exp::KeywordCall { ann, .. } => Err(untypable("keyword arguments", ann)),
+            Exp::MakeClosure { .. }
+            | Exp::ClosureCall(..)
+            | Exp::DirectCall(..)
+            | Exp::InternalTailCall(..)
+            | Exp::ExternalCall { .. } => unreachable!("only lambda_lift makes these"),
+        }
+    }
+
+    fn infer_fun(
+        &mut self,
+        parameters: &[String],
+        variadic: bool,
+        defaults: &[Exp<Span1>],
+        body: &Exp<Span1>,
+        ann: &Span1,
+        env: &mut Env,
+    ) -> Infer<Type> {
+        if variadic {
+            return Err(untypable("rest parameters", ann));
+        }
+        if !defaults.is_empty() {
+            return Err(untypable("default parameters", ann));
+        }
+        let depth = env.len();
+        let mut params = vec![];
+        for x in parameters {
+            let t = self.fresh();
+            params.push(t.clone());
+            env.push((x.clone(), mono(t)));
+        }
+        let ret = self.infer(body, env)?;
+        env.truncate(depth);
+        Ok(Type::Fun(params, Box::new(ret)))
+    }
+
+    // Unifies the type [t] inferred for [decl] with its annotations
+    fn annotate(&mut self, t: &Type, decl: &SurfFunDecl<Span1>) -> Infer<()> {
+        let (params, ret) = match t {
+            Type::Fun(params, ret) => (params, ret),
+            _ => unreachable!("infer_fun infers function types"),
+        };
+        for (param, annotation) in params.iter().zip(&decl.param_types) {
+            if let Some((a, location)) = annotation {
+                let expected = self.annotated(*a);
+                self.unify(&expected, param, location)?;
+            }
+        }
+        if let Some((a, location)) = &decl.result_type {
+            let expected = self.annotated(*a);
+            self.unify(&expected, ret, location)?;
+        }
+        Ok(())
+    }
+
+    // An annotation of Array stands for an array of any type
+    fn annotated(&mut self, a: TypeAnn) -> Type {
+        match a {
+            TypeAnn::Num => Type::Num,
+            TypeAnn::Bool => Type::Bool,
+            TypeAnn::Array => Type::Array(Box::new(self.fresh())),
+        }
+    }
+
+    fn bind_pattern(&mut self, p: &Pattern, t: &Type, ann: &Span1, env: &mut Env) -> Infer<()> {
+        match p {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Var(x) => {
+                env.push((x.clone(), mono(t.clone())));
+                Ok(())
+            }
+            Pattern::Num(_) => self.unify(&Type::Num, t, ann),
+            Pattern::Bool(_) => self.unify(&Type::Bool, t, ann),
+            Pattern::Array(ps) => {
+                let elt = self.fresh();
+                self.unify(&Type::Array(Box::new(elt.clone())), t, ann)?;
+                for p in ps {
+                    self.bind_pattern(p, &elt, ann, env)?;
+                }
+                Ok(())
+            }
+            Pattern::Constructor(..) => Err(untypable("data declarations", ann)),
+        }
+    }
+
+    // The types of the [n] arguments of [op] and of its result
+    fn prim_type(&mut self, op: Prim, n: usize, ann: &Span1) -> Infer<(Vec<Type>, Type)> {
+        let a = self.fresh();
+        let array = Type::Array(Box::new(a.clone()));
+        Ok(match op {
+            Prim::Add1 | Prim::Sub1 => (vec![Type::Num], Type::Num),
+            Prim::Not => (vec![Type::Bool], Type::Bool),
+            Prim::Print => (vec![a.clone()], a),
+            Prim::IsNum | Prim::IsBool | Prim::IsArray | Prim::IsFun => (vec![a], Type::Bool),
+            Prim::Length => (vec![array], Type::Num),
+            Prim::Copy => (vec![array.clone()], array),
+            Prim::Raise => (vec![a], self.fresh()),
+            // the code is any value, as for raise
+            Prim::Assert => (vec![Type::Bool, a][..n].to_vec(), Type::Bool),
+            Prim::Add | Prim::Sub | Prim::Mul => (vec![Type::Num, Type::Num], Type::Num),
+            Prim::And | Prim::Or => (vec![Type::Bool, Type::Bool], Type::Bool),
+            Prim::Lt | Prim::Gt | Prim::Le | Prim::Ge => (vec![Type::Num, Type::Num], Type::Bool),
+            Prim::Eq | Prim::Neq | Prim::Equal => (vec![a.clone(), a], Type::Bool),
+            Prim::ArrayGet => (vec![array, Type::Num], a),
+            Prim::NewArray => (vec![Type::Num, a], array),
+            Prim::Concat => (vec![array.clone(), array.clone()], array),
+            Prim::ArraySet => (vec![array.clone(), Type::Num, a], array),
+            Prim::Slice => (vec![array.clone(), Type::Num, Type::Num], array),
+            Prim::MakeArray => (vec![a; n], array),
+            Prim::NewVector | Prim::Push | Prim::Pop => return Err(untypable("vectors", ann)),
+            Prim::Generator | Prim::Yield | Prim::Resume => {
+                return Err(untypable("generators", ann))
+            }
+            Prim::Spawn | Prim::Send | Prim::Recv | Prim::NewChannel => {
+                return Err(untypable("threads", ann))
+            }
+            Prim::NewDict | Prim::DictGet | Prim::DictPut | Prim::DictHas | Prim::DictKeys => {
+                return Err(untypable("dicts", ann))
+            }
+            Prim::Delay | Prim::Force => return Err(untypable("lazy values", ann)),
+            _ => unreachable!("internal primitive {:?} in the surface program", op),
+        })
+    }
+}
+
+fn untypable(construct: &str, location: &Span1) -> CompileErr<Span1> {
+    CompileErr::Untypable {
+        construct: construct.to_string(),
+        location: *location,
+    }
+}
+
+fn replace(t: &Type, fresh: &HashMap<usize, Type>) -> Type {
+    match t {
+        Type::Var(v) => fresh.get(v).cloned().unwrap_or_else(|| t.clone()),
+        Type::Array(elt) => Type::Array(Box::new(replace(elt, fresh))),
+        Type::Fun(params, ret) => Type::Fun(
+            params.iter().map(|p| replace(p, fresh)).collect(),
+            Box::new(replace(ret, fresh)),
+        ),
+        _ => t.clone(),
+    }
+}
+
+// The functions of [decls] in groups of mutually recursive ones, each
+// group after the groups it uses (Tarjan's algorithm)
+fn groups(decls: &[SurfFunDecl<Span1>]) -> Vec<Vec<usize>> {
+    let index: HashMap<&str, usize> = decls
+        .iter()
+        .enumerate()
+        .map(|(i, d)| (d.name.as_str(), i))
+        .collect();
+    let uses = decls
+        .iter()
+        .map(|d| {
+            let mut vars = HashSet::new();
+            names(&d.body, &mut vars, &mut HashSet::new());
+            let mut uses: Vec<usize> = vars
+                .iter()
+                .filter_map(|x| index.get(x.as_str()).copied())
+                .collect();
+            uses.sort_unstable();
+            uses
+        })
+        .collect();
+    let mut tarjan = Tarjan {
+        uses,
+        order: vec![None; decls.len()],
+        low: vec![0; decls.len()],
+        visited: 0,
+        stack: vec![],
+        groups: vec![],
+    };
+    for i in 0..decls.len() {
+        if tarjan.order[i].is_none() {
+            tarjan.visit(i);
+        }
+    }
+    tarjan.groups
+}
+
+struct Tarjan {
+    uses: Vec<Vec<usize>>,
+    // when each function was visited
+    order: Vec<Option<usize>>,
+    visited: usize,
+    // the earliest function on the stack each one reaches
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    groups: Vec<Vec<usize>>,
+}
+
+impl Tarjan {
+    fn visit(&mut self, i: usize) {
+        self.order[i] = Some(self.visited);
+        self.low[i] = self.visited;
+        self.visited += 1;
+        self.stack.push(i);
+        for j in self.uses[i].clone() {
+            match self.order[j] {
+                None => {
+                    self.visit(j);
+                    self.low[i] = self.low[i].min(self.low[j]);
+                }
+                Some(o) if self.stack.contains(&j) => self.low[i] = self.low[i].min(o),
+                Some(_) => (),
+            }
+        }
+        if Some(self.low[i]) == self.order[i] {
+            let at = self.stack.iter().position(|j| *j == i).unwrap();
+            let mut group = self.stack.split_off(at);
+            group.sort_unstable();
+            self.groups.push(group);
+        }
+    }
+}
+
+// Collects the variables [e] mentions, bound in it or not, and the ones
+// it assigns
+fn names<Ann>(e: &Exp<Ann>, vars: &mut HashSet<String>, assigned: &mut HashSet<String>) {
+    match e {
+        Exp::Num(..) | Exp::Bool(..) => (),
+        Exp::Var(x, _) => {
+            vars.insert(x.clone());
+        }
+        Exp::Prim(_, args, _) => {
+            for arg in args {
+                names(arg, vars, assigned);
+            }
+        }
+        Exp::Let { bindings, body, .. } => {
+            for (_, value) in bindings {
+                names(value, vars, assigned);
+            }
+            names(body, vars, assigned);
+        }
+        Exp::LetPattern { bindings, body, .. } => {
+            for (_, value) in bindings {
+                names(value, vars, assigned);
+            }
+            names(body, vars, assigned);
+        }
+        Exp::If { cond, thn, els, .. } => {
+            names(cond, vars, assigned);
+            names(thn, vars, assigned);
+            names(els, vars, assigned);
+        }
+        Exp::Semicolon { e1, e2, .. } => {
+            names(e1, vars, assigned);
+            names(e2, vars, assigned);
+        }
+        Exp::Assign { var, value, .. } => {
+            assigned.insert(var.clone());
+            names(value, vars, assigned);
+        }
+        Exp::While { cond, body, .. } => {
+            names(cond, vars, assigned);
+            names(body, vars, assigned);
+        }
+        Exp::ForRange {
+            start, end, body, ..
+        } => {
+            names(start, vars, assigned);
+            names(end, vars, assigned);
+            names(body, vars, assigned);
+        }
+        Exp::ForEach { array, body, .. } => {
+            names(array, vars, assigned);
+            names(body, vars, assigned);
+        }
+        Exp::Try { body, handler, .. } => {
+            names(body, vars, assigned);
+            names(handler, vars, assigned);
+        }
+        Exp::Match {
+            scrutinee, arms, ..
+        } => {
+            names(scrutinee, vars, assigned);
+            for (_, arm) in arms {
+                names(arm, vars, assigned);
+            }
+        }
+        Exp::FunDefs { decls, body, .. } => {
+            for decl in decls {
+                for default in &decl.defaults {
+                    names(default, vars, assigned);
+                }
+                names(&decl.body, vars, assigned);
+            }
+            names(body, vars, assigned);
+        }
+        Exp::Record { fields, .. } => {
+            for (_, value) in fields {
+                names(value, vars, assigned);
+            }
+        }
+        Exp::GetField { record, .. } => names(record, vars, assigned),
+        Exp::SetField { record, value, .. } => {
+            names(record, vars, assigned);
+            names(value, vars, assigned);
+        }
+        Exp::DataDefs { body, .. } => names(body, vars, assigned),
+        Exp::Lambda { defaults, body, .. } => {
+            for default in defaults {
+                names(default, vars, assigned);
+            }
+            names(body, vars, assigned);
+        }
+        Exp::Call(fun, args, _) => {
+            names(fun, vars, assigned);
+            for arg in args {
+                names(arg, vars, assigned);
+            }
+        }
+        Exp::KeywordCall {
+            fun,
+            args,
+            keywords,
+            ..
+        } => {
+            names(fun, vars, assigned);
+            for arg in args {
+                names(arg, vars, assigned);
+            }
+            for (_, arg) in keywords {
+                names(arg, vars, assigned);
+            }
+        }
+        Exp::MakeClosure { .. }
+        | Exp::ClosureCall(..)
+        | Exp::DirectCall(..)
+        | Exp::InternalTailCall(..)
+        | Exp::ExternalCall { .. } => unreachable!("only lambda_lift makes these"),
+    }
+}
+
+// Shows [types] with their variables named 'a, 'b, ... in the order they
+// appear, the same variable getting the same name in all of them
+fn show_types(types: &[Type]) -> Vec<String> {
+    fn show(t: &Type, names: &mut HashMap<usize, String>) -> String {
+        match t {
+            Type::Num => "num".to_string(),
+            Type::Bool => "bool".to_string(),
+            Type::Var(v) => {
+                let n = names.len();
+                names
+                    .entry(*v)
+                    .or_insert_with(|| {
+                        let letter = (b'a' + (n % 26) as u8) as char;
+                        if n < 26 {
+                            format!("'{}", letter)
+                        } else {
+                            format!("'{}{}", letter, n / 26)
+                        }
+                    })
+                    .clone()
+            }
+            Type::Array(elt) => format!("[{}]", show(elt, names)),
+            Type::Fun(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| show(p, names)).collect();
+                format!("({}) -> {}", params.join(", "), show(ret, names))
+            }
+        }
+    }
+    let mut names = HashMap::new();
+    types.iter().map(|t| show(t, &mut names)).collect()
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", show_types(std::slice::from_ref(self))[0])
+    }
+}
@@ -18,6 +18,26 @@ macro_rules! mk_fail_test {
     };
 }
 
+// Typechecks the program without running it, see --typecheck
+macro_rules! mk_type_test {
+    ($test_name:ident, $file_name:expr, $expected_type:expr) => {
+        #[test]
+        fn $test_name() -> std::io::Result<()> {
+            test_example_type($file_name, $expected_type)
+        }
+    };
+}
+
+// The doc comments of the functions the program defines at its top level
+macro_rules! mk_doc_test {
+    ($test_name:ident, $file_name:expr, $expected_docs:expr) => {
+        #[test]
+        fn $test_name() -> std::io::Result<()> {
+            test_example_docs($file_name, $expected_docs)
+        }
+    };
+}
+
 /*
  * YOUR TESTS GO HERE
  */
@@ -26,14 +46,14 @@ mk_fail_test!(
     "parse_error.adder",
     "Unrecognized token `)`"
 );
-mk_fail_test!(err_1, "err_1", "Error generating assembly");
-mk_fail_test!(err_2, "err_2", "overflow");
+mk_test!(err_1, "err_1", "true");
+mk_test!(err_2, "err_2", "2126764793257158878651191192137409");
 mk_fail_test!(err_3, "err_3", "arithmetic expected a number");
 mk_fail_test!(err_4, "err_4", "arithmetic expected a number");
-mk_fail_test!(err_5, "err_5", "overflow");
-mk_fail_test!(err_6, "err_6", "overflow");
+mk_test!(err_5, "err_5", "4611686018427387904");
+mk_test!(err_6, "err_6", "4611686018427387904");
 mk_fail_test!(err_7, "err_7", "arithmetic expected a number");
-mk_fail_test!(err_8, "err_8", "overflow");
+mk_test!(err_8, "err_8", "-4611686018427387905");
 mk_fail_test!(err_9, "err_9", "arithmetic expected a number");
 mk_fail_test!(err_10, "err_10", "if expected a boolean");
 mk_fail_test!(logic_error_1, "logic_error_1", "logic expected a boolean");
@@ -102,6 +122,353 @@ mk_test!(list_7, "list_7", "[0, [0, 1]]");
 mk_test!(list_8, "list_8", "[0, [0, 1]]");
 mk_test!(list_9, "list_9", "[0, [0, 1]]");
 
+mk_test!(bignum_1, "bignum_1", "265252859812191058636308480000000");
+mk_test!(
+    bignum_2,
+    "bignum_2",
+    "[4611686018427387904, 4611686018427387903, true, true, true]\ntrue\n-13835058055282163712"
+);
+mk_test!(bignum_3, "bignum_3", "9969216677189303386214405760200\n-1");
+mk_test!(
+    bignum_4,
+    "bignum_4",
+    "99999999999999999999\n-123456789012345678901234567890\n100000000000000000000\ntrue\ntrue\n999999999999999999999999999999999999\n999999999999999999999999999999999999"
+);
+
+mk_test!(
+    equal_1,
+    "equal_1",
+    "[false, true, false, true]\n[true, false, false, false]\ntrue"
+);
+mk_test!(equal_2, "equal_2", "true\nfalse");
+
+mk_test!(array_1, "array_1", "[0, [[0], [0]], 0]\n[]\n168");
+mk_fail_test!(array_err_1, "array_err_1", "negative array size");
+mk_fail_test!(array_err_2, "array_err_2", "array size not a number");
+mk_fail_test!(array_err_3, "array_err_3", "out of memory");
+mk_fail_test!(
+    array_err_4,
+    "array_err_4",
+    "out of memory allocating 4611686018427387903 words"
+);
+mk_fail_test!(
+    array_err_5,
+    "array_err_5",
+    "out of memory allocating 1152921504606846975 words"
+);
+mk_fail_test!(
+    array_err_6,
+    "array_err_6",
+    "out of memory allocating 600000 words"
+);
+
+mk_test!(
+    slice_1,
+    "slice_1",
+    "[1, 2, 3, 4, 5]\n[10, 2, 3, 4, 5]\n[2, 3, 4, true, false]\n[]\n[]"
+);
+mk_fail_test!(slice_err_1, "slice_err_1", "index out of bounds: 5");
+mk_fail_test!(slice_err_2, "slice_err_2", "index out of bounds: -1");
+mk_fail_test!(slice_err_3, "slice_err_3", "not an array");
+
+mk_test!(
+    vector_1,
+    "vector_1",
+    "[0, 1, 4, 9, 16, 25, 36, 49, 64, 81]\n10\n9\n81\n[0, 1, 4, true, 16, 25, 36, 49, 64]\n[]\n[[1, 2], <loop>]\n[4, true, 16]\nfalse\ntrue\n1000"
+);
+mk_fail_test!(vector_err_1, "vector_err_1", "pop from an empty vector");
+mk_fail_test!(vector_err_2, "vector_err_2", "not a vector");
+mk_fail_test!(vector_err_3, "vector_err_3", "index out of bounds: 2");
+
+mk_test!(
+    assign_1,
+    "assign_1",
+    "2\n20\n2\n41\n12\n5\n[12, 20, 5]\n[12, 20, 5]"
+);
+mk_test!(assign_2, "assign_2", "[4, 15, 15]");
+mk_fail_test!(
+    assign_err_1,
+    "assign_err_1",
+    "only let-bound variables can be assigned"
+);
+
+mk_test!(
+    while_1,
+    "while_1",
+    "false\n45\n[0, 1, 4, 9, 16, 25, 36, 49, 64, 81]\n[2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47]\nfalse"
+);
+mk_test!(while_2, "while_2", "[4, 4, 4, 4]");
+mk_fail_test!(while_err_1, "while_err_1", "expected a boolean");
+
+mk_test!(
+    for_1,
+    "for_1",
+    "45\n[0, 1, 4, 9, 16, 25, 36, 49, 64, 81]\n1\ntrue\n[2, 3]\nfalse\n4\nfalse"
+);
+mk_test!(
+    for_2,
+    "for_2",
+    "[13, 23, 33]\n[[1, 2, 3], [2, 4, 6], [3, 6, 9]]"
+);
+mk_test!(for_3, "for_3", "100\n4\n4");
+mk_fail_test!(for_err_1, "for_err_1", "not an array");
+mk_fail_test!(for_err_2, "for_err_2", "comparison expected a number");
+mk_fail_test!(
+    for_err_3,
+    "for_err_3",
+    "only let-bound variables can be assigned"
+);
+
+mk_test!(
+    match_1,
+    "match_1",
+    "100\n200\n300\n7\n50\n6\n5\n[1, 2, 3]\nfalse\n42"
+);
+mk_test!(match_2, "match_2", "6\n[[1, true], [[2, false], []]]\n5");
+mk_fail_test!(match_err_1, "match_err_1", "no pattern matched [1, 2, 3]");
+mk_fail_test!(match_err_2, "match_err_2", "bound twice in one pattern");
+
+mk_test!(
+    adt_1,
+    "adt_1",
+    "Node(Node(Leaf, 3, Leaf), 5, Node(Leaf, 8, Leaf))\n16\nLeaf\ntrue\nfalse\n[false, false, true, false]\n16"
+);
+mk_test!(adt_2, "adt_2", "25\ntrue\nBox([1, Empty])\n5\nCircle(1)");
+mk_fail_test!(adt_err_1, "adt_err_1", "Unknown constructor Blue");
+mk_fail_test!(
+    adt_err_2,
+    "adt_err_2",
+    "expects 2 fields but the pattern has 1"
+);
+mk_fail_test!(adt_err_3, "adt_err_3", "no pattern matched Some([1, None])");
+mk_fail_test!(
+    adt_err_4,
+    "adt_err_4",
+    "constructor names start with an uppercase letter"
+);
+mk_fail_test!(adt_err_5, "adt_err_5", "multiple constructors named \"A\"");
+
+mk_test!(
+    record_1,
+    "record_1",
+    "{from: {x: 0, y: 0}, to: {x: 3, y: -4}, label: true}\n7\n-4\n13\n{from: {x: 0, y: 0}, to: {x: 13, y: 7}, label: true}\ntrue\nfalse\nfalse\n5\n1\n{value: 1, next: <loop>}\n{}\n3"
+);
+mk_fail_test!(record_err_1, "record_err_1", "missing field z");
+mk_fail_test!(record_err_2, "record_err_2", "multiple fields named \"x\"");
+mk_fail_test!(record_err_3, "record_err_3", "not a record [1, 2]");
+
+mk_test!(
+    destructure_1,
+    "destructure_1",
+    "[1, 2, 3, 6, false]\n30\n21\n[2, 1]"
+);
+mk_fail_test!(
+    destructure_err_1,
+    "destructure_err_1",
+    "destructuring at line 2, column 0 expected an array of 2 elements but got [1, 2, 3]"
+);
+mk_fail_test!(
+    destructure_err_2,
+    "destructure_err_2",
+    "Variable a defined twice"
+);
+mk_fail_test!(
+    destructure_err_3,
+    "destructure_err_3",
+    "expected an array of 2 elements but got 2"
+);
+
+mk_test!(
+    exception_1,
+    "exception_1",
+    "3\n43\n10\n[-3, 0]\n12\n[-2, 0]\n2\n-1\nRuntimeError(7, 5)\nRuntimeError(0, true)\nRuntimeError(3, 5)\nRuntimeError(16, {x: 1})\nRuntimeError(9, <closure>)\n111\n11\nfalse\n2\n107\n21\nRuntimeError(7, 9)\n[[1, 2], 5]\nfalse"
+);
+mk_test!(
+    exception_2,
+    "exception_2",
+    "RuntimeError(7, 5)\n{error: 7}\nfalse\n[7, false]\n7\n[0, true]"
+);
+mk_fail_test!(
+    exception_err_1,
+    "exception_err_1",
+    "uncaught exception [1, 2]"
+);
+mk_fail_test!(
+    exception_err_2,
+    "exception_err_2",
+    "Unbound variable RuntimeError"
+);
+
+mk_test!(
+    generator_1,
+    "generator_1",
+    "[1, 2, 3, 4, 5]\n6\n[1, 4, 9, 16]\n6\nfalse\nRuntimeError(21, <generator>)\n1\n2\n3\n4\n[10, 11]\n1\nRuntimeError(7, 7)\nRuntimeError(21, <generator>)\n1\n50\n3\nRuntimeError(22, <generator>)\nRuntimeError(23, 1)\nRuntimeError(20, 5)\n<generator>\n[100, 101]\n0\n1000\nfalse"
+);
+mk_fail_test!(generator_err_1, "generator_err_1", "generator is exhausted");
+mk_fail_test!(generator_err_2, "generator_err_2", "not a generator [1, 2]");
+mk_fail_test!(
+    generator_err_3,
+    "generator_err_3",
+    "yield outside of a generator 2"
+);
+
+mk_test!(
+    thread_1,
+    "thread_1",
+    "1\n3\n1\n2\n[[10, 1], [10, 4], [10, 9], [10, 16]]\n100\n0\n0\n1\n1\n2\n[7, 5]\nRuntimeError(24, 3)\n[1, 2]\n[1, 2]\n135\n<channel>\n<channel>"
+);
+mk_fail_test!(
+    thread_err_1,
+    "thread_err_1",
+    "deadlock: every thread is waiting on a channel"
+);
+mk_fail_test!(
+    thread_err_2,
+    "thread_err_2",
+    "arithmetic expected a number true"
+);
+
+mk_test!(
+    variadic_1,
+    "variadic_1",
+    "0\n10\n[1, []]\n[1, [2, 3]]\n[1, [2, []]]\n[7, [8, 9]]\n[3, 0]\n[3, 3]\n60\n[10, 20, 30]\n6000\n101\n102\nRuntimeError(26, false)\n[]\n[103, 103]\n[4, 4]\n[4, 4]"
+);
+mk_fail_test!(
+    variadic_err_1,
+    "variadic_err_1",
+    "missing argument for parameter b"
+);
+
+mk_test!(
+    keyword_1,
+    "keyword_1",
+    "[1, 80, false]\n[1, 8080, false]\n[2, 443, false]\n[3, 80, true]\n[80, []]\n[5, [6, 7]]\n[4, 80, false]\n[4, 1, true]\n101\n3\n105\n[5, 80, false]\nPoint(2, 1)\nRuntimeError(26, false)\nRuntimeError(26, false)\nRuntimeError(26, false)\nRuntimeError(26, false)"
+);
+mk_fail_test!(
+    keyword_err_1,
+    "keyword_err_1",
+    "missing argument for parameter host"
+);
+
+mk_test!(import_1, "import_1", "12\n12\n9\n21\n1003\n10\n25");
+mk_fail_test!(
+    import_err_1,
+    "import_err_1",
+    "module \"import_shapes\" doesn't declare volume"
+);
+mk_test!(import_2, "import_2", "5\n0\n[1, 2]\n[]\nSome(1, 2)\n3");
+mk_fail_test!(import_err_2, "import_cycle_a", "makes a cycle of imports");
+mk_fail_test!(
+    import_err_3,
+    "import_err_3",
+    "Unbound variable Some at line 3"
+);
+
+mk_test!(
+    dict_1,
+    "dict_1",
+    "dict(1: 11, true: 20, [1, [2, 3]]: 30, Point(1, 2): 40, 9223372036854775808: 50)\n11\n30\n40\n50\nfalse\ntrue\nfalse\n[1, true, [1, [2, 3]], Point(1, 2), 9223372036854775808]\n332833500\n1000\n998001\ndict(1: 11, true: 20, [1, [2, 3]]: 30, Point(1, 2): 40, 9223372036854775808: 50, 2: <loop>)\ndict(3: 3, 1: 2, 2: 1)"
+);
+mk_test!(dict_2, "dict_2", "[5, 5]\n3\ntrue\n[5]\ndict(5: true)");
+mk_fail_test!(dict_err_1, "dict_err_1", "not a dict [1, 2]");
+
+mk_test!(
+    typecheck_1,
+    "typecheck_1",
+    "3\ntrue\n[2, 3, 4, 5, 6]\n[0, 2, 4]\ntrue\n[true, false, true, false, true]\n50\n[false, true, false, true, false]"
+);
+mk_type_test!(typecheck_type_1, "typecheck_1", Ok("[bool]"));
+mk_type_test!(
+    typecheck_type_2,
+    "typecheck_2",
+    Ok("(('a) -> 'a, 'a) -> 'a")
+);
+mk_type_test!(
+    typecheck_err_1,
+    "typecheck_err_1",
+    Err("expected type [num] but found [bool] at line 2, column 25")
+);
+mk_type_test!(
+    typecheck_err_2,
+    "typecheck_err_2",
+    Err("infinite type 'a = ('a) -> 'b")
+);
+mk_type_test!(
+    typecheck_err_3,
+    "typecheck_err_3",
+    Err("expected type num but found bool at line 3")
+);
+mk_type_test!(
+    typecheck_err_4,
+    "typecheck_err_4",
+    Err("the typechecker doesn't support records")
+);
+
+mk_test!(
+    annotation_1,
+    "annotation_1",
+    "1000000\nfalse\n6\ntrue\n[1]\nRuntimeError(28, false)\nRuntimeError(28, 5)"
+);
+mk_type_test!(
+    annotation_type_1,
+    "annotation_2",
+    Ok("(bool, ['a], ['a]) -> ['a]")
+);
+mk_fail_test!(
+    annotation_err_1,
+    "annotation_err_1",
+    "the annotation Num at line 1, column 16 doesn't hold for true"
+);
+mk_fail_test!(
+    annotation_err_2,
+    "annotation_err_2",
+    "the annotation Array doesn't hold for a value of type Num at line 4, column 6"
+);
+mk_fail_test!(
+    annotation_err_3,
+    "annotation_err_3",
+    "the annotation Num doesn't hold for a value of type Bool at line 2, column 21"
+);
+mk_test!(
+    lazy_1,
+    "lazy_1",
+    "<thunk>\n42\n84\n<thunk: 42>\n[0, 1, 2]\nCons(0, <thunk: Cons(1, <thunk: Cons(2, <thunk: Cons(3, <thunk>)>)>)>)\n[0, 1, 4, 9, 16, 25]\n998002\ntrue\n[1, <thunk: <loop>>]\nRuntimeError(29, 5)\n<thunk>\nRuntimeError(0, true)\n<thunk>"
+);
+mk_test!(lazy_2, "lazy_2", "42\n43\n43");
+mk_fail_test!(lazy_err_1, "lazy_err_1", "not a thunk [1, 2]");
+mk_test!(
+    assert_1,
+    "assert_1",
+    "true\n120\n-4\nRuntimeError(30, false)\n[3]\nRuntimeError(30, false)\n7\ntrue"
+);
+mk_fail_test!(
+    assert_err_1,
+    "assert_err_1",
+    "assertion failed at line 4, column 0: equal([1, 2, 3], [1, 2])"
+);
+mk_fail_test!(
+    assert_err_2,
+    "assert_err_2",
+    "assertion failed with code 4 at line 2, column 2: 16 < 10"
+);
+mk_test!(comment_1, "comment_1", "120\n12\n12");
+mk_doc_test!(
+    comment_docs_1,
+    "comment_1",
+    &[("fact", "The factorial of n,\nwhich must not be negative.")]
+);
+mk_fail_test!(
+    comment_err_1,
+    "comment_err_1",
+    "the annotation Num doesn't hold for a value of type Array at line 5, column 38"
+);
+mk_doc_test!(comment_docs_2, "comment_err_1", &[("f", "Only numbers.")]);
+mk_fail_test!(
+    comment_err_2,
+    "comment_err_2",
+    "Unterminated block comment starting at line 1, column 9"
+);
+
 // IMPLEMENTATION
 fn test_example_file(f: &str, expected_str: &str) -> std::io::Result<()> {
     use std::path::Path;
@@ -157,3 +524,36 @@ fn test_example_fail(f: &str, includes: &str) -> std::io::Result<()> {
 
     Ok(())
 }
+
+fn test_example_type(f: &str, expected: Result<&str, &str>) -> std::io::Result<()> {
+    use std::path::Path;
+    let p_name = format!("examples/{}", f);
+    match (runner::typecheck_file(Path::new(&p_name)), expected) {
+        (Ok(t), Ok(expected_type)) => assert_eq!(t, expected_type),
+        (Err(e), Err(includes)) => {
+            let msg = format!("{}", e);
+            assert!(
+                msg.contains(includes),
+                "Expected error message to include the string \"{}\" but got the error: {}",
+                includes,
+                msg
+            )
+        }
+        (Ok(t), Err(_)) => panic!("Expected a type error but got the type {}", t),
+        (Err(e), Ok(_)) => panic!("Expected a type but got the error: {}", e),
+    }
+    Ok(())
+}
+
+fn test_example_docs(f: &str, expected: &[(&str, &str)]) -> std::io::Result<()> {
+    use std::path::Path;
+    let p_name = format!("examples/{}", f);
+    match runner::docs_file(Path::new(&p_name)) {
+        Ok(docs) => {
+            let docs: Vec<(&str, &str)> = docs.iter().map(|(f, d)| (&f[..], &d[..])).collect();
+            assert_eq!(docs, expected)
+        }
+        Err(e) => panic!("Expected doc comments but got the error: {}", e),
+    }
+    Ok(())
+}
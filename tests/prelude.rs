@@ -0,0 +1,110 @@
+use snake::runner;
+use std::path::Path;
+
+macro_rules! mk_prelude_test {
+    ($test_name:ident, $source:expr, $expected_output:expr) => {
+        #[test]
+        fn $test_name() -> std::io::Result<()> {
+            test_prelude($source, $expected_output)
+        }
+    };
+}
+
+mk_prelude_test!(map_1, "map(lambda x: x * 2 end, [1, 2, 3])", "[2, 4, 6]");
+mk_prelude_test!(map_empty, "map(lambda x: x end, [])", "[]");
+mk_prelude_test!(
+    filter_1,
+    "filter(lambda x: x > 2 end, [3, 1, 4, 1, 5])",
+    "[3, 4, 5]"
+);
+mk_prelude_test!(filter_none, "filter(lambda x: false end, [1, 2])", "[]");
+mk_prelude_test!(
+    fold_1,
+    "fold(lambda acc, x: acc * 10 + x end, 0, [1, 2, 3])",
+    "123"
+);
+mk_prelude_test!(fold_empty, "fold(lambda acc, x: x end, 7, [])", "7");
+mk_prelude_test!(range_1, "range(2, 6)", "[2, 3, 4, 5]");
+mk_prelude_test!(range_empty, "range(3, 1)", "[]");
+mk_prelude_test!(reverse_1, "reverse([1, 2, 3])", "[3, 2, 1]");
+mk_prelude_test!(sum_1, "sum(range(1, 101))", "5050");
+mk_prelude_test!(sum_empty, "sum([])", "0");
+mk_prelude_test!(
+    as_values,
+    "map(sum, [[1, 2], [], reverse(range(0, 4))])",
+    "[3, 0, 6]"
+);
+mk_prelude_test!(
+    for_range,
+    "let total = 0 in for i in range(0, 4): total := total + i end; total",
+    "6"
+);
+mk_prelude_test!(
+    shadowed,
+    "def sum(xs): length(xs) in sum(map(lambda x: x + 1 end, [5, 6]))",
+    "2"
+);
+mk_prelude_test!(
+    shadowed_by_let,
+    "let reverse = 3 in reverse + sum([reverse])",
+    "6"
+);
+
+#[test]
+fn in_imported_modules() -> std::io::Result<()> {
+    let dir = tempfile::TempDir::new()?;
+    std::fs::write(
+        dir.path().join("squares.snake"),
+        "def squares(n): map(lambda x: x * x end, range(0, n)) in false",
+    )?;
+    let main = dir.path().join("main.snake");
+    std::fs::write(&main, "import \"squares.snake\" (squares) sum(squares(4))")?;
+    assert_eq!(run_both(&main)?, "14");
+    Ok(())
+}
+
+#[test]
+fn unused_functions_are_dropped() -> std::io::Result<()> {
+    let dir = tempfile::TempDir::new()?;
+    let p = dir.path().join("prog.snake");
+    std::fs::write(&p, "print(1)")?;
+    assert_eq!(function_labels(&p), 0);
+    std::fs::write(&p, "reverse([1, 2])")?;
+    assert_eq!(function_labels(&p), 1);
+    Ok(())
+}
+
+// IMPLEMENTATION
+fn function_labels(p: &Path) -> usize {
+    let asm = match runner::compile_file(p) {
+        Ok(asm) => asm,
+        Err(e) => panic!("Expected the program to compile, got an error: {}", e),
+    };
+    asm.lines()
+        .filter(|line| line.starts_with("func_") && line.ends_with(':'))
+        .count()
+}
+
+// Runs [p] with the compiler and the interpreter, which have to agree
+fn run_both(p: &Path) -> std::io::Result<String> {
+    let tmp_dir = tempfile::TempDir::new()?;
+    let mut w = Vec::new();
+    if let Err(e) = runner::compile_and_run_file(p, tmp_dir.path(), &mut w) {
+        panic!("Expected the program to run, got an error: {}", e)
+    }
+    let mut interp_w = Vec::new();
+    if let Err(e) = runner::interpret_file(p, &mut interp_w) {
+        panic!("Expected the program to interpret, got an error: {}", e)
+    }
+    let stdout = String::from_utf8(w).unwrap();
+    assert_eq!(String::from_utf8(interp_w).unwrap(), stdout);
+    Ok(stdout.trim().to_string())
+}
+
+fn test_prelude(source: &str, expected_str: &str) -> std::io::Result<()> {
+    let dir = tempfile::TempDir::new()?;
+    let p = dir.path().join("prog.snake");
+    std::fs::write(&p, source)?;
+    assert_eq!(run_both(&p)?, expected_str);
+    Ok(())
+}